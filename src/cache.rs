@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
+use crate::traits::{AsString, Error};
+
+/// The hash of a file, together with what the file looked like when it was hashed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+  pub size: u64,
+  pub modified: u64, //Nanoseconds since the unix epoch
+  pub hash: String,
+  pub manifest_hash: Option<String>, //The NewHash instructions.json listed for the file at the time
+}
+
+/**
+ Remembers file hashes between runs, so unchanged files don't have to be hashed again.
+ An entry is only used while the size and modification time of the file still match.
+*/
+pub struct HashCache {
+  path: String,
+  entries: HashMap<String, CacheEntry>,
+}
+
+impl HashCache {
+  /**
+   Loads the cache from disk, a missing or unreadable cache is treated as empty.
+  */
+  pub fn load(path: &str) -> HashCache {
+    let mut entries = HashMap::new();
+    if let Ok(text) = std::fs::read_to_string(path) {
+      match json::parse(&text) {
+        Ok(data) => {
+          for (file_path, entry) in data["entries"].entries() {
+            let (size, modified) = match (entry["size"].as_u64(), entry["modified"].as_u64()) {
+              (Some(size), Some(modified)) => (size, modified),
+              _ => continue
+            };
+            let hash = match entry["hash"].as_string_option() {
+              Some(hash) => hash,
+              None => continue
+            };
+            entries.insert(file_path.to_string(), CacheEntry {
+              size,
+              modified,
+              hash,
+              manifest_hash: entry["manifest_hash"].as_string_option(),
+            });
+          }
+        },
+        Err(e) => println!("Ignoring corrupt hash cache \"{}\": {}", path, e)
+      }
+    }
+    HashCache {
+      path: path.to_string(),
+      entries,
+    }
+  }
+
+  pub fn save(&self) -> Result<(), Error> {
+    let mut entries = json::JsonValue::new_object();
+    for (file_path, entry) in self.entries.iter() {
+      let mut value = json::object!{
+        "size" => entry.size,
+        "modified" => entry.modified,
+        "hash" => entry.hash.clone()
+      };
+      if let Some(manifest_hash) = &entry.manifest_hash {
+        value["manifest_hash"] = manifest_hash.clone().into();
+      }
+      entries[file_path.as_str()] = value;
+    }
+    let data = json::object!{
+      "version" => 1,
+      "entries" => entries
+    };
+    std::fs::write(&self.path, data.dump())?;
+    Ok(())
+  }
+
+  /**
+   Returns the cached hash of a file, if the file hasn't changed since it was hashed.
+  */
+  pub fn get(&self, file_path: &str, metadata: &std::fs::Metadata) -> Option<String> {
+    let (size, modified) = fingerprint(metadata)?;
+    match self.entries.get(file_path) {
+      Some(entry) if entry.size == size && entry.modified == modified => Some(entry.hash.clone()),
+      _ => None
+    }
+  }
+
+  pub fn insert(&mut self, file_path: &str, metadata: &std::fs::Metadata, hash: String, manifest_hash: Option<String>) {
+    if let Some((size, modified)) = fingerprint(metadata) {
+      self.entries.insert(file_path.to_string(), CacheEntry {
+        size,
+        modified,
+        hash,
+        manifest_hash,
+      });
+    }
+  }
+
+  /**
+   Keeps the entries of files whose NewHash is the same in the new manifest, and drops everything else.
+  */
+  pub fn carry_forward<'a, I: Iterator<Item = (&'a str, Option<&'a str>)>>(&mut self, manifest: I) {
+    let manifest : HashMap<&str, Option<&str>> = manifest.collect();
+    self.entries.retain(|file_path, entry| {
+      match manifest.get(file_path.as_str()) {
+        Some(new_hash) => entry.manifest_hash.as_ref().map(|hash| hash.as_str()) == *new_hash,
+        None => false
+      }
+    });
+  }
+}
+
+fn fingerprint(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+  let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+  Some((metadata.len(), modified.as_secs() * 1_000_000_000 + u64::from(modified.subsec_nanos())))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unchanged_entries_survive_a_new_manifest() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let cache_path = dir.path().join("hashes.json").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let file_path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let other_path = dir.path().join("Rx_Pawn.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&file_path, b"game").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(&other_path, b"pawn").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let metadata = std::fs::metadata(&file_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let other_metadata = std::fs::metadata(&other_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mut cache = HashCache::load(&cache_path);
+    cache.insert(&file_path, &metadata, "AAAA".to_string(), Some("AAAA".to_string()));
+    cache.insert(&other_path, &other_metadata, "CCCC".to_string(), Some("CCCC".to_string()));
+    cache.save().expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mut cache = HashCache::load(&cache_path);
+    assert_eq!(cache.get(&file_path, &metadata), Some("AAAA".to_string()));
+    cache.carry_forward(vec![(file_path.as_str(), Some("AAAA")), (other_path.as_str(), Some("DDDD"))].into_iter());
+    assert_eq!(cache.get(&file_path, &metadata), Some("AAAA".to_string()));
+    assert_eq!(cache.get(&other_path, &other_metadata), None);
+
+    std::fs::write(&file_path, b"modified game").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let metadata = std::fs::metadata(&file_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(cache.get(&file_path, &metadata), None);
+  }
+}
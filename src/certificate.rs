@@ -0,0 +1,199 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::mirrors::SocketAddrs;
+
+/// Certificates that expire within this time are reported as `ExpiresSoon`.
+pub const EXPIRY_WARNING : Duration = Duration::from_secs(14 * 24 * 3600);
+
+/// What the mirror benchmark found out about a mirror's TLS certificate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertificateStatus {
+  Valid { expires_in: Duration },
+  ExpiresSoon { expires_in: Duration },
+  /// The handshake failed or the certificate couldn't be read, the mirror gets disabled.
+  Invalid(String),
+}
+
+impl CertificateStatus {
+  pub fn is_invalid(&self) -> bool {
+    match self {
+      CertificateStatus::Invalid(_) => true,
+      _ => false
+    }
+  }
+}
+
+/**
+ Connects to the mirror over TLS, lets native-tls validate the certificate chain and then looks at when the certificate expires.
+*/
+pub fn check_certificate(host: &str, ip: &SocketAddrs, timeout: Duration) -> CertificateStatus {
+  let address = match std::net::ToSocketAddrs::to_socket_addrs(ip).ok().and_then(|mut addresses| addresses.next()) {
+    Some(address) => address,
+    None => return CertificateStatus::Invalid(format!("No address known for {}", host))
+  };
+  let stream = match std::net::TcpStream::connect_timeout(&address, timeout) {
+    Ok(stream) => stream,
+    Err(e) => return CertificateStatus::Invalid(format!("Couldn't connect to {}: {}", host, e))
+  };
+  stream.set_read_timeout(Some(timeout)).ok();
+  stream.set_write_timeout(Some(timeout)).ok();
+  let connector = match native_tls::TlsConnector::new() {
+    Ok(connector) => connector,
+    Err(e) => return CertificateStatus::Invalid(format!("TLS initialization failed: {}", e))
+  };
+  let mut tls_stream = match connector.connect(host, stream) {
+    Ok(tls_stream) => tls_stream,
+    Err(e) => return CertificateStatus::Invalid(format!("TLS handshake with {} failed: {}", host, e))
+  };
+  let der = match tls_stream.peer_certificate() {
+    Ok(Some(certificate)) => certificate.to_der(),
+    Ok(None) => return CertificateStatus::Invalid(format!("{} did not send a certificate", host)),
+    Err(e) => return CertificateStatus::Invalid(format!("Couldn't read the certificate of {}: {}", host, e))
+  };
+  //Close the connection politely, the result doesn't matter
+  tls_stream.shutdown().ok();
+  let not_after = match der.ok().as_ref().and_then(|der| not_after(der)) {
+    Some(not_after) => not_after,
+    None => return CertificateStatus::Invalid(format!("Couldn't parse the certificate of {}", host))
+  };
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0)).as_secs();
+  status_for(not_after, now)
+}
+
+fn status_for(not_after: u64, now: u64) -> CertificateStatus {
+  if not_after <= now {
+    return CertificateStatus::Invalid("The certificate has expired".to_string());
+  }
+  let expires_in = Duration::from_secs(not_after - now);
+  if expires_in < EXPIRY_WARNING {
+    CertificateStatus::ExpiresSoon { expires_in }
+  } else {
+    CertificateStatus::Valid { expires_in }
+  }
+}
+
+/*
+ * Splits a DER encoded value into its tag, its contents and whatever comes after it
+ */
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+  let tag = *data.get(0)?;
+  let first = *data.get(1)?;
+  let (len, header) = if first & 0x80 == 0 {
+    (first as usize, 2)
+  } else {
+    let octets = (first & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+      return None;
+    }
+    let mut len = 0usize;
+    for i in 0..octets {
+      len = (len << 8) | *data.get(2 + i)? as usize;
+    }
+    (len, 2 + octets)
+  };
+  let end = header.checked_add(len)?;
+  if end > data.len() {
+    return None;
+  }
+  Some((tag, &data[header..end], &data[end..]))
+}
+
+/*
+ * Reads the notAfter field of an X.509 certificate, as seconds since the unix epoch
+ */
+fn not_after(der: &[u8]) -> Option<u64> {
+  let (_, certificate, _) = read_tlv(der)?;
+  let (_, tbs_certificate, _) = read_tlv(certificate)?;
+  let mut fields = tbs_certificate;
+  //The version is optional and explicitly tagged
+  if *fields.get(0)? == 0xa0 {
+    fields = read_tlv(fields)?.2;
+  }
+  //serialNumber, signature, issuer
+  for _ in 0..3 {
+    fields = read_tlv(fields)?.2;
+  }
+  let (_, validity, _) = read_tlv(fields)?;
+  let (_, _not_before, rest) = read_tlv(validity)?;
+  let (tag, time, _) = read_tlv(rest)?;
+  parse_time(tag, time)
+}
+
+/*
+ * Parses an UTCTime or GeneralizedTime in the YYMMDDHHMMSSZ or YYYYMMDDHHMMSSZ form
+ */
+fn parse_time(tag: u8, bytes: &[u8]) -> Option<u64> {
+  let text = std::str::from_utf8(bytes).ok()?;
+  let (year, rest) : (u64, &str) = match tag {
+    0x17 => {
+      let year : u64 = text.get(0..2)?.parse().ok()?;
+      (if year >= 50 { 1900 + year } else { 2000 + year }, text.get(2..)?)
+    },
+    0x18 => (text.get(0..4)?.parse().ok()?, text.get(4..)?),
+    _ => return None
+  };
+  let number = |start: usize| -> Option<u64> { rest.get(start..start + 2)?.parse().ok() };
+  let (month, day) = (number(0)?, number(2)?);
+  if month < 1 || month > 12 || day < 1 || day > 31 {
+    return None;
+  }
+  Some(days_from_civil(year, month, day)? * 86_400 + number(4)? * 3600 + number(6)? * 60 + number(8)?)
+}
+
+/*
+ * Days since 1970-01-01 of a date in the proleptic Gregorian calendar, None for years before 1970 (UTCTime goes back to 1950) or after 9999
+ */
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+  if !(1970..=9999).contains(&year) {
+    return None;
+  }
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year / 400;
+  let year_of_era = year - era * 400;
+  let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  Some(era * 146_097 + day_of_era - 719_468)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    if contents.len() < 0x80 {
+      result.push(contents.len() as u8);
+    } else {
+      result.push(0x82);
+      result.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+    }
+    result.extend_from_slice(contents);
+    result
+  }
+
+  #[test]
+  fn reads_not_after_from_certificate() {
+    let validity = [tlv(0x17, b"200101000000Z"), tlv(0x18, b"20301231235959Z")].concat();
+    let tbs_certificate = [
+      tlv(0xa0, &tlv(0x02, &[2])),
+      tlv(0x02, &[0x10; 16]),
+      tlv(0x30, &tlv(0x06, &[0x2a, 0x86, 0x48])),
+      tlv(0x30, &[0x31; 200]),
+      tlv(0x30, &validity),
+    ].concat();
+    let certificate = tlv(0x30, &[tlv(0x30, &tbs_certificate), tlv(0x03, &[0; 8])].concat());
+    assert_eq!(not_after(&certificate), Some(1_924_991_999));
+    assert_eq!(not_after(&certificate[..40]), None);
+  }
+
+  #[test]
+  fn certificate_expiry_is_classified() {
+    assert_eq!(days_from_civil(1970, 1, 1), Some(0));
+    assert_eq!(days_from_civil(2000, 3, 1), Some(11_017));
+    assert_eq!(parse_time(0x17, b"700102000000Z"), Some(86_400));
+    assert_eq!(parse_time(0x17, b"690101000000Z"), None);
+    assert_eq!(parse_time(0x17, b"500101000000Z"), None);
+    assert!(status_for(100, 200).is_invalid());
+    assert_eq!(status_for(200 + 3600, 200), CertificateStatus::ExpiresSoon { expires_in: Duration::from_secs(3600) });
+    assert_eq!(status_for(200 + EXPIRY_WARNING.as_secs(), 200), CertificateStatus::Valid { expires_in: EXPIRY_WARNING });
+  }
+}
@@ -0,0 +1,79 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use crate::traits::{Error, LockUnpoisoned};
+
+/**
+ Tells other threads when `Downloader::download()` returns, and what it returned.
+*/
+pub struct Completion {
+  result: Mutex<Option<Result<(), String>>>,
+  done: Condvar,
+}
+
+impl Completion {
+  pub fn new() -> Completion {
+    Completion {
+      result: Mutex::new(None),
+      done: Condvar::new(),
+    }
+  }
+
+  pub(crate) fn start(&self) {
+    *self.result.lock_unpoisoned() = None;
+  }
+
+  pub(crate) fn is_done(&self) -> bool {
+    self.result.lock_unpoisoned().is_some()
+  }
+
+  pub(crate) fn finish(&self, result: &Result<(), Error>) {
+    *self.result.lock_unpoisoned() = Some(result.as_ref().map(|_| ()).map_err(|e| format!("{}", e)));
+    self.done.notify_all();
+  }
+
+  /**
+   Blocks until the download is done, for at most `timeout` if one is given.
+   Returns right away if the last download already finished.
+  */
+  pub fn wait_until_done(&self, timeout: Option<Duration>) -> Result<(), Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut result = self.result.lock_unpoisoned();
+    loop {
+      if let Some(result) = result.as_ref() {
+        return result.clone().map_err(Error::new);
+      }
+      result = match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            return Err(Error::new("Timed out waiting for the download to finish".to_string()));
+          }
+          self.done.wait_timeout(result, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+        },
+        None => self.done.wait(result).unwrap_or_else(|e| e.into_inner())
+      };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+
+  #[test]
+  fn waits_for_the_result() {
+    let completion = Arc::new(Completion::new());
+    assert!(completion.wait_until_done(Some(Duration::from_millis(10))).is_err());
+    let waiter = {
+      let completion = completion.clone();
+      std::thread::spawn(move || completion.wait_until_done(None))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    completion.finish(&Err(Error::new("Couldn't download file".to_string())));
+    assert!(waiter.join().expect(concat!(module_path!(),":",file!(),":",line!())).is_err());
+    completion.start();
+    completion.finish(&Ok(()));
+    assert!(completion.wait_until_done(None).is_ok());
+  }
+}
@@ -0,0 +1,130 @@
+use crate::traits::Error;
+
+/// The outcome of a single check of `Downloader::diagnose()`: what was found, or what went wrong.
+pub type CheckResult = Result<String, String>;
+
+/// What `Downloader::diagnose()` found, meant to be attached to a bug report.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DiagnosticsReport {
+  /// Whether release.json could be downloaded and parsed from the version url.
+  pub release_reachable: CheckResult,
+  /// How many of the mirrors in release.json responded to a benchmark.
+  pub mirrors_responding: CheckResult,
+  pub install_writable: CheckResult,
+  /// The free space on the drive of the installation, compared to what is left to download if that is known.
+  pub disk_space: CheckResult,
+  /// The version in the ini file of the installation.
+  pub installed_version: CheckResult,
+}
+
+impl DiagnosticsReport {
+  pub fn is_ok(&self) -> bool {
+    self.checks().iter().all(|(_, result)| result.is_ok())
+  }
+
+  fn checks(&self) -> [(&'static str, &CheckResult); 5] {
+    [
+      ("release.json", &self.release_reachable),
+      ("mirrors", &self.mirrors_responding),
+      ("writable", &self.install_writable),
+      ("disk space", &self.disk_space),
+      ("installed version", &self.installed_version),
+    ]
+  }
+}
+
+impl std::fmt::Display for DiagnosticsReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    for (name, result) in self.checks().iter() {
+      match result {
+        Ok(detail) => writeln!(f, "{}: OK, {}", name, detail)?,
+        Err(detail) => writeln!(f, "{}: FAILED, {}", name, detail)?,
+      }
+    }
+    Ok(())
+  }
+}
+
+/**
+ Runs a check, a panic inside of it is reported as a failure of the check rather than taking the report down.
+*/
+pub fn check<F: FnOnce() -> CheckResult>(check: F) -> CheckResult {
+  match std::panic::catch_unwind(std::panic::AssertUnwindSafe(check)) {
+    Ok(result) => result,
+    Err(panic) => {
+      let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+      Err(format!("The check panicked: {}", message))
+    }
+  }
+}
+
+/**
+ The bytes available to the current user on the drive that `path` is on.
+*/
+#[cfg(windows)]
+pub fn available_space(path: &str) -> Result<u64, Error> {
+  use std::os::windows::ffi::OsStrExt;
+  extern "system" {
+    fn GetDiskFreeSpaceExW(directory_name: *const u16, free_bytes_available: *mut u64, total_bytes: *mut u64, total_free_bytes: *mut u64) -> i32;
+  }
+  let wide_path : Vec<u16> = std::ffi::OsStr::new(path).encode_wide().chain(std::iter::once(0)).collect();
+  let mut available = 0u64;
+  let mut total = 0u64;
+  let mut free = 0u64;
+  match unsafe { GetDiskFreeSpaceExW(wide_path.as_ptr(), &mut available, &mut total, &mut free) } {
+    0 => Err(std::io::Error::last_os_error().into()),
+    _ => Ok(available)
+  }
+}
+
+/**
+ The bytes available to the current user on the drive that `path` is on.
+*/
+#[cfg(unix)]
+pub fn available_space(path: &str) -> Result<u64, Error> {
+  use std::os::unix::ffi::OsStrExt;
+  let c_path = match std::ffi::CString::new(std::ffi::OsStr::new(path).as_bytes()) {
+    Ok(c_path) => c_path,
+    Err(_e) => return Err(format!("\"{}\" contains a nul byte", path).into())
+  };
+  let mut stats : libc::statvfs = unsafe { std::mem::zeroed() };
+  match unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } {
+    0 => Ok(stats.f_bavail as u64 * stats.f_frsize as u64),
+    _ => Err(std::io::Error::last_os_error().into())
+  }
+}
+
+#[cfg(not(any(windows, unix)))]
+pub fn available_space(_path: &str) -> Result<u64, Error> {
+  Err("Free space can't be determined on this platform".to_string().into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn panicking_checks_are_reported() {
+    let report = DiagnosticsReport {
+      release_reachable: check(|| Ok("version 5877".to_string())),
+      mirrors_responding: check(|| panic!("no mirrors")),
+      install_writable: Ok("yes".to_string()),
+      disk_space: Ok("1 GB free".to_string()),
+      installed_version: Err("no ini".to_string()),
+    };
+    assert_eq!(report.mirrors_responding, Err("The check panicked: no mirrors".to_string()));
+    assert!(!report.is_ok());
+    assert!(report.to_string().starts_with("release.json: OK, version 5877\nmirrors: FAILED, The check panicked: no mirrors\n"));
+  }
+
+  #[cfg(any(windows, unix))]
+  #[test]
+  fn available_space_is_found() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(available_space(&dir.path().to_string_lossy()).expect(concat!(module_path!(),":",file!(),":",line!())) > 0);
+    assert!(available_space("/nonexistent/RenegadeX").is_err());
+  }
+}
@@ -4,7 +4,8 @@ use crate::futures::Future;
 use futures::future::ok;
 use std::time::Duration;
 use crate::futures::Stream;
-use crate::traits::Error;
+use crate::traits::{Error, LockUnpoisoned};
+use crate::proxy::{env_proxy, ProxyConnector};
 
 /// A Response to a submitted `Request`.
 pub struct Response {
@@ -35,39 +36,172 @@ impl AsRef<[u8]> for Response {
     }
 }
 
-pub fn download_file(url: String, timeout: Duration) -> Result<Response, Error> {
-  if url.contains("http://") {
-    let client = hyper::Client::new();
-    let url = url.parse::<hyper::Uri>()?;
-    let mut req = hyper::Request::builder();
-    req.uri(url.clone()).header("host", url.host().unwrap()).header("User-Agent", format!("RenX-Patcher ({})", env!("CARGO_PKG_VERSION")));
-    let req = req.body(hyper::Body::empty())?;
-    let res = tokio::timer::Timeout::new(client.request(req).and_then(|res| {
-      let parts = res.into_parts();
-      Future::join(ok::<http::response::Parts, hyper::Error>(parts.0),parts.1.concat2())
-    }), timeout);
-    let mut rt = tokio::runtime::current_thread::Runtime::new()?;
-    let result = rt.block_on(res)?;
-    Ok(Response::new(result.0, result.1))
+pub fn download_file(url: String, timeout: Duration, respect_env_proxy: bool) -> Result<Response, Error> {
+  let uri = url.parse::<hyper::Uri>()?;
+  let mut req = hyper::Request::builder();
+  req.uri(uri.clone()).header("host", uri.host().unwrap()).header("User-Agent", format!("RenX-Patcher ({})", env!("CARGO_PKG_VERSION")));
+  let req = req.body(hyper::Body::empty())?;
+  if let Some(proxy) = proxy_for(&url, respect_env_proxy) {
+    fetch_whole(hyper::Client::builder().build::<_, hyper::Body>(ProxyConnector::new(proxy)), req, timeout)
+  } else if url.contains("http://") {
+    fetch_whole(hyper::Client::new(), req, timeout)
   } else if url.contains("https://") {
     let https = hyper_tls::HttpsConnector::new(4).expect("TLS initialization failed");
-    let client = hyper::Client::builder().build::<_, hyper::Body>(https);
-    let url = url.parse::<hyper::Uri>()?;
-    let mut req = hyper::Request::builder();
-    req.uri(url.clone()).header("host", url.host().unwrap()).header("User-Agent", format!("RenX-Patcher ({})", env!("CARGO_PKG_VERSION")));
-    let req = req.body(hyper::Body::empty())?;
-    let res = tokio::timer::Timeout::new(client.request(req).and_then(|res| {
-      let parts = res.into_parts();
-      Future::join(ok::<http::response::Parts, hyper::Error>(parts.0),parts.1.concat2())
-    }), timeout);
-    let mut rt = tokio::runtime::current_thread::Runtime::new()?;
-    let result = rt.block_on(res)?;
-    Ok(Response::new(result.0, result.1))
+    fetch_whole(hyper::Client::builder().build::<_, hyper::Body>(https), req, timeout)
   } else {
     Err(Error::new(format!("Unknown file format: {}", url)))
   }
 }
 
+fn fetch_whole<C>(client: hyper::Client<C>, req: hyper::Request<hyper::Body>, timeout: Duration) -> Result<Response, Error>
+where C: hyper::client::connect::Connect + Sync + 'static, C::Transport: 'static, C::Future: 'static {
+  let res = tokio::timer::Timeout::new(client.request(req).and_then(|res| {
+    let parts = res.into_parts();
+    Future::join(ok::<http::response::Parts, hyper::Error>(parts.0),parts.1.concat2())
+  }), timeout);
+  let mut rt = tokio::runtime::current_thread::Runtime::new()?;
+  let result = rt.block_on(res)?;
+  Ok(Response::new(result.0, result.1))
+}
+
+/**
+ The proxy `env_proxy()` finds for `url`, if the proxy environment variables are respected, see `Downloader::set_respect_env_proxy()`.
+*/
+fn proxy_for(url: &str, respect_env_proxy: bool) -> Option<String> {
+  if respect_env_proxy {
+    env_proxy(url)
+  } else {
+    None
+  }
+}
+
+/**
+ Asks the server how big a file is with a HEAD request, None if it doesn't say or the url isn't one that can be asked.
+*/
+pub fn content_length(url: String, timeout: Duration, respect_env_proxy: bool) -> Result<Option<u64>, Error> {
+  if let Some(path) = local_path(&url) {
+    return Ok(Some(std::fs::metadata(path)?.len()));
+  }
+  let uri = url.parse::<hyper::Uri>()?;
+  let mut req = hyper::Request::builder();
+  req.method("HEAD").uri(uri.clone()).header("host", uri.host().unwrap()).header("User-Agent", format!("RenX-Patcher ({})", env!("CARGO_PKG_VERSION")));
+  let req = req.body(hyper::Body::empty())?;
+  if let Some(proxy) = proxy_for(&url, respect_env_proxy) {
+    fetch_content_length(hyper::Client::builder().build::<_, hyper::Body>(ProxyConnector::new(proxy)), req, timeout)
+  } else if url.contains("http://") {
+    fetch_content_length(hyper::Client::new(), req, timeout)
+  } else if url.contains("https://") {
+    let https = hyper_tls::HttpsConnector::new(4).expect("TLS initialization failed");
+    fetch_content_length(hyper::Client::builder().build::<_, hyper::Body>(https), req, timeout)
+  } else {
+    Ok(None)
+  }
+}
+
+fn fetch_content_length<C>(client: hyper::Client<C>, req: hyper::Request<hyper::Body>, timeout: Duration) -> Result<Option<u64>, Error>
+where C: hyper::client::connect::Connect + Sync + 'static, C::Transport: 'static, C::Future: 'static {
+  let mut rt = tokio::runtime::current_thread::Runtime::new()?;
+  let res = rt.block_on(tokio::timer::Timeout::new(client.request(req), timeout))?;
+  if !res.status().is_success() {
+    return Err(format!("Unexpected response: found status code {}", res.status()).into());
+  }
+  Ok(res.headers().get(hyper::header::CONTENT_LENGTH).and_then(|value| value.to_str().ok()).and_then(|value| value.parse().ok()))
+}
+
+/**
+ Downloads a file into `received`, asking only for what comes after the bytes that are already in there.
+ Whatever arrived before an error is kept, so calling this again with the same buffer resumes the download.
+*/
+pub fn download_file_resumable(url: String, timeout: Duration, respect_env_proxy: bool, received: &mut Vec<u8>) -> Result<(), Error> {
+  let offset = received.len();
+  if let Some(path) = local_path(&url) {
+    received.extend(read_local(&path, offset as u64, None)?);
+    return Ok(());
+  }
+  let body = std::sync::Arc::new(std::sync::Mutex::new(std::mem::replace(received, Vec::new())));
+  let uri = url.parse::<hyper::Uri>()?;
+  let mut req = hyper::Request::builder();
+  req.uri(uri.clone()).header("host", uri.host().unwrap()).header("User-Agent", format!("RenX-Patcher ({})", env!("CARGO_PKG_VERSION"))).header("Accept-Encoding", "identity");
+  if offset != 0 {
+    req.header("Range", format!("bytes={}-", offset));
+  }
+  let req = req.body(hyper::Body::empty())?;
+  let result = if let Some(proxy) = proxy_for(&url, respect_env_proxy) {
+    fetch_into(hyper::Client::builder().build::<_, hyper::Body>(ProxyConnector::new(proxy)), req, timeout, offset, body.clone())
+  } else if url.contains("http://") {
+    fetch_into(hyper::Client::new(), req, timeout, offset, body.clone())
+  } else if url.contains("https://") {
+    let https = hyper_tls::HttpsConnector::new(4).expect("TLS initialization failed");
+    fetch_into(hyper::Client::builder().build::<_, hyper::Body>(https), req, timeout, offset, body.clone())
+  } else {
+    Err(Error::new(format!("Unknown file format: {}", url)))
+  };
+  *received = std::mem::replace(&mut *body.lock_unpoisoned(), Vec::new());
+  result
+}
+
+fn fetch_into<C>(client: hyper::Client<C>, req: hyper::Request<hyper::Body>, timeout: Duration, offset: usize, body: std::sync::Arc<std::sync::Mutex<Vec<u8>>>) -> Result<(), Error>
+where C: hyper::client::connect::Connect + Sync + 'static, C::Transport: 'static, C::Future: 'static {
+  let res = tokio::timer::Timeout::new(client.request(req).and_then(move |res| {
+    let status = res.status();
+    let encoded = res.headers().get(hyper::header::CONTENT_ENCODING).map_or(false, |value| value.to_str().map_or(true, |value| !value.eq_ignore_ascii_case("identity")));
+    if status == 200 && offset != 0 && !encoded {
+      //The server ignored the range, it sends everything again
+      body.lock_unpoisoned().clear();
+    }
+    let chunks = body.clone();
+    res.into_body().for_each(move |chunk| {
+      if (status == 200 || status == 206) && !encoded {
+        chunks.lock_unpoisoned().extend_from_slice(&chunk);
+      }
+      Ok(())
+    }).map(move |_| (status, encoded))
+  }), timeout);
+  let mut rt = tokio::runtime::current_thread::Runtime::new()?;
+  let (status, encoded) = rt.block_on(res)?;
+  if encoded {
+    return Err(format!("Unexpected response: the server compressed the file (status code {}) although it was asked not to", status).into());
+  }
+  match status.as_u16() {
+    200 | 206 => Ok(()),
+    //Everything has been received already
+    416 if offset != 0 => Ok(()),
+    _ => Err(format!("Unexpected response: found status code {}", status).into())
+  }
+}
+
+/**
+ The file a `file://` url points to, None for any other url.
+*/
+pub fn local_path(url: &str) -> Option<std::path::PathBuf> {
+  if !url.starts_with("file://") {
+    return None;
+  }
+  url.parse::<url::Url>().ok().and_then(|url| url.to_file_path().ok())
+}
+
+/**
+ Reads a file of a local mirror from `offset` on, `len` bytes of it or up to its end.
+ A file that's missing is treated like a 404 from a server.
+*/
+pub fn read_local(path: &std::path::Path, offset: u64, len: Option<usize>) -> Result<Vec<u8>, Error> {
+  let mut file = match std::fs::File::open(path) {
+    Ok(file) => file,
+    Err(e) => {
+      let mut error = Error::new(format!("Couldn't open \"{}\": {}", path.display(), e));
+      error.remove_mirror = true;
+      return Err(error);
+    }
+  };
+  file.seek(SeekFrom::Start(offset))?;
+  let mut data = Vec::new();
+  match len {
+    Some(len) => file.take(len as u64).read_to_end(&mut data)?,
+    None => file.read_to_end(&mut data)?
+  };
+  Ok(data)
+}
+
 pub struct BufWriter<W: Write, F: FnMut(&mut W, &mut u64)> {
     inner: Option<W>,
     buf: Vec<u8>,
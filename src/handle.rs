@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::pause::PauseFlag;
+use crate::traits::{Error, LockUnpoisoned};
+use crate::{Completion, Progress};
+
+/**
+ Controls an update that runs on its own thread, see `Downloader::spawn_download()`.
+*/
+pub struct UpdateHandle {
+  pub(crate) progress: Arc<Mutex<Progress>>,
+  pub(crate) download_tokens: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+  pub(crate) cancel_all: Arc<AtomicBool>,
+  pub(crate) paused: Arc<PauseFlag>,
+  pub(crate) completion: Arc<Completion>,
+  pub(crate) thread: std::thread::JoinHandle<Result<(), Error>>,
+}
+
+impl UpdateHandle {
+  /**
+   A snapshot of the progress of the update.
+  */
+  pub fn progress(&self) -> Progress {
+    self.progress.lock_unpoisoned().clone()
+  }
+
+  /**
+   Stops every download, the ones that haven't started yet don't start at all.
+   Partially downloaded files are kept, so a later update resumes them.
+  */
+  pub fn cancel(&self) {
+    self.cancel_all.store(true, Ordering::SeqCst);
+    for cancelled in self.download_tokens.lock_unpoisoned().values() {
+      cancelled.store(true, Ordering::SeqCst);
+    }
+    //A paused download has to wake up to notice it got cancelled
+    self.paused.resume();
+  }
+
+  pub fn pause(&self) {
+    self.paused.pause();
+  }
+
+  pub fn resume(&self) {
+    self.paused.resume();
+  }
+
+  /**
+   Whether the update has ended, including when it failed or got cancelled, `join()` tells which.
+  */
+  pub fn is_finished(&self) -> bool {
+    self.completion.is_done()
+  }
+
+  /**
+   Waits for the update to end and returns its result, which is an error if it got cancelled.
+  */
+  pub fn join(self) -> Result<(), Error> {
+    match self.thread.join() {
+      Ok(result) => result,
+      Err(_e) => Err(Error::new("The update thread panicked".to_string()))
+    }
+  }
+}
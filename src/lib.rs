@@ -10,24 +10,56 @@ extern crate tokio;
 extern crate url;
 extern crate tokio_reactor;
 extern crate http;
+extern crate rand;
 
 //Standard library
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{OpenOptions,DirBuilder};
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::iter::FromIterator;
 use std::ops::Deref;
 use std::panic;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 //Modules
 mod mirrors;
 mod downloader;
+mod throttle;
+mod certificate;
+mod cache;
+mod pause;
+mod retry;
+mod completion;
+mod lock;
+mod handle;
+mod parts;
+mod diagnostics;
+mod proxy;
+mod signature;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "torrent")]
+mod torrent;
 pub mod traits;
-use downloader::{BufWriter, download_file};
-use std::time::Duration;
-use mirrors::{Mirrors, Mirror};
-use traits::{AsString, BorrowUnwrap, Error};
+use downloader::{BufWriter, content_length, download_file_resumable};
+use std::time::{Duration, Instant, SystemTime};
+use mirrors::{Mirrors, Throughput};
+pub use certificate::CertificateStatus;
+pub use completion::Completion;
+pub use diagnostics::{CheckResult, DiagnosticsReport};
+pub use handle::UpdateHandle;
+pub use mirrors::{BenchmarkResult, LauncherInfo, Mirror, MirrorSelector, MirrorSource, ReleaseInfo};
+pub use retry::{Backoff, RetryPolicy};
+pub use throttle::TimeRange;
+#[cfg(feature = "torrent")]
+pub use torrent::{Aria2Transport, TorrentTransport};
+use throttle::Throttle;
+use cache::HashCache;
+use pause::PauseFlag;
+use lock::InstallLock;
+use parts::PartMap;
+use traits::{AsString, BorrowUnwrap, DownloadAttempt, Error, ErrorKind, LockUnpoisoned};
 
 //External crates
 use rayon::prelude::*;
@@ -35,14 +67,51 @@ use ini::Ini;
 use sha2::{Sha256, Digest};
 use hyper::rt::Future;
 
+/// The amount of threads process_instructions uses to look up files.
+const PROBE_THREADS : usize = 8;
+/// The most symbolic links `SymlinkPolicy::Follow` follows in a row, the same as Linux does.
+const MAX_SYMLINK_HOPS : usize = 40;
+/// The directory inside the installation where downloads and patches are kept until they are applied.
+pub const PATCH_SUBDIR : &str = "patcher/";
+/// Used instead of rayon's global pool for hashing by every downloader, see configure_thread_pool()
+static THREAD_POOL : Mutex<Option<Arc<rayon::ThreadPool>>> = Mutex::new(None);
+
 #[derive(Clone)]
 pub struct Progress {
   pub update: Update,
+  pub phase: Phase,
+  pub instructions_processed: (u64, u64), //Looked at .. out of .. entries of instructions.json
   pub hashes_checked: (u64, u64),
+  pub hash_progress: (u64, u64), //Hashed .. out of .. files, unlike hashes_checked the total is known as soon as hashing starts
+  pub files_up_to_date: u64, //Checked files that already matched instructions.json, including the ones the previous manifest says are unchanged
   pub download_size: (u64,u64), //Downloaded .. out of .. bytes
   pub patch_files: (u64, u64), //Patched .. out of .. files
   pub finished_hash: bool,
   pub finished_patching: bool,
+  pub total_known: bool, //Whether download_size.1 and patch_files.1 are final, they keep growing while the files are being checked
+  pub no_update_needed: bool, //Set when download() found the installation up to date and returned without checking the files
+  pub phase_weights: PhaseWeights,
+  pub write_strategy: Option<WriteStrategy>, //Found by download() once the patcher folder exists
+  current_files: Vec<String>, //The files being downloaded or patched right now, one for each worker busy with one
+  overall_floor: Arc<AtomicU32>, //The highest overall_progress() so far as f32 bits, shared between clones so it never goes back
+}
+
+/// How much of `Progress::overall_progress()` each phase takes up, relative to each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseWeights {
+  pub hashing: f32,
+  pub downloading: f32,
+  pub patching: f32,
+}
+
+impl Default for PhaseWeights {
+  fn default() -> PhaseWeights {
+    PhaseWeights {
+      hashing: 0.2,
+      downloading: 0.7,
+      patching: 0.1,
+    }
+  }
 }
 
 #[derive(Clone)]
@@ -54,16 +123,144 @@ pub enum Update {
   Delta,
 }
 
+/// What the downloader is busy with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+  Idle,
+  /// Going through instructions.json to find out which files exist.
+  AnalyzingManifest,
+  CheckingHashes,
+  Downloading,
+  Finished,
+}
+
+/// What happens when a file that needs patching turns out to be a symbolic link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+  /// Patch the file the link points to, the link itself stays.
+  Follow,
+  /// Replace the link with a regular file.
+  Replace,
+  /// Leave the link alone and print a warning.
+  Skip,
+  /// Fail the patch.
+  Error,
+}
+
+/// What happens to an installed file whose hash matches neither the old nor the new hash in instructions.json, e.g. because it was modded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserModifiedPolicy {
+  /// Download the file in full and replace it.
+  Overwrite,
+  /// Rename the file to `<name>.bak` and download it in full.
+  Backup,
+  /// Leave the file as it is.
+  Skip,
+}
+
+/// How many of the patched files get hashed again to verify the result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerificationLevel {
+  /// Every patched file is verified.
+  Full,
+  /// Only the given percentage of the patched files, picked at random, is verified. Executables are always verified.
+  Sampled(u8),
+  None,
+}
+
+/// Which HTTP version is used to download files from the mirrors.
+/// Mirrors are connected to over plain TCP, so there is no TLS handshake to negotiate the version in (ALPN) and it has to be picked up front.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+  /// HTTP/2 for the mirrors that answered the HTTP/2 connection preface when they were benchmarked, see `Mirror::http2`, and HTTP/1.1 for the others.
+  Auto,
+  Http1,
+  /// Speaks HTTP/2 straight away (prior knowledge), the mirror has to support that.
+  Http2,
+}
+
+/// When `download()` applies the files it downloaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateStrategy {
+  /// Every file is applied as soon as it's downloaded, an interrupted update leaves some files updated and others not.
+  InPlace,
+  /// Every file is downloaded and verified first, then they're all applied in one go, keeping the time in which the installation is inconsistent short.
+  Staged,
+}
+
+/// How full downloads get from the patcher folder into the installation, see `Progress::write_strategy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum WriteStrategy {
+  /// The patcher folder is on the same volume as the installation, a file used by one target is renamed into place.
+  Move,
+  /// The patcher folder is on another volume, e.g. because it's a mount point or a link to another drive, so every file is copied, which takes longer.
+  Copy,
+}
+
 impl Progress {
   fn new() -> Progress {
     Progress {
       update: Update::Unknown,
+      phase: Phase::Idle,
+      instructions_processed: (0,0),
       hashes_checked: (0,0),
+      hash_progress: (0,0),
+      files_up_to_date: 0,
       download_size: (0,0),
       patch_files: (0,0),
       finished_hash: false,
       finished_patching: false,
+      total_known: false,
+      no_update_needed: false,
+      phase_weights: PhaseWeights::default(),
+      write_strategy: None,
+      current_files: Vec::new(),
+      overall_floor: Arc::new(AtomicU32::new(0)),
+    }
+  }
+
+  /**
+   How much of the download is done, between 0 and 1. None while the total isn't known yet, show an indeterminate progress bar until then.
+  */
+  pub fn download_fraction(&self) -> Option<f64> {
+    if !self.total_known {
+      return None;
     }
+    match self.download_size {
+      (_, 0) => Some(1.0),
+      (done, total) => Some((done as f64 / total as f64).min(1.0))
+    }
+  }
+
+  /**
+   How much of the whole update is done, between 0 and 1, with hashing, downloading and patching weighted by `phase_weights`.
+   Never goes back, not even while the totals are still growing during the analysis, so it can be shown as a single progress bar.
+  */
+  pub fn overall_progress(&self) -> f32 {
+    let fraction = |(done, total): (u64, u64), finished: bool| -> f32 {
+      match total {
+        0 if finished => 1.0,
+        0 => 0.0,
+        total => (done as f32 / total as f32).min(1.0)
+      }
+    };
+    let finished = self.phase == Phase::Finished || self.no_update_needed;
+    let weights = self.phase_weights;
+    let weight_sum = weights.hashing + weights.downloading + weights.patching;
+    let progress = if finished {
+      1.0
+    } else if self.phase == Phase::Idle || self.phase == Phase::AnalyzingManifest || weight_sum <= 0.0 {
+      0.0
+    } else {
+      let hashing = fraction(self.hash_progress, self.finished_hash);
+      let downloading = if self.total_known { fraction(self.download_size, false) } else { 0.0 };
+      let patching = if self.total_known { fraction(self.patch_files, self.finished_patching) } else { 0.0 };
+      (weights.hashing * hashing + weights.downloading * downloading + weights.patching * patching) / weight_sum
+    };
+    //The bits of positive floats are ordered like the floats themselves
+    let floor = self.overall_floor.fetch_max(progress.max(0.0).to_bits(), Ordering::SeqCst);
+    f32::from_bits(floor).max(progress)
   }
 }
 
@@ -144,15 +341,31 @@ struct Instruction {
   delta_hash: Option<String>,
   full_replace_size: usize,
   delta_size: usize,
-  has_delta: bool
+  has_delta: bool,
+  compressed_part_hashes: Vec<String>, //optional, one hash per part of the full file
+  delta_part_hashes: Vec<String>, //optional, one hash per part of the delta
+  mode: Option<u32>, //optional, the unix permissions of the file
+}
+
+/// What a download contains, which decides the hash it is checked against before and after it is applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadKind {
+  /// The new file as it is, its CompressedHash is missing or equal to its NewHash. Checked against NewHash and copied into place.
+  RawFull,
+  /// The new file encoded as a delta without a source. Checked against CompressedHash, the decoded file against NewHash.
+  CompressedFull,
+  /// A delta from the old file to the new one. Checked against DeltaHash, the patched file against NewHash.
+  Delta,
 }
 
 #[derive(Debug,Clone)]
 pub struct PatchEntry {
   target_path: String,
   delta_path: String,
-  has_source: bool,
+  kind: DownloadKind,
+  source_hash: Option<String>,
   target_hash: String,
+  mode: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -160,18 +373,156 @@ pub struct DownloadEntry {
   file_path: String,
   file_size: usize,
   file_hash: String,
+  part_hashes: Vec<String>,
   patch_entries: Vec<PatchEntry>,
+  resumed_size: u64, //Bytes of a partial download found while planning, counted as done straight away
+}
+
+/// A read-only snapshot of a download that `plan()` decided is needed.
+#[derive(Debug, Clone)]
+pub struct PendingDownload {
+  pub key: String,
+  pub target_paths: Vec<String>,
+  pub size: usize,
+  pub delta: bool,
+  pub kind: DownloadKind,
+  /// The hash the download itself is checked against, see `DownloadKind`.
+  pub hash: String,
+}
+
+/// A read-only view of an entry of instructions.json, as returned by `instructions()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstructionView<'a> {
+  /// Where the file ends up, inside the installation directory.
+  pub path: &'a str,
+  pub old_hash: Option<&'a str>,
+  /// None if the file is to be removed.
+  pub new_hash: Option<&'a str>,
+  pub full_replace_size: usize,
+  pub delta_size: usize,
+  pub has_delta: bool,
+}
+
+/// A file that differs between two versions of instructions.json, see `diff_manifests()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ManifestChange {
+  pub path: String,
+  /// The FullReplaceSize in the old manifest, None if the file is new.
+  pub old_size: Option<usize>,
+  /// The FullReplaceSize in the new manifest, None if the file got removed.
+  pub new_size: Option<usize>,
+}
+
+impl ManifestChange {
+  pub fn size_delta(&self) -> i64 {
+    self.new_size.unwrap_or(0) as i64 - self.old_size.unwrap_or(0) as i64
+  }
+}
+
+/// What changed between two versions of instructions.json, sorted by path.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ManifestDiff {
+  pub added: Vec<ManifestChange>,
+  pub removed: Vec<ManifestChange>,
+  pub changed: Vec<ManifestChange>,
+}
+
+/// The outcome of `scan_integrity()` or `verify_and_repair()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RepairReport {
+  /// Files that matched instructions.json straight away.
+  pub verified: usize,
+  pub repaired: Vec<String>,
+  /// Files that are missing or don't match instructions.json.
+  pub still_corrupt: Vec<String>,
+  /// Files that match instructions.json once the report is made, the verified ones and the repaired ones.
+  pub files_up_to_date: usize,
+  pub bytes_downloaded: u64,
+  /// How the repaired files were put in place, None if nothing had to be downloaded.
+  pub write_strategy: Option<WriteStrategy>,
+}
+
+/// A file that `download()` patched, as passed to the post-patch hook.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileChange {
+  pub path: String,
+  /// The NewHash of the file in instructions.json.
+  pub new_hash: String,
+}
+
+/// A file found by `verify_since()` that doesn't match instructions.json.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CorruptFile {
+  pub path: String,
+  pub expected_hash: String,
+  /// None if the file is missing or couldn't be read.
+  pub actual_hash: Option<String>,
+}
+
+/// Where the installed game version is read from, relative to the RenegadeX location.
+struct VersionIni {
+  path: String,
+  section: String,
+  key: String,
 }
 
 pub struct Downloader {
   renegadex_location: Option<String>, //Os dependant
   version_url: Option<String>,
+  version_ini: VersionIni,
   mirrors: Mirrors,
   instructions: Vec<Instruction>, //instructions.json
   pub state: Arc<Mutex<Progress>>,
   download_hashmap: Mutex<BTreeMap<String, DownloadEntry>>,
+  queued_targets: Mutex<HashSet<String>>,
   hash_queue: Mutex<Vec<Instruction>>,
-  patch_queue: Arc<Mutex<Vec<Vec<PatchEntry>>>>
+  deletion_queue: Mutex<Vec<String>>, //Files instructions.json has no NewHash for, removed once everything is patched
+  patch_queue: Arc<Mutex<Vec<Vec<PatchEntry>>>>,
+  fallback_queue: Arc<Mutex<Vec<PatchEntry>>>,
+  patched_files: Arc<Mutex<Vec<FileChange>>>, //Every file the current download patched
+  post_patch_hook: Option<PostPatchHook>,
+  deletion_confirm: Option<DeletionConfirm>,
+  planned: bool,
+  progress_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+  event_senders: Arc<Mutex<Vec<std::sync::mpsc::Sender<Progress>>>>, //See progress_events(), sent a snapshot every time the progress thread polls
+  shutting_down: Arc<AtomicBool>,
+  throttle: Arc<Mutex<Throttle>>,
+  mirror_throughput: Arc<Mutex<HashMap<String, Throughput>>>,
+  http_version: HttpVersion,
+  update_strategy: UpdateStrategy,
+  patch_options: PatchOptions,
+  #[cfg(feature = "notifications")]
+  notify_on_complete: bool,
+  #[cfg(feature = "metrics")]
+  mirror_failures: Mutex<HashMap<String, u64>>, //Failed download attempts, by mirror address
+  #[cfg(feature = "torrent")]
+  torrent_transport: Option<Box<dyn TorrentTransport>>,
+  prune_ignore_list: Vec<String>,
+  hash_cache: Option<Mutex<HashCache>>,
+  trust_metadata: bool, //Whether the hash cache may be used instead of hashing a file whose size and modification time didn't change
+  slow_mirror_threshold: f64,
+  min_acceptable_speed: u64, //Bytes per second, a download that falls behind it overall is abandoned. 0 disables the budget
+  min_free_space: u64, //Bytes that have to stay free on the drive of the installation while downloading, 0 disables the check
+  out_of_space: Arc<Mutex<Option<u64>>>, //The space that was left once a download found the drive too full, which stops the other downloads as well
+  unchecked_space: Arc<AtomicU64>, //Bytes all downloads received since the free space was last checked
+  trust_server_size: bool,
+  force_update: bool,
+  intra_file_parallelism: usize, //How many parts of a single file are downloaded at the same time
+  multi_source: bool, //Whether the parts of a single file are spread over every enabled mirror
+  retry_policy: RetryPolicy,
+  user_modified_policy: UserModifiedPolicy,
+  previous_manifest: Option<String>, //instructions.json of the installed version, see set_previous_manifest
+  download_tokens: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>, //Cancellation flag of every queued download, by key
+  paused: Arc<PauseFlag>,
+  path_mapper: PathMapper,
+  completion: Arc<Completion>,
+  cancel_all: Arc<AtomicBool>, //Set by UpdateHandle::cancel(), downloads queued afterwards start out cancelled
+  warm_connections: Mutex<HashMap<String, Vec<std::net::TcpStream>>>, //Connections opened ahead of time by warm_connections(), by mirror address
 }
 
 impl Default for Downloader {
@@ -185,12 +536,85 @@ impl Downloader {
     Downloader {
       renegadex_location: None,
       version_url: None,
+      version_ini: VersionIni {
+        path: "UDKGame/Config/DefaultRenegadeX.ini".to_string(),
+        section: "RenX_Game.Rx_Game".to_string(),
+        key: "GameVersionNumber".to_string(),
+      },
       mirrors: Mirrors::new(),
       instructions: Vec::new(),
       state: Arc::new(Mutex::new(Progress::new())),
       download_hashmap: Mutex::new(BTreeMap::new()),
+      queued_targets: Mutex::new(HashSet::new()),
       hash_queue: Mutex::new(Vec::new()),
+      deletion_queue: Mutex::new(Vec::new()),
       patch_queue: Arc::new(Mutex::new(Vec::new())),
+      fallback_queue: Arc::new(Mutex::new(Vec::new())),
+      patched_files: Arc::new(Mutex::new(Vec::new())),
+      post_patch_hook: None,
+      deletion_confirm: None,
+      planned: false,
+      progress_thread: Mutex::new(None),
+      event_senders: Arc::new(Mutex::new(Vec::new())),
+      shutting_down: Arc::new(AtomicBool::new(false)),
+      throttle: Arc::new(Mutex::new(Throttle::new())),
+      mirror_throughput: Arc::new(Mutex::new(HashMap::new())),
+      http_version: HttpVersion::Auto,
+      update_strategy: UpdateStrategy::InPlace,
+      patch_options: PatchOptions::new(),
+      #[cfg(feature = "notifications")]
+      notify_on_complete: false,
+      #[cfg(feature = "metrics")]
+      mirror_failures: Mutex::new(HashMap::new()),
+      #[cfg(feature = "torrent")]
+      torrent_transport: None,
+      prune_ignore_list: vec!["UDKGame/Config/".to_string(), "UDKGame/Logs/".to_string(), "UDKGame/SaveData/".to_string()],
+      hash_cache: None,
+      trust_metadata: true,
+      slow_mirror_threshold: 0.0,
+      min_acceptable_speed: 0,
+      min_free_space: 0,
+      out_of_space: Arc::new(Mutex::new(None)),
+      unchecked_space: Arc::new(AtomicU64::new(SPACE_CHECK_INTERVAL)),
+      trust_server_size: false,
+      force_update: false,
+      intra_file_parallelism: 1,
+      multi_source: false,
+      retry_policy: RetryPolicy::default(),
+      user_modified_policy: UserModifiedPolicy::Overwrite,
+      previous_manifest: None,
+      download_tokens: Arc::new(Mutex::new(HashMap::new())),
+      paused: Arc::new(PauseFlag::new()),
+      path_mapper: Box::new(|path: &str| path.to_string()),
+      completion: Arc::new(Completion::new()),
+      cancel_all: Arc::new(AtomicBool::new(false)),
+      warm_connections: Mutex::new(HashMap::new()),
+    }
+  }
+
+  ///
+  /// Downloads and validates release.json from the given url, without touching any `Downloader` or benchmarking the mirrors.
+  /// The request goes through the proxy in `HTTP_PROXY`, like it does for a `Downloader` by default.
+  ///
+  pub fn validate_release_url(url: &str) -> Result<ReleaseInfo, Error> {
+    mirrors::fetch_release(url, true)
+  }
+
+  ///
+  /// The hash release.json gives for instructions.json, None if release.json hasn't been retrieved or the fallback mirrors are used.
+  ///
+  pub fn instructions_hash(&self) -> Option<&str> {
+    self.mirrors.instructions_hash.as_ref().map(|hash| hash.as_str())
+  }
+
+  ///
+  /// Checks instructions.json that was retrieved some other way against release.json, the way downloading it from a mirror does.
+  /// Without a hash from release.json there is nothing to check against, and it's refused.
+  ///
+  pub fn verify_instructions(&self, text: &str) -> bool {
+    match self.instructions_hash() {
+      Some(expected_hash) => instructions_hash(text.as_bytes()) == expected_hash,
+      None => false
     }
   }
 
@@ -203,11 +627,31 @@ impl Downloader {
   }
 
   ///
+  /// Returns the magnet link or torrent url for the full game, if release.json advertises one.
+  /// It's downloaded by `download()` when a transport is set with `set_torrent_transport()`.
   ///
+  pub fn torrent_link(&self) -> Option<String> {
+    self.mirrors.torrent.clone()
+  }
+
   ///
+  /// Sets the directory RenegadeX is installed in, a relative path is relative to the working directory.
+  /// Backslashes become slashes and doubled slashes are collapsed, so the paths built from it have exactly one slash between their parts.
   ///
   pub fn set_location(&mut self, loc: String) {
-    self.renegadex_location = Some(format!("{}/", loc).replace("\\","/").replace("//","/"));
+    self.renegadex_location = Some(normalize_location(&loc));
+  }
+
+  ///
+  /// The directory downloads and patches are kept in until they are applied, `PATCH_SUBDIR` inside the location.
+  /// Panics if `set_location()` hasn't been called.
+  ///
+  pub fn patch_dir(&self) -> std::path::PathBuf {
+    std::path::PathBuf::from(self.patch_path(""))
+  }
+
+  fn patch_path(&self, name: &str) -> String {
+    format!("{}{}{}", self.renegadex_location.borrow(), PATCH_SUBDIR, name)
   }
   
   ///
@@ -218,6 +662,351 @@ impl Downloader {
     self.version_url = Some(url);
   }
 
+  ///
+  /// Overrides which mirror gets used for a download, the selector receives the enabled mirrors and returns an index into them.
+  /// Mirrors a download failed on are left out when it's retried, unless no other mirror is enabled.
+  /// Without a selector the least busy of the fastest mirrors is used.
+  ///
+  pub fn set_mirror_selector(&mut self, selector: MirrorSelector) {
+    self.mirrors.selector = Some(selector);
+  }
+
+  ///
+  /// Caps the total download speed (in bytes per second) during the given parts of the day, in the local time zone of this machine.
+  /// For example `vec![(TimeRange::new(Duration::from_secs(8 * 3600), Duration::from_secs(23 * 3600)), 1_000_000)]` caps it at 1 MB/s during the day.
+  ///
+  pub fn set_schedule(&mut self, schedule: Vec<(TimeRange, u64)>) {
+    self.throttle.lock_unpoisoned().set_schedule(schedule);
+  }
+
+  ///
+  /// When enabled, benchmarking the mirrors also checks the TLS certificate of https mirrors, see `Mirror::certificate`.
+  /// Mirrors with an invalid certificate get disabled. Disabled by default since it needs an extra connection per mirror.
+  ///
+  pub fn set_check_certificates(&mut self, check_certificates: bool) {
+    self.mirrors.check_certificates = check_certificates;
+  }
+
+  ///
+  /// Sets how many mirrors are benchmarked at the same time, defaults to 8.
+  ///
+  pub fn set_benchmark_concurrency(&mut self, concurrency: usize) {
+    self.mirrors.benchmark_concurrency = std::cmp::max(concurrency, 1);
+  }
+
+  ///
+  /// Sets how long benchmarking the mirrors may take altogether, defaults to 30 seconds.
+  /// Mirrors that haven't answered by then are disabled, so a mirror that hangs doesn't hold up the launcher.
+  ///
+  pub fn set_benchmark_deadline(&mut self, deadline: Duration) {
+    self.mirrors.benchmark_deadline = deadline;
+  }
+
+  ///
+  /// Disables the mirrors that are more than `ratio` times slower than the fastest one after benchmarking them, defaults to 4.
+  /// None keeps every mirror that responded enabled, only ordered by speed, which keeps the slower mirrors around as fallbacks.
+  /// Ratios below 1 would disable the fastest mirror as well, they're raised to 1.
+  ///
+  pub fn set_mirror_prune_ratio(&mut self, ratio: Option<f64>) {
+    self.mirrors.prune_ratio = ratio.map(|ratio| ratio.max(1.0));
+  }
+
+  ///
+  /// Switches to another mirror once a download from a mirror stays below `fraction` of its benchmarked speed for a few parts in a row.
+  /// The parts that were downloaded already are kept, and the mirror stays in use for the other downloads. Defaults to 0, which never switches.
+  ///
+  pub fn set_slow_mirror_threshold(&mut self, fraction: f64) {
+    self.slow_mirror_threshold = fraction.max(0.0);
+  }
+
+  ///
+  /// Gives every download from a mirror a time budget of its size divided by `bytes_per_sec`, counted from its first bytes.
+  /// A mirror that's still sending once the budget is used up is abandoned and the download continues on another one, keeping the parts downloaded so far.
+  /// Unlike `set_slow_mirror_threshold()` this doesn't depend on the benchmark. Defaults to 0, which gives every download all the time it needs.
+  ///
+  pub fn set_min_acceptable_speed(&mut self, bytes_per_sec: u64) {
+    self.min_acceptable_speed = bytes_per_sec;
+  }
+
+  ///
+  /// Stops downloading once less than `bytes` are free on the drive of the installation, checked every 64 MB, e.g. because another program is filling it as well.
+  /// The update then fails with `ErrorKind::InsufficientSpace`, nothing that's still queued gets downloaded and the parts downloaded so far are kept for the next attempt.
+  /// Defaults to 0, which doesn't check the free space.
+  ///
+  pub fn set_min_free_space(&mut self, bytes: u64) {
+    self.min_free_space = bytes;
+  }
+
+  ///
+  /// Whether release.json, the mirror benchmarks and the downloads from the mirrors go through the proxy in the `HTTP_PROXY` environment variable, leaving out the hosts in `NO_PROXY`.
+  /// Only plain http urls can be reached through a proxy. Defaults to true.
+  ///
+  pub fn set_respect_env_proxy(&mut self, respect_env_proxy: bool) {
+    self.mirrors.respect_env_proxy = respect_env_proxy;
+  }
+
+  ///
+  /// Sizes downloads by the Content-Length the mirror reports instead of the size in instructions.json, when the two differ.
+  /// The downloaded file still has to match the hash in instructions.json. Disabled by default.
+  ///
+  pub fn set_trust_server_size(&mut self, trust_server_size: bool) {
+    self.trust_server_size = trust_server_size;
+  }
+
+  ///
+  /// Makes `download()` check every file even when the installed version is the latest one, e.g. to repair an installation.
+  /// Disabled by default, `download()` then returns straight away when `update_available()` says the installation is up to date.
+  ///
+  pub fn set_force_update(&mut self, force_update: bool) {
+    self.force_update = force_update;
+  }
+
+  ///
+  /// Downloads up to `parallelism` parts of the same file at the same time, each over its own connection with a Range request.
+  /// Speeds up updates that consist of a few large files, as files are downloaded in parallel with each other already. Defaults to 1.
+  ///
+  pub fn set_intra_file_parallelism(&mut self, parallelism: usize) {
+    self.intra_file_parallelism = std::cmp::max(parallelism, 1);
+  }
+
+  ///
+  /// Downloads the parts of a large file from every enabled mirror at the same time, rather than all of them from one mirror.
+  /// Helps when no single mirror can saturate the connection. A part that fails is downloaded again from another mirror. Defaults to false.
+  ///
+  pub fn set_multi_source(&mut self, multi_source: bool) {
+    self.multi_source = multi_source;
+  }
+
+  ///
+  /// Whether a file of this size is downloaded by `download_file_parallel` rather than `download_file`.
+  ///
+  fn downloads_in_parts(&self, file_size: usize) -> bool {
+    (self.intra_file_parallelism > 1 || self.multi_source) && file_size > PART_SIZE
+  }
+
+  ///
+  /// Sets how often instructions.json and every file are attempted, on each mirror and in total, and how long to wait in between.
+  /// Defaults to 5 attempts in total without waiting, on any mirror. Retries go to mirrors that haven't failed yet first either way.
+  ///
+  pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+    self.retry_policy = RetryPolicy {
+      per_mirror: std::cmp::max(retry_policy.per_mirror, 1),
+      total: std::cmp::max(retry_policy.total, 1),
+      ..retry_policy
+    };
+  }
+
+  ///
+  /// The same as the free function `configure_thread_pool()`, the pool is shared by every downloader.
+  ///
+  pub fn configure_thread_pool(&mut self, num_threads: usize) -> Result<(), Error> {
+    configure_thread_pool(num_threads)
+  }
+
+  ///
+  /// Runs `op` on the pool set by `configure_thread_pool()`, or on rayon's global pool.
+  ///
+  fn install<R: Send, F: FnOnce() -> R + Send>(&self, op: F) -> R {
+    let thread_pool = THREAD_POOL.lock_unpoisoned().clone();
+    match thread_pool {
+      Some(thread_pool) => thread_pool.install(op),
+      None => op()
+    }
+  }
+
+  ///
+  /// Makes `retrieve_mirrors()` fail when fewer than `min_mirrors` mirrors pass the benchmark, defaults to 1.
+  ///
+  pub fn set_min_mirrors(&mut self, min_mirrors: usize) {
+    self.mirrors.min_mirrors = min_mirrors;
+  }
+
+  ///
+  /// Sets mirrors to fall back on when release.json can't be retrieved, as full urls including the patch path.
+  /// instructions.json can't be checked against its hash in that case, and the game version is treated as unknown.
+  ///
+  pub fn set_fallback_mirrors(&mut self, mirrors: Vec<String>) {
+    self.mirrors.fallback_mirrors = mirrors;
+  }
+
+  ///
+  /// Sets how long a request for release.json may take, and how often it is made before giving up or using the fallback mirrors.
+  /// Defaults to 10 seconds and 3 attempts, waiting 0.5 seconds between the first two and twice as long after every further failure.
+  ///
+  pub fn set_release_retries(&mut self, timeout: Duration, attempts: usize, backoff: Backoff) {
+    self.mirrors.release_timeout = timeout;
+    self.mirrors.release_attempts = std::cmp::max(attempts, 1);
+    self.mirrors.release_backoff = backoff;
+  }
+
+  ///
+  /// Sets the HTTP version used for downloading files, defaults to `HttpVersion::Auto`.
+  /// Every download and every part of one uses a connection of its own, so HTTP/2 doesn't multiplex anything, it only saves a little on headers.
+  /// `Http2` fails on every mirror that doesn't accept HTTP/2 without negotiating it first, which most HTTP/1.1 servers and CDNs don't, `Auto` only uses it where the benchmark found it works.
+  /// Mirrors that weren't benchmarked, e.g. after `with_release_json()`, are treated as HTTP/1.1 by `Auto`.
+  /// Downloads through a proxy always use HTTP/1.1, as that's what proxies are spoken to with.
+  ///
+  pub fn set_http_version(&mut self, http_version: HttpVersion) {
+    self.http_version = http_version;
+  }
+
+  ///
+  /// Sets whether files are applied as they're downloaded or once everything is downloaded, defaults to `UpdateStrategy::InPlace`.
+  /// `Staged` needs room for every download at once in the patcher folder, but is safer if the game may be started in the middle of an update.
+  ///
+  pub fn set_update_strategy(&mut self, update_strategy: UpdateStrategy) {
+    self.update_strategy = update_strategy;
+  }
+
+  ///
+  /// Sets what `plan()` does with files that match neither version in instructions.json, defaults to `UserModifiedPolicy::Overwrite`.
+  /// Missing files are always downloaded.
+  ///
+  pub fn set_user_modified_policy(&mut self, policy: UserModifiedPolicy) {
+    self.user_modified_policy = policy;
+  }
+
+  ///
+  /// Hands over the instructions.json of the version that's installed, e.g. one the launcher kept from the last update.
+  /// `plan()` then only hashes the files whose NewHash changed since, the others are taken to be intact. `scan_integrity()` still hashes everything.
+  ///
+  pub fn set_previous_manifest(&mut self, text: &str) {
+    self.previous_manifest = Some(text.to_string());
+  }
+
+  ///
+  /// Sets how symbolic links in the installation are patched, defaults to `SymlinkPolicy::Skip`.
+  ///
+  pub fn set_symlink_policy(&mut self, policy: SymlinkPolicy) {
+    self.patch_options.symlink_policy = policy;
+  }
+
+  ///
+  /// Sets how thoroughly patched files are verified, defaults to `VerificationLevel::Full`.
+  ///
+  pub fn set_verification_level(&mut self, level: VerificationLevel) {
+    self.patch_options.verification_level = level;
+  }
+
+  ///
+  /// When enabled, deltas are only applied to a source whose hash is known and matches, and their result is always verified, whatever the verification level.
+  /// A delta whose result doesn't match is undone and the file is downloaded in full instead. Disabled by default.
+  ///
+  pub fn set_verify_deltas(&mut self, verify_deltas: bool) {
+    self.patch_options.verify_deltas = verify_deltas;
+  }
+
+  ///
+  /// Sets the directory xdelta puts its temporary files in while decoding, by pointing TMPDIR, TMP and TEMP at it.
+  /// This doesn't move the atomic write of the patched file: its source is still moved aside as `.vcdiff_src.<run>` next to the target, so that rename stays on one volume.
+  ///
+  /// WARNING: this changes the environment of the whole process, not just of this downloader. Everything else in the launcher that embeds the patcher,
+  /// and every program it starts afterwards, gets the new TMPDIR, TMP and TEMP as well, and they aren't put back.
+  /// Setting environment variables isn't thread-safe: call this once at startup, before any thread is started, e.g. before the first `Downloader` is made
+  /// and before the rayon pool or `spawn_download()` runs. Calling it while anything else may read the environment is undefined behaviour on some platforms.
+  ///
+  pub fn set_xdelta_temp_dir(&mut self, dir: std::path::PathBuf) {
+    for name in ["TMPDIR", "TMP", "TEMP"].iter() {
+      std::env::set_var(name, &dir);
+    }
+  }
+
+  ///
+  /// When enabled, an `.exe` or `.dll` that had a valid Authenticode signature before it was patched has to have one afterwards, or patching fails.
+  /// This catches a bad patch that passed the hash check because the hash in instructions.json was wrong as well. Does nothing outside of Windows, disabled by default.
+  ///
+  #[cfg(feature = "signatures")]
+  pub fn set_verify_signatures(&mut self, verify_signatures: bool) {
+    self.patch_options.verify_signatures = verify_signatures;
+  }
+
+  ///
+  /// Downloads the torrent that release.json advertises with `transport` before downloading from the mirrors, when any file has to be downloaded in full.
+  /// Its content is used as a local mirror in front of the others, so every file is still checked against its hash and applied like any other download.
+  /// A file that isn't in the torrent or doesn't match is downloaded from the mirrors instead, as is everything if the transport fails.
+  ///
+  #[cfg(feature = "torrent")]
+  pub fn set_torrent_transport(&mut self, transport: Box<dyn TorrentTransport>) {
+    self.torrent_transport = Some(transport);
+  }
+
+  ///
+  /// Shows a desktop notification once `download()` has finished updating the game.
+  ///
+  #[cfg(feature = "notifications")]
+  pub fn set_notify_on_complete(&mut self, notify_on_complete: bool) {
+    self.notify_on_complete = notify_on_complete;
+  }
+
+  ///
+  /// Sets the paths, relative to the RenegadeX location, that `prune_unknown_files` never touches.
+  /// A path ending in `/` covers a whole directory. Defaults to the config, log and save directories.
+  ///
+  pub fn set_prune_ignore_list(&mut self, ignore_list: Vec<String>) {
+    self.prune_ignore_list = ignore_list.into_iter().map(|path| path.replace("\\", "/")).collect();
+  }
+
+  ///
+  /// Replaces the SHA256 hash that downloads and patched files are verified with.
+  /// The hash has to be returned as hex in the format instructions.json uses, lower-case hex is upper-cased.
+  ///
+  pub fn set_hasher(&mut self, hasher: HashFunction) {
+    self.patch_options.hasher = Hasher::Custom(Arc::from(hasher));
+  }
+
+  ///
+  /// Runs `hook` once `download()` has patched every file, with the files it patched, before the patcher folder is removed.
+  /// An error from the hook is returned by `download()`, which leaves the patcher folder in place in that case.
+  ///
+  pub fn set_post_patch_hook(&mut self, hook: PostPatchHook) {
+    self.post_patch_hook = Some(hook);
+  }
+
+  ///
+  /// Asks `confirm` before removing the files instructions.json has no NewHash for, with the full paths of all of them.
+  /// Returning false keeps every one of them, e.g. because the list looks like a broken manifest. Without it they're removed.
+  ///
+  pub fn set_deletion_confirm(&mut self, confirm: DeletionConfirm) {
+    self.deletion_confirm = Some(confirm);
+  }
+
+  ///
+  /// Rewrites where the files of instructions.json (and the version ini) are installed, the mapped path is still relative to the installation directory.
+  /// Defaults to leaving paths as they are. Applies from the next time instructions.json is retrieved.
+  ///
+  pub fn set_path_mapper(&mut self, path_mapper: PathMapper) {
+    self.path_mapper = path_mapper;
+  }
+
+  ///
+  /// Remembers the hashes of the installed files in the given file, so that files which didn't change aren't hashed again by the next `plan()`.
+  /// Entries stay valid across updates as long as the file itself and its NewHash in instructions.json didn't change.
+  ///
+  pub fn set_hash_cache(&mut self, path: String) {
+    self.hash_cache = Some(Mutex::new(HashCache::load(&path)));
+  }
+
+  ///
+  /// Sets whether a file whose size and modification time match the hash cache is trusted to be unchanged, without hashing it. Defaults to true.
+  /// Metadata can lie: a tool that changes a file and restores its modification time, or a file system with coarse timestamps, goes unnoticed.
+  /// With false every file is hashed, and the hash cache is only kept up to date.
+  ///
+  pub fn set_trust_metadata(&mut self, trust_metadata: bool) {
+    self.trust_metadata = trust_metadata;
+  }
+
+  ///
+  /// Sets the ini file, section and key the installed game version is read from.
+  /// Defaults to `UDKGame/Config/DefaultRenegadeX.ini`, `RenX_Game.Rx_Game` and `GameVersionNumber`.
+  ///
+  pub fn set_version_ini(&mut self, relative_path: String, section: String, key: String) {
+    self.version_ini = VersionIni {
+      path: relative_path.replace("\\", "/"),
+      section,
+      key,
+    };
+  }
+
   ///
   ///
   ///
@@ -233,8 +1022,26 @@ impl Downloader {
   }
 
   ///
+  /// Uses the given release.json instead of downloading it from the version url.
+  /// The mirrors are used as they are, without benchmarking them, which makes this usable without internet access.
   ///
+  pub fn with_release_json(&mut self, json: &str) -> Result<(), Error> {
+    let release_info = ReleaseInfo::parse(json)?;
+    self.mirrors.mirrors.clear();
+    self.mirrors.load_release(release_info);
+    if self.mirrors.is_empty() {
+      return Err("None of the mirrors in release.json could be used".to_string().into());
+    }
+    for i in 0..self.mirrors.mirrors.len() {
+      *self.mirrors.mirrors[i].enabled.lock_unpoisoned() = true;
+    }
+    Ok(())
+  }
+
   ///
+  /// Leftovers in the patcher folder mean an interrupted update is waiting to be resumed, unless instructions.json has been retrieved already
+  /// and none of them are needed for it, e.g. after cleaning up a finished update failed. Those are ignored here and removed by the next `download()`.
+  /// Fails if the installation can't be written to, like `download()` would.
   ///
   pub fn update_available(&self) -> Result<Update, String> {
     if self.mirrors.is_empty() {
@@ -243,11 +1050,14 @@ impl Downloader {
     if self.renegadex_location.is_none() {
       return Err("The RenegadeX location hasn't been set, aborting!".to_string());
     }
-    let patch_dir_path = format!("{}/patcher/", self.renegadex_location.borrow()).replace("//", "/");
-    match std::fs::read_dir(patch_dir_path) {
+    //An update a read-only installation can't take isn't worth offering
+    check_writable(self.renegadex_location.borrow()).map_err(|e| e.to_string())?;
+    let patch_dir_path = self.patch_path("");
+    match std::fs::read_dir(&patch_dir_path) {
       Ok(iter) => {
-        if iter.count() != 0 {
-          let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+        let leftovers : Vec<String> = iter.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().to_string()).collect();
+        if !leftovers.is_empty() && !self.leftovers_are_stale(&leftovers) {
+          let mut state = self.state.lock_unpoisoned();
           state.update = Update::Resume;
           drop(state);
           return Ok(Update::Resume);
@@ -256,65 +1066,677 @@ impl Downloader {
       Err(_e) => {}
     };
 
-    let path = format!("{}UDKGame/Config/DefaultRenegadeX.ini", self.renegadex_location.borrow());
+    let path = self.install_path(&self.version_ini.path);
     let conf = match Ini::load_from_file(&path) {
       Ok(file) => file,
       Err(_e) => {
-        let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+        let mut state = self.state.lock_unpoisoned();
         state.update = Update::Full;
         drop(state);
         return Ok(Update::Full);
       }
     };
 
-    let section = conf.section(Some("RenX_Game.Rx_Game".to_owned())).expect(concat!(module_path!(),":",file!(),":",line!()));
-    let game_version_number = section.get("GameVersionNumber").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let game_version_number : Option<String> = conf.section(Some(self.version_ini.section.clone())).and_then(|section| section.get(&self.version_ini.key)).map(|version_number| version_number.to_string());
 
-    if self.mirrors.version_number.borrow() != game_version_number {
-      let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+    //Without a known version (release.json couldn't be retrieved, or the ini doesn't have one) every file has to be checked
+    if game_version_number.is_none() || self.mirrors.version_number != game_version_number {
+      let mut state = self.state.lock_unpoisoned();
       state.update = Update::Delta;
       drop(state);
       return Ok(Update::Delta);
     }
-    let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut state = self.state.lock_unpoisoned();
     state.update = Update::UpToDate;
     drop(state);
     Ok(Update::UpToDate)
   }
 
   ///
+  /// Whether a game of version `installed` needs an update, going by release.json alone without looking at any files.
+  /// Always true if the version of the release isn't known, e.g. because the fallback mirrors are used.
   ///
+  pub fn update_available_for_version(&self, installed: &str) -> bool {
+    match &self.mirrors.version_number {
+      Some(version_number) => version_number.as_str() != installed.trim(),
+      None => true
+    }
+  }
+
   ///
+  /// Checks what most often goes wrong, for a report users can attach to a bug report: whether release.json can be retrieved,
+  /// how many of its mirrors respond, whether the installation can be written to and has enough space, and which version is installed.
+  /// Every check reports on its own, and this never panics.
   ///
-  pub fn download(&mut self) -> Result<(), Error> {
-    let mut progress = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-    progress.update = Update::Unknown;
+  pub fn diagnose(&self) -> DiagnosticsReport {
+    let mut release = None;
+    let release_reachable = diagnostics::check(|| {
+      let url = self.version_url.as_ref().ok_or_else(|| "The version url hasn't been set".to_string())?;
+      let release_info = mirrors::fetch_release(url, self.mirrors.respect_env_proxy).map_err(|e| e.to_string())?;
+      let detail = format!("version {}, mirrors listed: {}", release_info.version_number, release_info.mirrors.len());
+      release = Some(release_info);
+      Ok(detail)
+    });
+    let mirrors_responding = diagnostics::check(|| {
+      let release_info = release.take().ok_or_else(|| "release.json couldn't be retrieved".to_string())?;
+      let mut mirrors = Mirrors::new();
+      mirrors.check_certificates = self.mirrors.check_certificates;
+      mirrors.respect_env_proxy = self.mirrors.respect_env_proxy;
+      mirrors.benchmark_concurrency = self.mirrors.benchmark_concurrency;
+      mirrors.benchmark_deadline = self.mirrors.benchmark_deadline;
+      mirrors.prune_ratio = None;
+      mirrors.load_release(release_info);
+      mirrors.test_mirrors().map_err(|e| e.to_string())?;
+      match (mirrors.enabled_count(), mirrors.mirrors.len()) {
+        (0, total) => Err(format!("none of the {} mirrors responded", total)),
+        (responding, total) => Ok(format!("{} out of {} mirrors responded", responding, total))
+      }
+    });
+    let install_writable = diagnostics::check(|| {
+      let location = self.renegadex_location.as_ref().ok_or_else(|| "The RenegadeX location hasn't been set".to_string())?;
+      if !std::path::Path::new(location).exists() {
+        return Ok(format!("\"{}\" doesn't exist yet and will be created", location));
+      }
+      check_writable(location).map(|()| format!("\"{}\" can be written to", location)).map_err(|e| e.to_string())
+    });
+    let disk_space = diagnostics::check(|| {
+      let location = self.renegadex_location.as_ref().ok_or_else(|| "The RenegadeX location hasn't been set".to_string())?;
+      //The installation may not exist yet, the drive it will be on is what matters
+      let existing = std::path::Path::new(location).ancestors().find(|path| path.exists()).ok_or_else(|| format!("No part of \"{}\" exists", location))?;
+      let available = diagnostics::available_space(&existing.to_string_lossy()).map_err(|e| e.to_string())?;
+      let progress = self.state.lock_unpoisoned();
+      let needed = if progress.total_known { Some(progress.download_size.1.saturating_sub(progress.download_size.0)) } else { None };
+      drop(progress);
+      match needed {
+        Some(needed) if needed > available => Err(format!("{} bytes free, {} bytes are left to download", available, needed)),
+        Some(needed) => Ok(format!("{} bytes free, {} bytes are left to download", available, needed)),
+        None => Ok(format!("{} bytes free", available))
+      }
+    });
+    let installed_version = diagnostics::check(|| {
+      if self.renegadex_location.is_none() {
+        return Err("The RenegadeX location hasn't been set".to_string());
+      }
+      let path = self.install_path(&self.version_ini.path);
+      let conf = Ini::load_from_file(&path).map_err(|e| format!("Couldn't read \"{}\": {}", &path, e))?;
+      let version_number : Option<String> = conf.section(Some(self.version_ini.section.clone())).and_then(|section| section.get(&self.version_ini.key)).map(|version_number| version_number.to_string());
+      version_number.ok_or_else(|| format!("\"{}\" has no {} in [{}]", &path, &self.version_ini.key, &self.version_ini.section))
+    });
+    DiagnosticsReport {
+      release_reachable,
+      mirrors_responding,
+      install_writable,
+      disk_space,
+      installed_version,
+    }
+  }
+
+  ///
+  /// Downloads and applies everything `plan()` found, planning first if that hasn't happened yet.
+  /// Without a plan it returns straight away if the installation is up to date, setting `Progress::no_update_needed`, see `set_force_update()`.
+  /// Fails with `ErrorKind::AlreadyRunning` if another patcher is working on the same installation,
+  /// and with `ErrorKind::ReadOnlyInstall` before doing anything if the installation can't be written to.
+  ///
+  pub fn download(&mut self) -> Result<(), Error> {
+    self.completion.start();
+    let location = self.renegadex_location.borrow().clone();
+    let result = check_writable(&location).and_then(|()| InstallLock::acquire(&location)).and_then(|_lock| self.run_download());
+    self.completion.finish(&result);
+    result
+  }
+
+  ///
+  /// Downloads and verifies everything an update needs into the patcher folder without applying any of it, e.g. while the game is running.
+  /// The schedule and pausing apply to it as they do to `download()`, which plans again afterwards and finds the downloads in place, so only the patching is left.
+  /// Returns straight away if the installation is up to date.
+  ///
+  pub fn prefetch(&mut self) -> Result<(), Error> {
+    let location = self.renegadex_location.borrow().clone();
+    check_writable(&location)?;
+    let _lock = InstallLock::acquire(&location)?;
+    if !self.planned && !self.force_update {
+      if let Ok(Update::UpToDate) = self.update_available() {
+        self.state.lock_unpoisoned().no_update_needed = true;
+        return Ok(());
+      }
+    }
+    if !self.planned {
+      self.plan()?;
+    }
+    self.remove_stale_leftovers();
+    //Files may change while the game runs, the plan is made again when the update gets applied
+    self.planned = false;
+    self.state.lock_unpoisoned().phase = Phase::Downloading;
+    self.download_files()?;
+    self.patch_queue.lock_unpoisoned().clear();
+    self.state.lock_unpoisoned().phase = Phase::Idle;
+    Ok(())
+  }
+
+  ///
+  /// Runs `download()` on a thread of its own, the returned handle shows the progress and can pause, cancel or wait for the update.
+  ///
+  pub fn spawn_download(mut self) -> UpdateHandle {
+    let progress = self.state.clone();
+    let download_tokens = self.download_tokens.clone();
+    let cancel_all = self.cancel_all.clone();
+    let paused = self.paused.clone();
+    //A result left by an earlier download() doesn't count
+    self.completion.start();
+    let completion = self.completion.clone();
+    let thread = {
+      let cancel_all = cancel_all.clone();
+      std::thread::spawn(move || {
+        let result = self.download();
+        if cancel_all.load(Ordering::SeqCst) {
+          return Err(Error::new("The update was cancelled".to_string()));
+        }
+        result
+      })
+    };
+    UpdateHandle {
+      progress,
+      download_tokens,
+      cancel_all,
+      paused,
+      completion,
+      thread,
+    }
+  }
+
+  fn run_download(&mut self) -> Result<(), Error> {
+    if !self.planned && !self.force_update {
+      if let Ok(Update::UpToDate) = self.update_available() {
+        println!("Already on version {}, nothing to download", self.mirrors.version_number.borrow());
+        let mut state = self.state.lock_unpoisoned();
+        state.no_update_needed = true;
+        state.phase = Phase::Finished;
+        return Ok(());
+      }
+    }
+    if !self.planned {
+      self.plan()?;
+    }
+    self.remove_stale_leftovers();
+    self.planned = false;
+    self.state.lock_unpoisoned().phase = Phase::Downloading;
+    DirBuilder::new().recursive(true).create(self.patch_path(""))?;
+    let write_strategy = detect_write_strategy(&self.patch_path(""), self.renegadex_location.borrow());
+    self.patch_options.write_strategy = write_strategy;
+    self.state.lock_unpoisoned().write_strategy = Some(write_strategy);
+    #[cfg(feature = "torrent")]
+    {
+      self.fetch_torrent();
+    }
+    self.download_and_apply()?;
+    //Deltas whose source file changed underneath us are retried as full downloads.
+    let fallback = std::mem::replace(&mut *self.fallback_queue.lock_unpoisoned(), Vec::new());
+    if !fallback.is_empty() {
+      self.download_hashmap = Mutex::new(BTreeMap::new());
+      self.queued_targets = Mutex::new(HashSet::new());
+      self.state.lock_unpoisoned().total_known = false;
+      for patch_entry in fallback.iter() {
+        println!("Source of \"{}\" changed since it was hashed, downloading full file instead.", &patch_entry.target_path);
+        let instruction = self.instructions.iter().find(|instruction| instruction.path == patch_entry.target_path).cloned();
+        match instruction {
+          Some(instruction) => { self.queue_full_download(&instruction)?; },
+          None => return Err(format!("Couldn't find instruction for \"{}\"", &patch_entry.target_path).into())
+        }
+      }
+      let mut state = self.state.lock_unpoisoned();
+      state.finished_patching = false;
+      state.total_known = true;
+      drop(state);
+      self.download_and_apply()?;
+    }
+    self.delete_files()?;
+    let patched_files = std::mem::replace(&mut *self.patched_files.lock_unpoisoned(), Vec::new());
+    self.cache_patched_hashes(&patched_files);
+    if let Some(post_patch_hook) = &self.post_patch_hook {
+      //The patcher folder is left as it is, for the hook to be run again
+      post_patch_hook(&patched_files)?;
+    }
+    //remove patcher folder and all remaining files in there, unless a cancelled download is waiting to be resumed:
+    let any_cancelled = self.download_tokens.lock_unpoisoned().values().any(|cancelled| cancelled.load(Ordering::SeqCst));
+    if !any_cancelled {
+      std::fs::remove_dir_all(self.patch_path(""))?;
+    }
+    let mut state = self.state.lock_unpoisoned();
+    state.update = Update::UpToDate;
+    state.phase = Phase::Finished;
+    drop(state);
+    #[cfg(feature = "notifications")]
+    {
+      if self.notify_on_complete {
+        let notification = notify_rust::Notification::new().summary("Renegade X").body(&format!("Updated to version {}", self.mirrors.version_number.clone().unwrap_or_else(|| "unknown".to_string()))).show();
+        if let Err(e) = notification {
+          println!("Couldn't show the update notification: {}", e);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Removes the files in the deletion queue, unless the deletion confirmation turns the whole batch down.
+  ///
+  fn delete_files(&self) -> Result<(), Error> {
+    let mut deletion_queue = std::mem::replace(&mut *self.deletion_queue.lock_unpoisoned(), Vec::new());
+    if deletion_queue.is_empty() {
+      return Ok(());
+    }
+    deletion_queue.sort();
+    if let Some(deletion_confirm) = &self.deletion_confirm {
+      if !deletion_confirm(&deletion_queue) {
+        println!("Keeping the {} files instructions.json deletes, the deletion wasn't confirmed", deletion_queue.len());
+        return Ok(());
+      }
+    }
+    for path in deletion_queue.iter() {
+      match std::fs::remove_file(path) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(format!("Couldn't delete \"{}\": {}", path, e).into())
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Puts the files that were just patched in the hash cache with their new hash from instructions.json, so the next check doesn't have to hash them.
+  /// Files that weren't patched, e.g. because their download got cancelled, keep whatever the cache had for them.
+  ///
+  fn cache_patched_hashes(&self, patched_files: &[FileChange]) {
+    let hash_cache = match &self.hash_cache {
+      Some(hash_cache) => hash_cache,
+      None => return
+    };
+    let mut hash_cache = hash_cache.lock_unpoisoned();
+    for file_change in patched_files {
+      match std::fs::metadata(&file_change.path) {
+        Ok(metadata) => hash_cache.insert(&file_change.path, &metadata, file_change.new_hash.clone(), Some(file_change.new_hash.clone())),
+        Err(e) => println!("Couldn't add \"{}\" to the hash cache: {}", &file_change.path, e)
+      }
+    }
+    if let Err(e) = hash_cache.save() {
+      println!("Couldn't save the hash cache: {}", e);
+    }
+  }
+
+  ///
+  /// Downloads the torrent with the transport of `set_torrent_transport()` and adds its content as the first mirror, if there's anything to download in full.
+  ///
+  #[cfg(feature = "torrent")]
+  fn fetch_torrent(&mut self) {
+    let (transport, link) = match (&self.torrent_transport, &self.mirrors.torrent) {
+      (Some(transport), Some(link)) => (transport, link.clone()),
+      _ => return
+    };
+    if !self.download_hashmap.lock_unpoisoned().values().any(|entry| entry.patch_entries[0].kind != DownloadKind::Delta) {
+      return;
+    }
+    let dir = self.patch_path("torrent");
+    println!("Downloading the torrent {}", &link);
+    let content = match transport.fetch(&link, std::path::Path::new(&dir)) {
+      Ok(content) => content,
+      Err(e) => {
+        println!("Couldn't download the torrent, using the mirrors instead: {}", e);
+        return;
+      }
+    };
+    let url = match url::Url::from_directory_path(&content) {
+      Ok(url) => url,
+      Err(()) => {
+        println!("Couldn't use the torrent in \"{}\", using the mirrors instead", content.display());
+        return;
+      }
+    };
+    match Mirror::parse(url.as_str().trim_end_matches('/'), "") {
+      Ok(mirror) => {
+        *mirror.enabled.lock_unpoisoned() = true;
+        self.mirrors.mirrors.insert(0, mirror);
+      },
+      Err(e) => println!("Couldn't use the torrent, using the mirrors instead: {}", e)
+    }
+  }
+
+  ///
+  /// Retrieves the instructions and works out what needs to be downloaded, without downloading anything.
+  /// The result can be inspected with `pending_downloads()` before calling `download()`.
+  ///
+  pub fn plan(&mut self) -> Result<(), Error> {
+    self.reset();
+    if self.instructions.is_empty() {
+      self.retrieve_instructions()?;
+    }
+    self.process_instructions()?;
+    println!("Retrieved instructions, checking hashes.");
+    self.state.lock_unpoisoned().phase = Phase::CheckingHashes;
+    if let Some(hash_cache) = &self.hash_cache {
+      let manifest = self.instructions.iter().map(|instruction| (instruction.path.as_str(), instruction.new_hash.as_ref().map(|hash| hash.as_str())));
+      hash_cache.lock_unpoisoned().carry_forward(manifest);
+    }
+    self.check_hashes()?;
+    if let Some(hash_cache) = &self.hash_cache {
+      if let Err(e) = hash_cache.lock_unpoisoned().save() {
+        println!("Couldn't save the hash cache: {}", e);
+      }
+    }
+    self.planned = true;
+    Ok(())
+  }
+
+  ///
+  /// Hashes every file in instructions.json and reports the ones that are missing or don't match, without changing anything.
+  ///
+  pub fn scan_integrity(&mut self) -> Result<RepairReport, Error> {
+    if self.instructions.is_empty() {
+      self.retrieve_instructions()?;
+    }
+    let corrupt_files = Mutex::new(Vec::new());
+    self.install(|| self.instructions.par_iter().filter(|instruction| instruction.new_hash.is_some()).for_each(|instruction| {
+      if !self.file_is_intact(instruction) {
+        corrupt_files.lock_unpoisoned().push(instruction.path.clone());
+      }
+    }));
+    let mut still_corrupt = corrupt_files.into_inner().unwrap_or_else(|e| e.into_inner());
+    still_corrupt.sort();
+    let verified = self.instructions.iter().filter(|instruction| instruction.new_hash.is_some()).count() - still_corrupt.len();
+    Ok(RepairReport {
+      verified,
+      repaired: Vec::new(),
+      still_corrupt,
+      files_up_to_date: verified,
+      bytes_downloaded: 0,
+      write_strategy: None,
+    })
+  }
+
+  ///
+  /// Scans the installation like `scan_integrity()`, downloads every missing or corrupt file in full and checks them again.
+  ///
+  pub fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+    let mut report = self.scan_integrity()?;
+    if report.still_corrupt.is_empty() {
+      return Ok(report);
+    }
+    self.reset();
+    for path in report.still_corrupt.iter() {
+      let instruction = self.instructions.iter().find(|instruction| &instruction.path == path).cloned().expect(concat!(module_path!(),":",file!(),":",line!()));
+      self.queue_full_download(&instruction)?;
+    }
+    let mut state = self.state.lock_unpoisoned();
+    state.finished_hash = true;
+    state.total_known = true;
+    drop(state);
+    self.planned = true;
+    self.download()?;
+    let state = self.state.lock_unpoisoned();
+    report.bytes_downloaded = state.download_size.0;
+    report.write_strategy = state.write_strategy;
+    drop(state);
+    let (repaired, still_corrupt) : (Vec<String>, Vec<String>) = report.still_corrupt.drain(..).partition(|path| {
+      self.instructions.iter().find(|instruction| &instruction.path == path).map_or(false, |instruction| self.file_is_intact(instruction))
+    });
+    report.files_up_to_date = report.verified + repaired.len();
+    report.repaired = repaired;
+    report.still_corrupt = still_corrupt;
+    Ok(report)
+  }
+
+  ///
+  /// Hashes a single file, given by its path in instructions.json, and tells whether it matches the manifest.
+  /// A file the manifest deletes is intact once it's gone. Requires instructions.json to have been retrieved, by `update_available()` or `plan()`.
+  ///
+  pub fn verify_file(&self, relative_path: &str) -> Result<bool, Error> {
+    if self.instructions.is_empty() {
+      return Err(Error::new("instructions.json hasn't been retrieved yet".to_string()));
+    }
+    let path = self.install_path(relative_path);
+    let instruction = match self.instructions.iter().find(|instruction| instruction.path == path) {
+      Some(instruction) => instruction,
+      None => return Err(Error::new(format!("\"{}\" isn't in instructions.json", relative_path)))
+    };
+    match &instruction.new_hash {
+      Some(_) => Ok(std::path::Path::new(&path).exists() && self.file_is_intact(instruction)),
+      None => Ok(!std::path::Path::new(&path).exists())
+    }
+  }
+
+  ///
+  /// Only hashes the files that were modified after `since`, e.g. the start of an update that got interrupted.
+  /// Missing files are always reported. Requires instructions.json to have been retrieved, by `update_available()` or `plan()`.
+  ///
+  pub fn verify_since(&self, since: SystemTime) -> Result<Vec<CorruptFile>, Error> {
+    if self.instructions.is_empty() {
+      return Err(Error::new("instructions.json hasn't been retrieved yet".to_string()));
+    }
+    let corrupt_files = Mutex::new(Vec::new());
+    self.install(|| self.instructions.par_iter().for_each(|instruction| {
+      let expected_hash = match &instruction.new_hash {
+        Some(hash) => hash,
+        None => return
+      };
+      let actual_hash = match std::fs::metadata(&instruction.path) {
+        Ok(metadata) => {
+          //A file without a usable modification time is hashed anyway
+          if metadata.modified().map_or(false, |modified| modified <= since) {
+            return;
+          }
+          self.patch_options.hasher.hash(&instruction.path).ok()
+        },
+        Err(_e) => None
+      };
+      if actual_hash.as_ref() != Some(expected_hash) {
+        corrupt_files.lock_unpoisoned().push(CorruptFile {
+          path: instruction.path.clone(),
+          expected_hash: expected_hash.clone(),
+          actual_hash,
+        });
+      }
+    }));
+    let mut corrupt_files = corrupt_files.into_inner().unwrap_or_else(|e| e.into_inner());
+    corrupt_files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(corrupt_files)
+  }
+
+  ///
+  /// Applies a delta that is already in memory, e.g. one that arrived over a custom transport, and verifies the result against `expected_hash`.
+  /// Without a source the delta has to be a full file. The source may be the target itself, in which case it is patched in place.
+  ///
+  pub fn apply_delta_bytes(&self, target: &std::path::Path, source: Option<&std::path::Path>, delta: &[u8], expected_hash: &str) -> Result<(), Error> {
+    let target_path = target.to_string_lossy().replace("\\", "/");
+    let expected_hash = expected_hash.to_uppercase();
+    let delta_path = format!("{}.vcdiff_delta", &target_path);
+    //The delta is written next to a target that may be new, in a directory that doesn't exist yet
+    if let Some(parent) = target.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&delta_path, delta)?;
+    let result = match source {
+      Some(source) if source != target => {
+        let source_path = source.to_string_lossy().replace("\\", "/");
+        xdelta::decode_file(Some(&source_path), &delta_path, &target_path);
+        match self.patch_options.hasher.hash(&target_path) {
+          Ok(hash) if hash == expected_hash => Ok(()),
+          Ok(hash) => {
+            std::fs::remove_file(&target_path).ok();
+            Err(format!("Hash for file {} is incorrect!\nGot hash: {}\nExpected hash: {}", &target_path, &hash, &expected_hash).into())
+          },
+          Err(e) => Err(e)
+        }
+      },
+      _ => {
+        let patch_entry = PatchEntry {
+          target_path: target_path.clone(),
+          delta_path: delta_path.clone(),
+          kind: if source.is_some() { DownloadKind::Delta } else { DownloadKind::CompressedFull },
+          source_hash: None,
+          target_hash: expected_hash,
+          mode: None,
+        };
+        let options = PatchOptions { verification_level: VerificationLevel::Full, ..self.patch_options.clone() };
+        match apply_patch(&patch_entry, &options, Arc::new(Mutex::new(Progress::new()))) {
+          Ok(PatchOutcome::Applied) => Ok(()),
+          Ok(PatchOutcome::Skipped) => Err(format!("\"{}\" is a symbolic link, it was left alone", &target_path).into()),
+          Ok(PatchOutcome::SourceChanged) => Err(format!("The delta did not produce the expected file for \"{}\"", &target_path).into()),
+          Err(e) => Err(e)
+        }
+      }
+    };
+    std::fs::remove_file(&delta_path).ok();
+    result
+  }
+
+  ///
+  /// Where a path from instructions.json ends up on disk, after the path mapper had a go at it.
+  ///
+  fn install_path(&self, path: &str) -> String {
+    let mapped_path = (self.path_mapper)(&path.replace("\\", "/")).replace("\\", "/");
+    format!("{}{}", self.renegadex_location.borrow(), mapped_path.trim_start_matches('/'))
+  }
+
+  ///
+  /// Removes the patcher folder if none of the files in it are needed, see `update_available()`.
+  /// Only called while the installation is locked, another patcher may be using the files otherwise.
+  ///
+  fn remove_stale_leftovers(&self) {
+    let patch_dir_path = self.patch_path("");
+    let leftovers : Vec<String> = match std::fs::read_dir(&patch_dir_path) {
+      Ok(iter) => iter.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().to_string()).collect(),
+      Err(_e) => return
+    };
+    if !leftovers.is_empty() && self.leftovers_are_stale(&leftovers) {
+      println!("None of the files in \"{}\" are needed for this release, removing them", &patch_dir_path);
+      if let Err(e) = std::fs::remove_dir_all(&patch_dir_path) {
+        println!("Couldn't remove \"{}\": {}", &patch_dir_path, e);
+      }
+    }
+  }
+
+  ///
+  /// Whether none of the files left in the patcher folder are downloads of the current instructions.json for a file that still needs them.
+  /// Unknown until instructions.json has been retrieved, so false before that. Doesn't touch the hash cache, `update_available()` uses it as well.
+  ///
+  fn leftovers_are_stale(&self, leftovers: &[String]) -> bool {
+    if self.instructions.is_empty() {
+      return false;
+    }
+    leftovers.iter().all(|name| {
+      let key = name.trim_end_matches(".parts");
+      !self.instructions.iter().any(|instruction| {
+        let new_hash = match &instruction.new_hash {
+          Some(new_hash) => new_hash,
+          None => return false
+        };
+        let is_download = full_key(new_hash) == key || instruction.old_hash.as_ref().map_or(false, |old_hash| delta_key(new_hash, old_hash) == key);
+        is_download && self.patch_options.hasher.hash(&instruction.path).ok().as_ref() != Some(new_hash)
+      })
+    })
+  }
+
+  fn file_is_intact(&self, instruction: &Instruction) -> bool {
+    match self.patch_options.hasher.hash(&instruction.path) {
+      Ok(hash) => Some(&hash) == instruction.new_hash.as_ref(),
+      Err(_e) => false
+    }
+  }
+
+  ///
+  /// Resets the progress and the queues left behind by a previous run.
+  ///
+  fn reset(&mut self) {
+    let mut progress = self.state.lock_unpoisoned();
+    progress.update = Update::Unknown;
+    progress.phase = Phase::Idle;
+    progress.instructions_processed = (0,0);
     progress.hashes_checked = (0,0);
+    progress.hash_progress = (0,0);
+    progress.files_up_to_date = 0;
     progress.download_size = (0,0);
     progress.patch_files = (0,0);
     progress.finished_hash = false;
+    progress.total_known = false;
     progress.finished_patching = false;
+    progress.no_update_needed = false;
+    progress.overall_floor.store(0, Ordering::SeqCst);
     drop(progress);
     self.download_hashmap = Mutex::new(BTreeMap::new());
+    self.queued_targets = Mutex::new(HashSet::new());
     self.hash_queue = Mutex::new(Vec::new());
+    self.deletion_queue = Mutex::new(Vec::new());
     self.patch_queue = Arc::new(Mutex::new(Vec::new()));
+    self.fallback_queue = Arc::new(Mutex::new(Vec::new()));
+    self.patched_files = Arc::new(Mutex::new(Vec::new()));
+    *self.out_of_space.lock_unpoisoned() = None;
+    self.unchecked_space.store(SPACE_CHECK_INTERVAL, Ordering::SeqCst);
+    //Cleared rather than replaced, an UpdateHandle holds on to it
+    self.download_tokens.lock_unpoisoned().clear();
+  }
 
-    if self.instructions.is_empty() {
-      self.retrieve_instructions()?;
+  ///
+  /// Stops the download with the given key (see `pending_downloads()`), while the other downloads carry on.
+  /// The files it would have patched are left alone and the partially downloaded file is kept, so a later `download()` resumes it.
+  ///
+  pub fn cancel_download(&self, key: &str) {
+    match self.download_tokens.lock_unpoisoned().get(key) {
+      Some(cancelled) => cancelled.store(true, Ordering::SeqCst),
+      None => println!("There is no download with key {} to cancel", key)
     }
-    self.process_instructions();
-    println!("Retrieved instructions, checking hashes.");
-    self.check_hashes();
-    let child_process = self.check_patch_queue();
-    self.download_files()?;
-    child_process.join().expect(concat!(module_path!(),":",file!(),":",line!()));
-    //need to wait somehow for patch_queue to finish.
-    let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-    state.update = Update::UpToDate;
-    drop(state);
-    Ok(())
+  }
+
+  ///
+  /// Pauses all downloads until `resume()` is called, without giving up on them.
+  /// Connections are kept open for a while, after that they're closed and the downloads continue where they left off on resume.
+  ///
+  pub fn pause(&self) {
+    self.paused.pause();
+  }
+
+  ///
+  /// Lets paused downloads carry on.
+  ///
+  pub fn resume(&self) {
+    self.paused.resume();
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused.is_paused()
+  }
+
+  ///
+  /// Lists the downloads found by `plan()`, along with the files each of them will patch.
+  ///
+  pub fn pending_downloads(&self) -> Vec<PendingDownload> {
+    let download_hashmap = self.download_hashmap.lock_unpoisoned();
+    download_hashmap.iter().map(|(key, download_entry)| {
+      PendingDownload {
+        key: key.clone(),
+        target_paths: download_entry.patch_entries.iter().map(|patch_entry| patch_entry.target_path.clone()).collect(),
+        size: download_entry.file_size,
+        delta: download_entry.patch_entries.first().map_or(false, |patch_entry| patch_entry.kind == DownloadKind::Delta),
+        kind: download_entry.patch_entries.first().map_or(DownloadKind::CompressedFull, |patch_entry| patch_entry.kind),
+        hash: download_entry.file_hash.clone(),
+      }
+    }).collect()
   }
   
+  ///
+  /// Lists the entries of instructions.json, which is empty until `update_available()`, `plan()` or `scan_integrity()` retrieved it.
+  ///
+  pub fn instructions(&self) -> impl Iterator<Item = InstructionView> {
+    self.instructions.iter().map(|instruction| {
+      InstructionView {
+        path: &instruction.path,
+        old_hash: instruction.old_hash.as_ref().map(|hash| hash.as_str()),
+        new_hash: instruction.new_hash.as_ref().map(|hash| hash.as_str()),
+        full_replace_size: instruction.full_replace_size,
+        delta_size: instruction.delta_size,
+        has_delta: instruction.has_delta,
+      }
+    })
+  }
+
   /*
    * Downloads instructions.json from a mirror, checks its validity and passes it on to process_instructions()
    * -------------------------      ------------
@@ -328,61 +1750,66 @@ impl Downloader {
     if !self.instructions.is_empty() {
       return Ok(());
     }
-    let instructions_mutex : Mutex<String> = Mutex::new("".to_string());
-    for retry in 0..3 {
-      let mirror = self.mirrors.get_mirror();
-      let result : Result<(),Error> = {
-        let url = format!("{}/instructions.json", &mirror.address);
-        //println!("{}", &instructions_url);
-        //let text = reqwest::get(&instructions_url)?.text().expect(concat!(module_path!(),":",file!(),":",line!()));
-        let mut text = download_file(url, Duration::from_secs(60))?;
-        let text = text.text()?;
-        // check instructions hash
-        let mut sha256 = Sha256::new();
-        sha256.input(&text);
-        let hash = hex::encode_upper(sha256.result());
-        if &hash != self.mirrors.instructions_hash.borrow() {
-          Err(format!("Hash of instructions.json ({}) did not match the one specified in release.json ({})!", &hash, self.mirrors.instructions_hash.borrow()).into())
-        } else {
-          *instructions_mutex.lock().expect(concat!(module_path!(),":",file!(),":",line!())) = text;
-          Ok(())
-        }
+    //Kept across attempts, so a flaky connection continues where the previous attempt stopped
+    let mut received : Vec<u8> = Vec::new();
+    let mut instructions_text = None;
+    let mut failed_mirrors : Vec<String> = Vec::new();
+    for retry in 0..self.retry_policy.total {
+      if retry > 0 {
+        std::thread::sleep(self.retry_policy.backoff.delay(retry));
+      }
+      let mirror = match self.retry_mirror(&failed_mirrors) {
+        Some(mirror) => mirror,
+        None => break
       };
-      if result.is_ok() {
-        break;
-      } else if result.is_err() && retry == 2 {
-        //TODO: This is bound to one day go wrong
-        return Err("Couldn't fetch instructions.json".to_string().into());
-      } else {
+      let _in_use = mirror.acquire();
+      let url = format!("{}/instructions.json", &mirror.address);
+      if let Err(e) = download_file_resumable(url.clone(), Duration::from_secs(60), self.mirrors.respect_env_proxy, &mut received) {
+        println!("Downloading {} stopped after {} bytes: {}", &url, received.len(), e);
+        failed_mirrors.push(mirror.address.to_string());
+        continue;
+      }
+      if let Some(instructions_size) = self.mirrors.instructions_size {
+        if received.len() as u64 != instructions_size {
+          println!("instructions.json from {} is {} bytes, release.json says {} bytes, attempt {}", &mirror.address, received.len(), instructions_size, retry + 1);
+          println!("Removing mirror: {:#?}", &mirror);
+          failed_mirrors.push(mirror.address.to_string());
+          self.mirrors.remove(mirror);
+          received.clear();
+          continue;
+        }
+      }
+      // check instructions hash
+      let hash = instructions_hash(&received);
+      if self.mirrors.instructions_hash.is_some() && &hash != self.mirrors.instructions_hash.borrow() {
+        println!("Hash of instructions.json ({}) did not match the one specified in release.json ({}), attempt {}", &hash, self.mirrors.instructions_hash.borrow(), retry + 1);
         println!("Removing mirror: {:#?}", &mirror);
+        failed_mirrors.push(mirror.address.to_string());
         self.mirrors.remove(mirror);
+        received.clear();
+        continue;
       }
+      instructions_text = Some(String::from_utf8(received)?);
+      break;
     }
-    let instructions_text : String = instructions_mutex.into_inner().expect(concat!(module_path!(),":",file!(),":",line!()));
-    let instructions_data = match json::parse(&instructions_text) {
-      Ok(result) => result,
-      Err(e) => return Err(format!("Invalid JSON: {}", e).into())
-    };
-    instructions_data.into_inner().iter().for_each(|instruction| {
-      let file_path = format!("{}{}", self.renegadex_location.borrow(), instruction["Path"].as_string().replace("\\", "/"));
-      self.instructions.push(Instruction {
-              path:                file_path,
-              old_hash:            instruction["OldHash"].as_string_option(),
-              new_hash:            instruction["NewHash"].as_string_option(),
-              compressed_hash:     instruction["CompressedHash"].as_string_option(),
-              delta_hash:          instruction["DeltaHash"].as_string_option(),
-              full_replace_size:   instruction["FullReplaceSize"].as_usize().expect(concat!(module_path!(),":",file!(),":",line!())),
-              delta_size:          instruction["DeltaSize"].as_usize().expect(concat!(module_path!(),":",file!(),":",line!())),
-              has_delta:           instruction["HasDelta"].as_bool().expect(concat!(module_path!(),":",file!(),":",line!()))
-            });
-    });
+    let instructions_text : String = match instructions_text {
+      Some(text) => text,
+      None => return Err("Couldn't fetch instructions.json".to_string().into())
+    };
+    let instructions = parse_instructions(&instructions_text, |path| self.install_path(path))?;
+    if let Some(expected_file_count) = self.mirrors.expected_file_count {
+      if instructions.len() != expected_file_count {
+        return Err(format!("instructions.json lists {} files, while release.json says it should list {}. Is it truncated or out of date?", instructions.len(), expected_file_count).into());
+      }
+    }
+    self.instructions = instructions;
     Ok(())
   }
 
   /*
-   * ------------------------   par   --------------------
-   * | process_instructions |  ---->  | Try to Open File | 
-   * ------------------------         --------------------
+   * ------------------------   par   -------------------------
+   * | process_instructions |  ---->  | Look up file metadata | 
+   * ------------------------         -------------------------
    *                                   |                |
    *                                   |                |
    *                           ------------------    ----------
@@ -397,54 +1824,118 @@ impl Downloader {
    *                    ------------------------
    * 
    */
-  fn process_instructions(&self) {
-    self.instructions.par_iter().for_each(|instruction| {
-      //lets start off by trying to open the file.
-      match OpenOptions::new().read(true).open(&instruction.path) {
-        Ok(_file) => {
+  fn process_instructions(&self) -> Result<(), Error> {
+    let mut state = self.state.lock_unpoisoned();
+    state.phase = Phase::AnalyzingManifest;
+    state.instructions_processed = (0, self.instructions.len() as u64);
+    drop(state);
+    //Keep the amount of threads probing the disk fixed, no matter how big rayon's global pool is.
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(PROBE_THREADS).thread_name(|index| format!("patcher-probe-{}", index)).build().expect(concat!(module_path!(),":",file!(),":",line!()));
+    pool.install(|| self.instructions.par_iter().try_for_each(|instruction| -> Result<(), Error> {
+      //lets start off by checking whether the file exists, without holding on to a file handle.
+      match std::fs::metadata(&instruction.path) {
+        Ok(_metadata) => {
           if instruction.new_hash.is_some() {
-            let mut hash_queue = self.hash_queue.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+            let mut hash_queue = self.hash_queue.lock_unpoisoned();
             hash_queue.push(instruction.clone());
             drop(hash_queue);
-            let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+            let mut state = self.state.lock_unpoisoned();
             state.hashes_checked.1 += 1;
             drop(state);
           } else {
             println!("Found entry {} that needs deleting.", instruction.path);
-            //TODO: DeletionQueue, delete it straight away?
+            self.deletion_queue.lock_unpoisoned().push(instruction.path.clone());
           }
         },
         Err(_e) => {
-          if let Some(key) = &instruction.new_hash {
-            let delta_path = format!("{}patcher/{}", self.renegadex_location.borrow(), &key);
-            let mut download_hashmap = self.download_hashmap.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-            if !download_hashmap.contains_key(key) {
-              let download_entry = DownloadEntry {
-                file_path: delta_path.clone(),
-                file_size: instruction.full_replace_size,
-                file_hash: instruction.compressed_hash.clone().expect(concat!(module_path!(),":",file!(),":",line!())),
-                patch_entries: Vec::new(),
-              };
-              download_hashmap.insert(key.clone(), download_entry);
-              let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-              state.download_size.1 += instruction.full_replace_size as u64;
-              drop(state);
-            }
-            let patch_entry = PatchEntry {
-              target_path: instruction.path.clone(),
-              delta_path,
-              has_source: false,
-              target_hash: key.clone(),
-            };
-            download_hashmap.get_mut(key).expect(concat!(module_path!(),":",file!(),":",line!())).patch_entries.push(patch_entry); //should we add it to a downloadQueue??
-            drop(download_hashmap);
-            let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-            state.patch_files.1 += 1;
-            drop(state);
+          if instruction.new_hash.is_some() {
+            self.queue_full_download(instruction)?;
           }
         }
       };
-    });
+      self.state.lock_unpoisoned().instructions_processed.0 += 1;
+      Ok(())
+    }))
+  }
+
+  ///
+  /// Queues the full file for an instruction, patching it without a source file.
+  ///
+  fn queue_full_download(&self, instruction: &Instruction) -> Result<bool, Error> {
+    let new_hash = new_hash(instruction)?;
+    let key = full_key(&new_hash);
+    let (kind, file_hash) = full_download(instruction)?;
+    let patch_entry = PatchEntry {
+      target_path: instruction.path.clone(),
+      delta_path: self.patch_path(&key),
+      kind,
+      source_hash: None,
+      target_hash: new_hash,
+      mode: instruction.mode,
+    };
+    Ok(self.queue_download(&key, instruction.full_replace_size, file_hash, instruction.compressed_part_hashes.clone(), patch_entry))
+  }
+
+  ///
+  /// Queues the delta that turns the old version of an instruction's file into the new one.
+  /// An instruction without a DeltaHash gets the full file instead, there's nothing to check the delta against.
+  ///
+  fn queue_delta_download(&self, instruction: &Instruction) -> Result<bool, Error> {
+    let file_hash = match instruction.delta_hash.clone() {
+      Some(hash) => hash,
+      None => {
+        println!("Delta hash is empty for \"{}\", downloading full file!", &instruction.path);
+        return self.queue_full_download(instruction);
+      }
+    };
+    let new_hash = new_hash(instruction)?;
+    let key = delta_key(&new_hash, instruction.old_hash.borrow());
+    let patch_entry = PatchEntry {
+      target_path: instruction.path.clone(),
+      delta_path: self.patch_path(&key),
+      kind: DownloadKind::Delta,
+      source_hash: instruction.old_hash.clone(),
+      target_hash: new_hash,
+      mode: instruction.mode,
+    };
+    Ok(self.queue_download(&key, instruction.delta_size, file_hash, instruction.delta_part_hashes.clone(), patch_entry))
+  }
+
+  ///
+  /// Adds a patch entry to the download with the given key, creating that download if it doesn't exist yet.
+  /// A target is only ever queued once, the first download it was queued for is the one that patches it.
+  ///
+  fn queue_download(&self, key: &str, file_size: usize, file_hash: String, part_hashes: Vec<String>, patch_entry: PatchEntry) -> bool {
+    let mut download_hashmap = self.download_hashmap.lock_unpoisoned();
+    let mut queued_targets = self.queued_targets.lock_unpoisoned();
+    if !queued_targets.insert(patch_entry.target_path.clone()) {
+      println!("\"{}\" is already queued for download, ignoring it for {}", &patch_entry.target_path, key);
+      return false;
+    }
+    drop(queued_targets);
+    if !download_hashmap.contains_key(key) {
+      let resumed_size = self.resumed_size(&patch_entry.delta_path, file_size);
+      let download_entry = DownloadEntry {
+        file_path: patch_entry.delta_path.clone(),
+        file_size,
+        file_hash,
+        part_hashes,
+        patch_entries: Vec::new(),
+        resumed_size,
+      };
+      download_hashmap.insert(key.to_string(), download_entry);
+      self.download_tokens.lock_unpoisoned().insert(key.to_string(), Arc::new(AtomicBool::new(self.cancel_all.load(Ordering::SeqCst))));
+      let mut state = self.state.lock_unpoisoned();
+      state.download_size.0 += resumed_size;
+      state.download_size.1 += file_size as u64;
+      drop(state);
+    }
+    download_hashmap.get_mut(key).expect(concat!(module_path!(),":",file!(),":",line!())).patch_entries.push(patch_entry);
+    drop(download_hashmap);
+    let mut state = self.state.lock_unpoisoned();
+    state.patch_files.1 += 1;
+    drop(state);
+    true
   }
 
   pub fn remove_unversioned(&mut self) -> Result<(), Error> {
@@ -487,14 +1978,62 @@ impl Downloader {
     Ok(())
   }
 
-  fn read_dir(&self, dir: &std::path::Path, versioned_files: &Directory, renegadex_path: &std::path::PathBuf) -> Result<(),Error> {
-    let files = std::fs::read_dir(dir).expect(concat!(module_path!(),":",file!(),":",line!()));
-    for file in files {
-      let file = file.expect(concat!(module_path!(),":",file!(),":",line!()));
-      if file.file_type().expect(concat!(module_path!(),":",file!(),":",line!())).is_dir() {
-        if versioned_files.directory_exists(file.path().strip_prefix(&renegadex_path).expect(concat!(module_path!(),":",file!(),":",line!())).to_owned()) {
-          self.read_dir(&file.path(), versioned_files, renegadex_path)?;
-        } else {
+  ///
+  /// Finds the files in the RenegadeX location that aren't part of instructions.json, and removes them unless `dry_run` is set.
+  /// The instructions have to be retrieved already, e.g. by `plan()`. Returns the files that were found.
+  ///
+  pub fn prune_unknown_files(&self, dry_run: bool) -> Result<Vec<String>, Error> {
+    if self.instructions.is_empty() {
+      return Err("No instructions found! Did you call plan()?".to_string().into());
+    }
+    let location = self.renegadex_location.borrow();
+    let known_files : HashSet<&str> = self.instructions.iter().filter(|instruction| instruction.new_hash.is_some()).map(|instruction| instruction.path.as_str()).collect();
+    let mut ignore_list = self.prune_ignore_list.clone();
+    ignore_list.push(PATCH_SUBDIR.to_string());
+    ignore_list.push(lock::LOCK_FILE.to_string());
+    let mut unknown_files = Vec::new();
+    let mut directories = vec![std::path::PathBuf::from(location)];
+    while let Some(directory) = directories.pop() {
+      for entry in std::fs::read_dir(&directory)? {
+        let entry = entry?;
+        let path = entry.path().to_string_lossy().replace("\\", "/");
+        let relative_path = path.strip_prefix(location.as_str()).unwrap_or(&path).to_string();
+        let file_type = entry.file_type()?;
+        let ignored = ignore_list.iter().any(|ignored| {
+          if ignored.ends_with('/') {
+            format!("{}/", &relative_path).starts_with(ignored.as_str())
+          } else {
+            &relative_path == ignored
+          }
+        });
+        if ignored {
+          continue;
+        }
+        if file_type.is_dir() {
+          directories.push(entry.path());
+        } else if !known_files.contains(path.as_str()) {
+          unknown_files.push(path);
+        }
+      }
+    }
+    unknown_files.sort();
+    if !dry_run {
+      for file in unknown_files.iter() {
+        println!("Removing file: {}", file);
+        std::fs::remove_file(file)?;
+      }
+    }
+    Ok(unknown_files)
+  }
+
+  fn read_dir(&self, dir: &std::path::Path, versioned_files: &Directory, renegadex_path: &std::path::PathBuf) -> Result<(),Error> {
+    let files = std::fs::read_dir(dir).expect(concat!(module_path!(),":",file!(),":",line!()));
+    for file in files {
+      let file = file.expect(concat!(module_path!(),":",file!(),":",line!()));
+      if file.file_type().expect(concat!(module_path!(),":",file!(),":",line!())).is_dir() {
+        if versioned_files.directory_exists(file.path().strip_prefix(&renegadex_path).expect(concat!(module_path!(),":",file!(),":",line!())).to_owned()) {
+          self.read_dir(&file.path(), versioned_files, renegadex_path)?;
+        } else {
           println!("Removing directory: {:?}", &file.path());
           std::fs::remove_dir_all(&file.path())?;
         }
@@ -526,136 +2065,258 @@ impl Downloader {
  *                         |      Add to Patch HashMap      |   |    Add to Patch Hashmap    |
  *                         ----------------------------------   ------------------------------
  */
-  fn check_hashes(&mut self) {
-    let hash_queue = self.hash_queue.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-    hash_queue.par_iter().for_each(|hash_entry| {
-      let file_path_source = format!("{}.vcdiff_src", &hash_entry.path);
-      let file_hash = match OpenOptions::new().read(true).open(&file_path_source) {
-        Ok(_file) => {
-          if hash_entry.old_hash.is_some() && &get_hash(&file_path_source) == hash_entry.old_hash.borrow() {
-            match std::fs::remove_file(&hash_entry.path) {
-              Ok(()) => {},
-              Err(_e) => {
-                println!("Couldn't remove file before renaming .vcdiff_src...");
-              },
-            }
-            std::fs::rename(&file_path_source, &hash_entry.path).expect(concat!(module_path!(),":",file!(),":",line!()));
-          } else {
-            match std::fs::remove_file(&file_path_source) {
-              Ok(()) => {
-                println!("Removed .vcdiff_src which did not match old_hash...");
-              },
-              Err(_e) => {
-                println!("Couldn't remove .vcdiff_src which did not match old_hash...");
-              }
+  fn check_hashes(&mut self) -> Result<(), Error> {
+    let unchanged = self.unchanged_files();
+    let hash_queue = self.hash_queue.lock_unpoisoned();
+    self.state.lock_unpoisoned().hash_progress = (0, hash_queue.len() as u64);
+    let leftover_sources = leftover_sources(hash_queue.iter().map(|hash_entry| hash_entry.path.as_str()));
+    //A file that can't be hashed matches neither hash, so it gets downloaded in full
+    let hash_file = |file_path: &str, manifest_hash: Option<&String>| self.cached_hash(file_path, manifest_hash).unwrap_or_else(|e| {
+      println!("Couldn't hash \"{}\": {}", file_path, e);
+      String::new()
+    });
+    let result = self.install(|| hash_queue.par_iter().try_for_each(|hash_entry| -> Result<(), Error> {
+      if unchanged.contains(&hash_entry.path) {
+        let mut state = self.state.lock_unpoisoned();
+        state.hashes_checked.0 += 1;
+        state.hash_progress.0 += 1;
+        state.files_up_to_date += 1;
+        drop(state);
+        return Ok(());
+      }
+      //Sources of interrupted delta patches, the first one that is still the old file is put back
+      let mut restored = false;
+      for file_path_source in leftover_sources.get(&hash_entry.path).into_iter().flatten() {
+        if !restored && hash_entry.old_hash.is_some() && &hash_file(file_path_source, None) == hash_entry.old_hash.borrow() {
+          match std::fs::remove_file(&hash_entry.path) {
+            Ok(()) => {},
+            Err(_e) => {
+              println!("Couldn't remove file before renaming .vcdiff_src...");
+            },
+          }
+          std::fs::rename(file_path_source, &hash_entry.path).expect(concat!(module_path!(),":",file!(),":",line!()));
+          restored = true;
+        } else {
+          match std::fs::remove_file(file_path_source) {
+            Ok(()) => {
+              println!("Removed .vcdiff_src which did not match old_hash...");
+            },
+            Err(_e) => {
+              println!("Couldn't remove .vcdiff_src which did not match old_hash...");
             }
           }
-          get_hash(&hash_entry.path)
-        },
-        Err(_e) => {
-          get_hash(&hash_entry.path)
-        },
-      };
+        }
+      }
+      let file_hash = hash_file(&hash_entry.path, hash_entry.new_hash.as_ref());
       if hash_entry.old_hash.is_some() && hash_entry.new_hash.is_some() && &file_hash == hash_entry.old_hash.borrow() && &file_hash != hash_entry.new_hash.borrow() && hash_entry.has_delta {
         //download patch file
-        let key = format!("{}_from_{}", hash_entry.new_hash.borrow(), hash_entry.old_hash.borrow());
-        let delta_path = format!("{}patcher/{}", self.renegadex_location.borrow(), &key);
-        let mut download_hashmap = self.download_hashmap.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-        if !download_hashmap.contains_key(&key) {
-          let download_entry = DownloadEntry {
-            file_path: delta_path.clone(),
-            file_size: hash_entry.delta_size,
-            file_hash: match hash_entry.delta_hash.clone() {
-              Some(hash) => hash,
-              None => {
-                panic!("Delta hash is empty for download_entry: {:?}", hash_entry)
-              }
-            },
-            patch_entries: Vec::new(),
-          };
-          download_hashmap.insert(key.clone(), download_entry);
-          let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-          state.download_size.1 += hash_entry.delta_size as u64;
-          drop(state);
-        }
-
-        let patch_entry = PatchEntry {
-          target_path: hash_entry.path.clone(),
-          delta_path,
-          has_source: true,
-          target_hash: hash_entry.new_hash.clone().expect(concat!(module_path!(),":",file!(),":",line!())),
-        };
-        download_hashmap.get_mut(&key).expect(concat!(module_path!(),":",file!(),":",line!())).patch_entries.push(patch_entry);
-        drop(download_hashmap);
-        let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-        state.patch_files.1 += 1;
+        self.queue_delta_download(hash_entry)?;
+        let mut state = self.state.lock_unpoisoned();
         state.hashes_checked.0 += 1;
         drop(state);
       } else if hash_entry.new_hash.is_some() && &file_hash == hash_entry.new_hash.borrow() {
         //this file is up to date
-        let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+        let mut state = self.state.lock_unpoisoned();
+        state.hashes_checked.0 += 1;
+        state.files_up_to_date += 1;
+        drop(state);
+      } else if hash_entry.new_hash.is_some() && self.user_modified_policy != UserModifiedPolicy::Overwrite && !file_hash.is_empty() && Some(&file_hash) != hash_entry.old_hash.as_ref() {
+        //this file matches neither hash, but it's there, so it may have been changed on purpose
+        match self.user_modified_policy {
+          UserModifiedPolicy::Skip => println!("\"{}\" was modified, leaving it alone.", &hash_entry.path),
+          _ => {
+            let backup_path = format!("{}.bak", &hash_entry.path);
+            match std::fs::rename(&hash_entry.path, &backup_path) {
+              Ok(()) => {
+                println!("\"{}\" was modified, moved it to \"{}\".", &hash_entry.path, &backup_path);
+                self.queue_full_download(hash_entry)?;
+              },
+              //Overwriting it without a backup would lose the changes, so it's left alone like with Skip
+              Err(e) => println!("Couldn't back up \"{}\", leaving it alone: {}", &hash_entry.path, e)
+            }
+          }
+        }
+        let mut state = self.state.lock_unpoisoned();
         state.hashes_checked.0 += 1;
         drop(state);
       } else {
         //this file does not math old hash, nor the new hash, thus it's corrupted
         //download full file
         println!("No suitable patch file found for \"{}\", downloading full file!", &hash_entry.path);
-        let key : &String = hash_entry.new_hash.borrow();
-        let delta_path = format!("{}patcher/{}", self.renegadex_location.borrow(), &key);
-        let mut download_hashmap = self.download_hashmap.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-        if !download_hashmap.contains_key(key) {
-         let download_entry = DownloadEntry {
-            file_path: delta_path.clone(),
-            file_size: hash_entry.full_replace_size,
-            file_hash: match hash_entry.compressed_hash.clone() {
-              Some(hash) => hash,
-              None => {
-                panic!("Delta hash is empty for download_entry: {:?}", hash_entry)
-              }
-            },
-            patch_entries: Vec::new(),
-          };
-          download_hashmap.insert(key.clone(), download_entry);
-          let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-          state.download_size.1 += hash_entry.full_replace_size as u64;
-          drop(state);
-        }
+        self.queue_full_download(hash_entry)?;
+        let mut state = self.state.lock_unpoisoned();
+        state.hashes_checked.0 += 1;
+        drop(state);
+      }
+      self.state.lock_unpoisoned().hash_progress.0 += 1;
+      Ok(())
+    }));
+    drop(hash_queue);
+    result?;
+    self.dedup_downloads();
+    let mut state = self.state.lock_unpoisoned();
+    state.finished_hash = true;
+    state.total_known = true;
+    drop(state);
+    Ok(())
+  }
 
-        let patch_entry = PatchEntry {
-          target_path: hash_entry.path.clone(),
-          delta_path,
-          has_source: false,
-          target_hash: hash_entry.new_hash.clone().expect(concat!(module_path!(),":",file!(),":",line!())),
+  ///
+  /// The files that have the same NewHash in the previous manifest as in instructions.json, empty without a previous manifest.
+  ///
+  fn unchanged_files(&self) -> HashSet<String> {
+    let previous_manifest = match &self.previous_manifest {
+      Some(previous_manifest) => previous_manifest,
+      None => return HashSet::new()
+    };
+    let previous : HashMap<String, String> = match parse_instructions(previous_manifest, |path| self.install_path(path)) {
+      Ok(instructions) => instructions.into_iter().filter_map(|instruction| instruction.new_hash.map(|hash| (instruction.path, hash))).collect(),
+      Err(e) => {
+        println!("Couldn't parse the previous manifest, hashing every file: {}", e);
+        return HashSet::new();
+      }
+    };
+    self.instructions.iter().filter(|instruction| instruction.new_hash.is_some() && previous.get(&instruction.path) == instruction.new_hash.as_ref()).map(|instruction| instruction.path.clone()).collect()
+  }
+
+  ///
+  /// Makes sure content that several targets need is only downloaded once.
+  /// Deltas are dropped in favour of the full file when that's downloaded anyway, or when the deltas for the same content add up to more than the full file.
+  ///
+  fn dedup_downloads(&self) {
+    let mut download_hashmap = self.download_hashmap.lock_unpoisoned();
+    let mut deltas : BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (key, download_entry) in download_hashmap.iter() {
+      if let Some(patch_entry) = download_entry.patch_entries.first().filter(|patch_entry| patch_entry.kind == DownloadKind::Delta) {
+        deltas.entry(patch_entry.target_hash.clone()).or_insert_with(Vec::new).push(key.clone());
+      }
+    }
+    for (new_hash, delta_keys) in deltas {
+      let full_key = full_key(&new_hash);
+      if !download_hashmap.contains_key(&full_key) {
+        let delta_size : usize = delta_keys.iter().map(|key| download_hashmap[key].file_size).sum();
+        let target_path = &download_hashmap[&delta_keys[0]].patch_entries[0].target_path;
+        let instruction = match self.instructions.iter().find(|instruction| &instruction.path == target_path) {
+          Some(instruction) if delta_keys.len() > 1 && delta_size >= instruction.full_replace_size => instruction,
+          _ => continue
         };
-        download_hashmap.get_mut(key).expect(concat!(module_path!(),":",file!(),":",line!())).patch_entries.push(patch_entry);
-        drop(download_hashmap);
-        let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-        state.patch_files.1 += 1;
-        state.hashes_checked.0 += 1;
+        let file_hash = match full_download(instruction) {
+          Ok((_, file_hash)) => file_hash,
+          Err(_) => continue
+        };
+        let file_path = self.patch_path(&full_key);
+        let resumed_size = self.resumed_size(&file_path, instruction.full_replace_size);
+        download_hashmap.insert(full_key.clone(), DownloadEntry {
+          file_path,
+          file_size: instruction.full_replace_size,
+          file_hash,
+          part_hashes: instruction.compressed_part_hashes.clone(),
+          patch_entries: Vec::new(),
+          resumed_size,
+        });
+        self.download_tokens.lock_unpoisoned().insert(full_key.clone(), Arc::new(AtomicBool::new(self.cancel_all.load(Ordering::SeqCst))));
+        let mut state = self.state.lock_unpoisoned();
+        state.download_size.0 += resumed_size;
+        state.download_size.1 += instruction.full_replace_size as u64;
         drop(state);
       }
-    });
-    self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!())).finished_hash = true;
+      let full_path = download_hashmap[&full_key].file_path.clone();
+      let kind = match download_hashmap[&full_key].file_hash.eq_ignore_ascii_case(&new_hash) {
+        true => DownloadKind::RawFull,
+        false => DownloadKind::CompressedFull
+      };
+      for key in delta_keys {
+        let delta_entry = download_hashmap.remove(&key).expect(concat!(module_path!(),":",file!(),":",line!()));
+        self.download_tokens.lock_unpoisoned().remove(&key);
+        let mut state = self.state.lock_unpoisoned();
+        state.download_size.0 = state.download_size.0.saturating_sub(delta_entry.resumed_size);
+        state.download_size.1 -= delta_entry.file_size as u64;
+        drop(state);
+        println!("Downloading {} in full instead of the delta {}, its content is needed elsewhere as well", &full_key, &key);
+        let full_entry = download_hashmap.get_mut(&full_key).expect(concat!(module_path!(),":",file!(),":",line!()));
+        full_entry.patch_entries.extend(delta_entry.patch_entries.into_iter().map(|patch_entry| PatchEntry {
+          delta_path: full_path.clone(),
+          kind,
+          source_hash: None,
+          ..patch_entry
+        }));
+      }
+    }
+  }
+
+
+  ///
+  /// Hashes a file, using the hash cache if one was set. Files without a manifest hash aren't cached.
+  ///
+  fn cached_hash(&self, file_path: &str, manifest_hash: Option<&String>) -> Result<String, Error> {
+    let hash_cache = match (&self.hash_cache, manifest_hash) {
+      (Some(hash_cache), Some(_)) => hash_cache,
+      _ => return self.patch_options.hasher.hash(file_path)
+    };
+    let metadata = std::fs::metadata(file_path)?;
+    if self.trust_metadata {
+      if let Some(hash) = hash_cache.lock_unpoisoned().get(file_path, &metadata) {
+        return Ok(hash);
+      }
+    }
+    let hash = self.patch_options.hasher.hash(file_path)?;
+    hash_cache.lock_unpoisoned().insert(file_path, &metadata, hash.clone(), manifest_hash.cloned());
+    Ok(hash)
   }
 
+  ///
+  /// Downloads everything that's queued and applies it, while downloading or afterwards depending on the `UpdateStrategy`.
+  ///
+  fn download_and_apply(&self) -> Result<(), Error> {
+    match self.update_strategy {
+      UpdateStrategy::InPlace => {
+        let child_process = self.check_patch_queue();
+        //The files that did get downloaded are applied either way
+        let result = self.download_files();
+        child_process.join().expect(concat!(module_path!(),":",file!(),":",line!()));
+        result?;
+      },
+      UpdateStrategy::Staged => {
+        //Every download has been verified by now, a failed one returns its error before anything gets applied
+        self.download_files()?;
+        self.check_patch_queue().join().expect(concat!(module_path!(),":",file!(),":",line!()));
+      }
+    }
+    Ok(())
+  }
 
 /*
  * Iterates over the download_hashmap and calls download_and_patch for each DownloadEntry.
+ * The other downloads carry on when one fails, the first error is returned once they're done.
  */
   fn download_files(&self) -> Result<(), Error> {
-    let dir_path = format!("{}patcher/", self.renegadex_location.borrow());
+    let dir_path = self.patch_path("");
     DirBuilder::new().recursive(true).create(dir_path).expect(concat!(module_path!(),":",file!(),":",line!()));
-    let download_hashmap = self.download_hashmap.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = self.download_hashmap.lock_unpoisoned();
     let mut sorted_downloads_by_size = Vec::from_iter(download_hashmap.deref());
     sorted_downloads_by_size.sort_by(|&(_, a), &(_,b)| b.file_size.cmp(&a.file_size));
-    let pool = rayon::ThreadPoolBuilder::new().num_threads(20).build().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(20).thread_name(|index| format!("patcher-download-{}", index)).build().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let first_error : Mutex<Option<Error>> = Mutex::new(None);
     pool.install(|| {
       rayon::scope_fifo(|s| {
         for (key, download_entry) in sorted_downloads_by_size.into_iter() {
-          s.spawn_fifo(move |_| {self.download_and_patch(key, download_entry).expect(concat!(module_path!(),":",file!(),":",line!()));});
+          let first_error = &first_error;
+          s.spawn_fifo(move |_| {
+            if let Err(e) = self.download_and_patch(key, download_entry) {
+              //Its files won't be patched, so the patch thread doesn't wait for them
+              self.state.lock_unpoisoned().patch_files.1 -= download_entry.patch_entries.len() as u64;
+              first_error.lock_unpoisoned().get_or_insert(e);
+            }
+          });
         }
       })
     });
+    if let Some(e) = first_error.into_inner().unwrap_or_else(|e| e.into_inner()) {
+      return Err(e);
+    }
+    if let Some(available) = *self.out_of_space.lock_unpoisoned() {
+      return Err(Error::insufficient_space(self.patch_path(""), available, self.min_free_space));
+    }
     Ok(())
   }
 
@@ -664,36 +2325,203 @@ impl Downloader {
   ///
   ///
   fn download_and_patch(&self, key: &str, download_entry: &DownloadEntry) -> Result<(), Error> {
-    for attempt in 0..5 {
-      let mirror = self.mirrors.get_mirror();
-      let download_url = match download_entry.patch_entries[0].has_source {
-        true => format!("{}/delta/{}", &mirror.address, &key),
-        false => format!("{}/full/{}", &mirror.address, &key)
+    let _current_file = CurrentFile::enter(&self.state, &download_entry.patch_entries[0].target_path);
+    let cancelled = self.download_tokens.lock_unpoisoned().get(key).cloned().unwrap_or_else(|| Arc::new(AtomicBool::new(false)));
+    let mut attempts : Vec<DownloadAttempt> = Vec::new();
+    let mut corrupt_mirrors : Vec<String> = Vec::new(); //Mirrors that sent this file with the wrong hash, once per time they did
+    let mut resized_entry : Option<DownloadEntry> = None;
+    let mut size_checked = !self.trust_server_size;
+    let mut size_check_failed = false;
+    let mut attempt = 0;
+    while attempt < self.retry_policy.total {
+      self.paused.wait(None);
+      if cancelled.load(Ordering::SeqCst) {
+        return self.skip_cancelled(key, download_entry);
+      }
+      if self.out_of_space.lock_unpoisoned().is_some() {
+        return self.skip_out_of_space(key, download_entry);
+      }
+      let failed_mirrors : Vec<String> = attempts.iter().map(|attempt| attempt.mirror.clone()).collect();
+      let mirror = match self.retry_mirror(&failed_mirrors) {
+        Some(mirror) => mirror,
+        None => return Err(Error::download_failed(key.to_string(), download_entry.file_size, attempts))
+      };
+      let _in_use = mirror.acquire();
+      let download_url = match download_entry.patch_entries[0].kind {
+        DownloadKind::Delta => format!("{}/{}/{}", &mirror.address, &mirror.delta_path, &key),
+        DownloadKind::RawFull | DownloadKind::CompressedFull => format!("{}/{}/{}", &mirror.address, &mirror.full_path, &key)
       };
-      match self.download_file(&mirror, &download_url, download_entry, attempt == 0) {
+      if !size_checked {
+        match content_length(download_url.clone(), Duration::from_secs(10), self.mirrors.respect_env_proxy) {
+          Ok(served_size) => {
+            size_checked = true;
+            resized_entry = served_size.and_then(|served_size| self.resized_entry(&download_url, download_entry, served_size as usize));
+          },
+          //Asked again on the next attempt, but only reported once
+          Err(e) if !size_check_failed => {
+            println!("Couldn't ask {} for the size of {}: {}", &mirror.address, &key, e);
+            size_check_failed = true;
+          },
+          Err(_e) => {}
+        }
+      }
+      let entry = resized_entry.as_ref().unwrap_or(download_entry);
+      let result = if self.downloads_in_parts(entry.file_size) {
+        self.download_file_parallel(&mirror, &download_url, entry, attempt == 0, &cancelled)
+      } else {
+        self.download_file(&mirror, &download_url, entry, attempt == 0, &cancelled)
+      };
+      match result {
         Ok(()) => {
+          //Another mirror sent the file intact, so the ones that didn't are serving corrupt files
+          for address in corrupt_mirrors.iter().filter(|address| address.as_str() != mirror.address.as_str()) {
+            if let Some(corrupt_mirror) = self.mirrors.mirrors.iter().find(|corrupt_mirror| corrupt_mirror.address.as_str() == address.as_str()) {
+              println!("Removing mirror {}, it sent a corrupt copy of {}", address, &key);
+              self.mirrors.remove(corrupt_mirror.clone());
+            }
+          }
           break
         },
+        Err(_e) if cancelled.load(Ordering::SeqCst) => {
+          return self.skip_cancelled(key, download_entry);
+        },
+        Err(ref e) if matches!(e.kind, ErrorKind::InsufficientSpace { .. }) => {
+          return self.skip_out_of_space(key, download_entry);
+        },
+        Err(ref e) if e.kind == ErrorKind::Paused => {
+          //Doesn't count as an attempt, the download continues from the last complete part once resumed
+          println!("Download {} was paused", &download_url);
+          continue;
+        },
         Err(e) => {
           println!("Download {} failed with error message: {}", &download_url, e);
-          if attempt == 4 { return Err(format!("Couldn't download file: {}", &key).into()) }
+          attempts.push(DownloadAttempt {
+            mirror: mirror.address.to_string(),
+            url: download_url.clone(),
+            error: format!("{}", e),
+          });
+          #[cfg(feature = "metrics")]
+          {
+            *self.mirror_failures.lock_unpoisoned().entry(mirror.address.to_string()).or_insert(0) += 1;
+          }
+          if attempt + 1 == self.retry_policy.total { return Err(Error::download_failed(key.to_string(), download_entry.file_size, attempts)) }
           else {
             println!("Downloading file from {} failed due to error: {}", download_url, e);
-            if e.remove_mirror {
+            match e.kind {
+              ErrorKind::RateLimited { retry_after } => std::thread::sleep(retry_after),
+              _ => std::thread::sleep(self.retry_policy.backoff.delay(attempts.len()))
+            }
+            //A mirror that sends the wrong bytes twice is more likely to be corrupt than the connection
+            let corrupt_again = match e.kind {
+              ErrorKind::HashMismatch { .. } => {
+                let corrupt_again = self.retry_policy.remove_on_hash_mismatch || corrupt_mirrors.iter().any(|address| address.as_str() == mirror.address.as_str());
+                corrupt_mirrors.push(mirror.address.to_string());
+                corrupt_again
+              },
+              _ => false
+            };
+            if e.remove_mirror || corrupt_again {
               println!("Removing mirror: {}", mirror.address);
               self.mirrors.remove(mirror);
             }
           }
         }
       };
+      attempt += 1;
     }
     //apply delta
-    let mut patch_queue = self.patch_queue.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut patch_queue = self.patch_queue.lock_unpoisoned();
     patch_queue.push(download_entry.patch_entries.clone());
     drop(patch_queue);
     Ok(())
   }
 
+  ///
+  /// The mirror for the next attempt, given the mirror of every failed attempt so far, or None if no mirror has attempts left.
+  /// Every retry goes to a mirror that hasn't failed yet, as long as there is one.
+  ///
+  fn retry_mirror(&self, failed_mirrors: &[String]) -> Option<Mirror> {
+    let exhausted = self.retry_policy.exhausted(failed_mirrors);
+    let has_attempts_left = |mirror: &Mirror| !exhausted.iter().any(|address| address == mirror.address.as_str());
+    self.mirrors.try_get_mirror_avoiding(failed_mirrors).filter(|mirror| has_attempts_left(mirror))
+      .or_else(|| self.mirrors.try_get_mirror_avoiding(&exhausted).filter(|mirror| has_attempts_left(mirror)))
+  }
+
+  ///
+  /// A copy of the download entry with the size the server reported, or None if that's the size instructions.json has as well.
+  ///
+  fn resized_entry(&self, download_url: &str, download_entry: &DownloadEntry, served_size: usize) -> Option<DownloadEntry> {
+    if served_size == download_entry.file_size {
+      return None;
+    }
+    println!("{} is {} bytes, while instructions.json says {} bytes. Is instructions.json out of date?", download_url, served_size, download_entry.file_size);
+    let mut state = self.state.lock_unpoisoned();
+    state.download_size.1 = (state.download_size.1 + served_size as u64).saturating_sub(download_entry.file_size as u64);
+    drop(state);
+    Some(DownloadEntry {
+      file_path: download_entry.file_path.clone(),
+      file_size: served_size,
+      file_hash: download_entry.file_hash.clone(),
+      //The part hashes were made for parts of a file of the size instructions.json has, only the whole file can be checked
+      part_hashes: Vec::new(),
+      patch_entries: download_entry.patch_entries.clone(),
+      resumed_size: download_entry.resumed_size,
+    })
+  }
+
+  ///
+  /// How much of a download is on disk already from an earlier run, going by the part counter or part map that `download_file` and `download_file_parallel` keep.
+  /// Counted while planning, so the progress of a resumed update is right from the start rather than once each file gets downloaded.
+  ///
+  fn resumed_size(&self, file_path: &str, file_size: usize) -> u64 {
+    let parts_amount : usize = file_size / PART_SIZE + if file_size % PART_SIZE > 0 {1} else {0};
+    if self.downloads_in_parts(file_size) {
+      if let Some(complete_parts) = PartMap::read_complete(&format!("{}.parts", file_path), parts_amount) {
+        return complete_parts.into_iter().map(|part| std::cmp::min(PART_SIZE, file_size - part * PART_SIZE) as u64).sum();
+      }
+    }
+    match OpenOptions::new().read(true).open(file_path) {
+      Ok(mut file) if file.metadata().map_or(false, |metadata| metadata.len() == (file_size + 4) as u64) => {
+        std::cmp::min(read_resume_part(&mut file, file_size as u64, parts_amount) * PART_SIZE, file_size) as u64
+      },
+      _ => 0
+    }
+  }
+
+  ///
+  /// Replaces the bytes counted for a partial download while planning with what the download found when it started.
+  ///
+  fn count_resumed(&self, download_entry: &DownloadEntry, resumed_size: u64) {
+    let mut state = self.state.lock_unpoisoned();
+    state.download_size.0 = (state.download_size.0 + resumed_size).saturating_sub(download_entry.resumed_size);
+  }
+
+  ///
+  /// Leaves the files of a cancelled download unpatched, and takes the download out of `download_size`.
+  /// The parts that were kept for a later resume are all that's still counted of it by now, unverified ones were uncounted when the transfer stopped.
+  ///
+  fn skip_cancelled(&self, key: &str, download_entry: &DownloadEntry) -> Result<(), Error> {
+    println!("Download {} was cancelled", &key);
+    let kept_size = self.resumed_size(&download_entry.file_path, download_entry.file_size);
+    let mut state = self.state.lock_unpoisoned();
+    state.patch_files.1 -= download_entry.patch_entries.len() as u64;
+    state.download_size.0 = state.download_size.0.saturating_sub(kept_size);
+    state.download_size.1 = state.download_size.1.saturating_sub(download_entry.file_size as u64);
+    drop(state);
+    Ok(())
+  }
+
+  ///
+  /// Leaves a download for later like `skip_cancelled`, because the drive got too full. `download_files()` reports it once every download stopped.
+  ///
+  fn skip_out_of_space(&self, key: &str, download_entry: &DownloadEntry) -> Result<(), Error> {
+    println!("Download {} was stopped, the drive is running out of space", &key);
+    let mut state = self.state.lock_unpoisoned();
+    state.patch_files.1 -= download_entry.patch_entries.len() as u64;
+    drop(state);
+    Ok(())
+  }
+
   ///
   ///
   ///
@@ -701,38 +2529,63 @@ impl Downloader {
   fn check_patch_queue(&self) -> std::thread::JoinHandle<()> {
     let unlocked_state = self.state.clone();
     let patch_queue_unlocked = self.patch_queue.clone();
-    let renegadex_location = self.renegadex_location.clone();
+    let fallback_queue = self.fallback_queue.clone();
+    let patched_files = self.patched_files.clone();
+    let patch_options = self.patch_options.clone();
     let num_threads = num_cpus::get()-1;
     std::thread::spawn(move || {
-      let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().expect(concat!(module_path!(),":",file!(),":",line!()));
+      let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).thread_name(|index| format!("patcher-apply-{}", index)).build().expect(concat!(module_path!(),":",file!(),":",line!()));
       pool.install(|| {
         rayon::scope(|s| {
           for _i in 0..num_threads {
             s.spawn(|_| {
-              let state = unlocked_state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+              let state = unlocked_state.lock_unpoisoned();
               let mut patch_files = state.patch_files;
               drop(state);
               while patch_files.0 != patch_files.1 {
                 // Check for entry in patch_queue, get one, remove it, free the mutex, process entry.
                 let patch_entries : Option<Vec<PatchEntry>>;
                 {
-                  let mut patch_queue = patch_queue_unlocked.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+                  let mut patch_queue = patch_queue_unlocked.lock_unpoisoned();
                   patch_entries = patch_queue.pop();
                   drop(patch_queue);
                 }
                 if patch_entries.is_some() {
+                  //A download shared by several targets has to stay where it is until all of them have a copy
+                  let patch_options = match patch_entries.borrow().len() {
+                    1 => patch_options.clone(),
+                    _ => PatchOptions { write_strategy: WriteStrategy::Copy, ..patch_options.clone() }
+                  };
                   patch_entries.borrow().par_iter().for_each(|patch_entry| {
                     //println!("Patching with diff file: {}", &patch_entry.delta_path);
-                    apply_patch(patch_entry, unlocked_state.clone()).expect(concat!(module_path!(),":",file!(),":",line!()));
+                    match apply_patch(patch_entry, &patch_options, unlocked_state.clone()).expect(concat!(module_path!(),":",file!(),":",line!())) {
+                      PatchOutcome::Applied => {
+                        patched_files.lock_unpoisoned().push(FileChange {
+                          path: patch_entry.target_path.clone(),
+                          new_hash: patch_entry.target_hash.clone(),
+                        });
+                      },
+                      PatchOutcome::Skipped => {
+                        unlocked_state.lock_unpoisoned().patch_files.1 -= 1;
+                      },
+                      PatchOutcome::SourceChanged => {
+                        fallback_queue.lock_unpoisoned().push(patch_entry.clone());
+                        unlocked_state.lock_unpoisoned().patch_files.1 -= 1;
+                      }
+                    }
                     //println!("Patching success: {}", &patch_entry.delta_path);
                   });
-                  std::fs::remove_file(patch_entries.borrow().first().expect(concat!(module_path!(),":",file!(),":",line!())).delta_path.clone()).expect(concat!(module_path!(),":",file!(),":",line!()));
-                  let state = unlocked_state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+                  //Gone already if it was moved into place
+                  let delta_path = patch_entries.borrow().first().expect(concat!(module_path!(),":",file!(),":",line!())).delta_path.clone();
+                  if std::path::Path::new(&delta_path).exists() {
+                    std::fs::remove_file(&delta_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+                  }
+                  let state = unlocked_state.lock_unpoisoned();
                   patch_files = state.patch_files;
                   drop(state);
                 } else {
                   std::thread::sleep(std::time::Duration::from_millis(20));
-                  let state = unlocked_state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+                  let state = unlocked_state.lock_unpoisoned();
                   patch_files = state.patch_files;
                   drop(state);
                 }
@@ -741,22 +2594,103 @@ impl Downloader {
           }
         });
         {
-          let mut state = unlocked_state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+          let mut state = unlocked_state.lock_unpoisoned();
           state.finished_patching = true;
           drop(state);
         }
-        //remove patcher folder and all remaining files in there:
-        std::fs::remove_dir_all(format!("{}patcher/", renegadex_location.expect(concat!(module_path!(),":",file!(),":",line!())))).expect(concat!(module_path!(),":",file!(),":",line!()));
       });
     })
   }
 
+  ///
+  /// Opens a connection to every enabled mirror ahead of time, e.g. while `plan()` is hashing on another thread, so the first download from each mirror doesn't wait for the handshake.
+  /// The mirrors are connected to over plain TCP and there is no pool of HTTP clients, so a warmed connection is only used by the next download from its mirror.
+  ///
+  pub fn warm_connections(&self) -> Result<(), Error> {
+    let enabled_mirrors : Vec<Mirror> = self.mirrors.mirrors.iter().filter(|mirror| *mirror.enabled.lock_unpoisoned()).cloned().collect();
+    if enabled_mirrors.is_empty() {
+      return Err("No mirrors found! Did you retrieve mirrors?".to_string().into());
+    }
+    let enabled_mirrors : Vec<Mirror> = enabled_mirrors.into_iter().filter(|mirror| !mirror.is_local()).collect();
+    let connections : Vec<(String, std::io::Result<std::net::TcpStream>, Duration)> = self.install(|| enabled_mirrors.par_iter().map(|mirror| {
+      let started = Instant::now();
+      let connection = std::net::ToSocketAddrs::to_socket_addrs(&mirror.ip).and_then(|mut addresses| {
+        let address = addresses.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "The mirror has no address"))?;
+        std::net::TcpStream::connect_timeout(&address, Duration::from_secs(5))
+      });
+      (mirror.address.to_string(), connection, started.elapsed())
+    }).collect());
+    let mut warm_connections = self.warm_connections.lock_unpoisoned();
+    for (address, connection, elapsed) in connections {
+      match connection {
+        Ok(stream) => {
+          println!("Connected to {} ahead of time, saving {} ms on its first download", &address, elapsed.as_millis());
+          warm_connections.entry(address).or_insert_with(Vec::new).push(stream);
+        },
+        Err(e) => println!("Couldn't connect to {} ahead of time: {}", &address, e)
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Takes a connection to the mirror that `warm_connections()` opened, if the mirror hasn't closed it in the meantime, or otherwise opens a new one.
+  ///
+  fn connect(&self, mirror: &Mirror) -> Result<std::net::TcpStream, Error> {
+    let warmed = self.warm_connections.lock_unpoisoned().get_mut(mirror.address.as_str()).and_then(|streams| streams.pop());
+    if let Some(stream) = warmed {
+      if is_open(&stream) {
+        return Ok(stream);
+      }
+    }
+    Ok(std::net::TcpStream::connect(&mirror.ip)?)
+  }
+
+  ///
+  /// Connects to the proxy for `url` if there is one, see `set_respect_env_proxy()`, and to the mirror otherwise.
+  /// Returns the connection and whether it goes to a proxy.
+  ///
+  fn connect_for(&self, mirror: &Mirror, url: &hyper::Uri) -> Result<(std::net::TcpStream, bool), Error> {
+    let proxy = if self.mirrors.respect_env_proxy { proxy::env_proxy(&url.to_string()) } else { None };
+    match proxy {
+      Some(proxy) => Ok((proxy::connect(&proxy)?, true)),
+      None => Ok((self.connect(mirror)?, false))
+    }
+  }
+
+  ///
+  /// Sets up the connection and the GET request for `url` on `mirror` in the HTTP version of `set_http_version()`.
+  /// HTTP/2 and proxies need the whole url as the target, HTTP/1.1 just the path and a host header.
+  ///
+  fn start_request(&self, mirror: &Mirror, url: &hyper::Uri, proxied: bool) -> (hyper::client::conn::Builder, http::request::Builder) {
+    let http2 = !proxied && match self.http_version {
+      HttpVersion::Auto => mirror.http2,
+      HttpVersion::Http1 => false,
+      HttpVersion::Http2 => true,
+    };
+    let mut connection_builder = hyper::client::conn::Builder::new();
+    connection_builder.http2_only(http2);
+    let mut req = hyper::Request::builder();
+    if http2 || proxied {
+      req.uri(url.clone());
+    } else {
+      req.uri(url.path());
+    }
+    if !http2 {
+      req.header("host", url.host().expect(concat!(module_path!(),":",file!(),":",line!())));
+    }
+    req.header("User-Agent", "sonny-launcher/1.0");
+    //The parts are hashed as they are on the mirror, a compressed response would never match
+    req.header("Accept-Encoding", "identity");
+    (connection_builder, req)
+  }
+
   ///
   /// Downloads the file in parts
   ///
   ///
-  fn download_file(&self, mirror: &Mirror, download_url: &str, download_entry: &DownloadEntry, first_attempt: bool) -> Result<(), Error> {
-    let part_size = 10u64.pow(6) as usize; //1.000.000
+  fn download_file(&self, mirror: &Mirror, download_url: &str, download_entry: &DownloadEntry, first_attempt: bool, cancelled: &Arc<AtomicBool>) -> Result<(), Error> {
+    let part_size = PART_SIZE;
     let mut f = match OpenOptions::new().read(true).write(true).create(true).open(&download_entry.file_path) {
       Ok(file) => file,
       Err(e) => {
@@ -766,13 +2700,15 @@ impl Downloader {
     //set the size of the file, add a 32bit integer to the end of the file as a means of tracking progress. We won't download parts async.
     let parts_amount : usize = download_entry.file_size / part_size + if download_entry.file_size % part_size > 0 {1} else {0};
     let file_size : usize = download_entry.file_size + 4;
-    if (f.metadata().expect(concat!(module_path!(),":",file!(),":",line!())).len() as usize) < file_size {
-      if f.metadata().expect(concat!(module_path!(),":",file!(),":",line!())).len() == (download_entry.file_size as u64) {
+    //A file that is longer than expected is left over from something else, the counter is checked below
+    if (f.metadata().expect(concat!(module_path!(),":",file!(),":",line!())).len() as usize) != file_size {
+      let complete = f.metadata().expect(concat!(module_path!(),":",file!(),":",line!())).len() == (download_entry.file_size as u64);
+      if complete {
         //If hash is correct, return.
         //Otherwise download again.
-        let hash = get_hash(&download_entry.file_path);
+        let hash = self.patch_options.hasher.hash(&download_entry.file_path)?;
         if hash == download_entry.file_hash {
-          let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
+          let mut state = self.state.lock_unpoisoned();
           state.download_size.0 += (download_entry.file_size) as u64;
           drop(state);
           return Ok(());
@@ -784,134 +2720,644 @@ impl Downloader {
           return Err(format!("Could not change file size of patch file, is it in use?\n{}",e).into());
         }
       }
+      //Only what comes after the first part that doesn't match its hash is downloaded again
+      if complete && download_entry.part_hashes.len() == parts_amount {
+        let good_parts = (0..parts_amount).take_while(|&part| part_matches(&mut f, part, part_size, download_entry.file_size as u64, &download_entry.part_hashes[part])).count();
+        f.seek(SeekFrom::Start(download_entry.file_size as u64))?;
+        f.write_all(&(good_parts as u32).to_be_bytes())?;
+      }
     }
     //We have set up the file
-    f.seek(SeekFrom::Start(download_entry.file_size as u64)).expect(concat!(module_path!(),":",file!(),":",line!()));
-    let mut buf = [0,0,0,0];
-    f.read_exact(&mut buf).expect(concat!(module_path!(),":",file!(),":",line!()));
-    let resume_part : usize = u32::from_be_bytes(buf) as usize;
+    let resume_part : usize = read_resume_part(&mut f, download_entry.file_size as u64, parts_amount);
+    let rewound = read_counter(&mut f, download_entry.file_size as u64) & REWOUND != 0;
     if resume_part != 0 { 
       println!("Resuming download \"{}\" from part {} out of {}", &download_entry.file_path, resume_part, parts_amount);
-      if first_attempt {
-        let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-        state.download_size.0 += (part_size * resume_part) as u64;
-        drop(state);
-      }
     };
+    if first_attempt {
+      self.count_resumed(download_entry, std::cmp::min(part_size * resume_part, download_entry.file_size) as u64);
+    }
 
-    let future;
+    //Part hashes are optional, without them we can only verify the file as a whole.
+    let part_hashes = if download_entry.part_hashes.len() == parts_amount { download_entry.part_hashes.clone() } else { Vec::new() };
+    let verify_parts = !part_hashes.is_empty();
+    //(verified parts, found a corrupt part, bytes written)
+    let part_verification = Arc::new(Mutex::new((resume_part, false, 0u64)));
     {
-      let unlocked_state = self.state.clone();
+      let transfer = self.transfer(mirror, cancelled, download_entry.file_size.saturating_sub(part_size * resume_part) as u64);
+      let part_verification = part_verification.clone();
+      let corrupt = transfer.corrupt.clone();
+      let entry_size = download_entry.file_size as u64;
       let mut writer = BufWriter::new(f.try_clone().expect(concat!(module_path!(),":",file!(),":",line!())), move | writer, total_written | {
         //When the buffer is being written to file, this closure gets executed
-        let parts = *total_written / part_size as u64;
+        let mut parts = *total_written / part_size as u64;
+        if verify_parts {
+          //The counter never moves past a corrupt part, so the next attempt resumes right there.
+          let complete_parts = if *total_written >= entry_size { parts_amount } else { parts as usize };
+          let mut verification = part_verification.lock_unpoisoned();
+          while !verification.1 && verification.0 < complete_parts {
+            if part_matches(writer, verification.0, part_size, entry_size, &part_hashes[verification.0]) {
+              verification.0 += 1;
+            } else {
+              verification.1 = true;
+              corrupt.store(true, Ordering::SeqCst);
+            }
+          }
+          verification.2 = *total_written;
+          parts = std::cmp::min(parts, verification.0 as u64);
+        }
         writer.seek(SeekFrom::End(-4)).expect(concat!(module_path!(),":",file!(),":",line!()));
         writer.write_all(&(parts as u32).to_be_bytes()).expect(concat!(module_path!(),":",file!(),":",line!()));
         writer.seek(SeekFrom::Start(*total_written)).expect(concat!(module_path!(),":",file!(),":",line!()));
       });
       writer.seek(SeekFrom::Start((part_size * resume_part) as u64)).expect(concat!(module_path!(),":",file!(),":",line!()));
 
-      let url = download_url.parse::<hyper::Uri>().expect(concat!(module_path!(),":",file!(),":",line!()));
-      let trunc_size = download_entry.file_size as u64;
-      future = tokio::net::TcpStream::from_std(std::net::TcpStream::connect(&mirror.ip).expect(concat!(module_path!(),":",file!(),":",line!())), &tokio_reactor::Handle::default()).map(|tcp| {
-        hyper::client::conn::handshake(tcp)
-      }).expect(concat!(module_path!(),":",file!(),":",line!())).and_then(move |(mut client, conn)| {
-        let mut req = hyper::Request::builder();
-        req.uri(url.path()).header("host", url.host().expect(concat!(module_path!(),":",file!(),":",line!()))).header("User-Agent", "sonny-launcher/1.0");
-        if resume_part != 0 {
-          req.header("Range", format!("bytes={}-{}", (part_size * resume_part), download_entry.file_size));
-        };
-        let req = req.body(hyper::Body::empty()).expect(concat!(module_path!(),":",file!(),":",line!()));
-        let res = client.send_request(req).and_then(move |res| process_response(res, writer, unlocked_state, f, trunc_size));
-        // Put in an Option so poll_fn can return it later
-        let mut conn = Some(conn);
-        let until_upgrade = futures::future::poll_fn(move || {
-          try_ready!(conn.as_mut().expect(concat!(module_path!(),":",file!(),":",line!())).poll_without_shutdown());
-          Ok(futures::Async::Ready(conn.take().expect(concat!(module_path!(),":",file!(),":",line!()))))
-        });
+      if let Some(source) = downloader::local_path(download_url) {
+        copy_local(&source, (part_size * resume_part) as u64, writer, transfer)?;
+      } else {
+        let url = download_url.parse::<hyper::Uri>().expect(concat!(module_path!(),":",file!(),":",line!()));
+        let (stream, proxied) = self.connect_for(mirror, &url)?;
+        let (connection_builder, mut req) = self.start_request(mirror, &url, proxied);
+        let future = tokio::net::TcpStream::from_std(stream, &tokio_reactor::Handle::default()).map(|tcp| {
+          connection_builder.handshake(tcp)
+        }).expect(concat!(module_path!(),":",file!(),":",line!())).and_then(move |(mut client, conn)| {
+          if resume_part != 0 {
+            req.header("Range", format!("bytes={}-{}", (part_size * resume_part), download_entry.file_size));
+          };
+          let req = req.body(hyper::Body::empty()).expect(concat!(module_path!(),":",file!(),":",line!()));
+          let res = client.send_request(req).and_then(move |res| process_response(res, writer, transfer));
+          // Put in an Option so poll_fn can return it later
+          let mut conn = Some(conn);
+          let until_upgrade = futures::future::poll_fn(move || {
+            try_ready!(conn.as_mut().expect(concat!(module_path!(),":",file!(),":",line!())).poll_without_shutdown());
+            Ok(futures::Async::Ready(conn.take().expect(concat!(module_path!(),":",file!(),":",line!()))))
+          });
 
-        res.join(until_upgrade)
-      }).and_then(move |(result, client)| {
-        drop(client);
-        Ok(result)
-      });
+          res.join(until_upgrade)
+        }).and_then(move |(result, client)| {
+          drop(client);
+          Ok(result)
+        });
+        tokio::runtime::current_thread::Runtime::new().expect(concat!(module_path!(),":",file!(),":",line!())).block_on(future)??;
+      }
     }
-    tokio::runtime::current_thread::Runtime::new().expect(concat!(module_path!(),":",file!(),":",line!())).block_on(future)??;
 
-    //Let's make sure the downloaded file matches the Hash found in Instructions.json
-    let hash = get_hash(&download_entry.file_path);
-    if hash != download_entry.file_hash {
-      let mut state = self.state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-      state.download_size.0 -= download_entry.file_size as u64;
-      drop(state);
-      return Err(format!("File \"{}\"'s hash ({}) did not match with the one provided in Instructions.json ({})", &download_entry.file_path, &hash, &download_entry.file_hash).into());
+    if verify_parts {
+      let (verified_parts, found_corrupt_part, written) = *part_verification.lock_unpoisoned();
+      if found_corrupt_part || verified_parts < parts_amount {
+        //Everything after the last good part will be downloaded again.
+        let mut state = self.state.lock_unpoisoned();
+        state.download_size.0 = state.download_size.0.saturating_sub(written.saturating_sub((verified_parts * part_size) as u64));
+        drop(state);
+        return Err(Error::hash_mismatch(format!("Part {} out of {} of \"{}\" did not match its hash", verified_parts, parts_amount, &download_entry.file_path), mirror.address.to_string()));
+      }
     }
-    Ok(())
-  }
-
-  ///
-  /// Spawns magical unicorns, only usefull for testing
-  ///
-  ///
-  pub fn poll_progress(&self) {
-    let state = self.state.clone();
-    std::thread::spawn(move || {
-      let mut finished_hash : bool;
-      let mut finished_patching = false;
-      let mut old_download_size : (u64, u64) = (0, 0);
-      let mut old_patch_files : (u64, u64) = (0, 0);
-      let mut old_hashes_checked : (u64, u64) = (0, 0);
-      while !finished_patching {
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        let state = state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-        finished_hash = state.finished_hash;
-        finished_patching = state.finished_patching;
-        let download_size : (u64, u64) = state.download_size;
-        let patch_files : (u64, u64) = state.patch_files;
-        let hashes_checked : (u64, u64) = state.hashes_checked;
+    //Let's make sure the downloaded file matches the Hash found in Instructions.json, unless every part was verified already.
+    //The progress counter stays at the end of the file while it's hashed, so a file that doesn't match can still be resumed.
+    if !verify_parts {
+      let hash = self.patch_options.hasher.hash_prefix(&download_entry.file_path, download_entry.file_size as u64)?;
+      if hash != download_entry.file_hash {
+        //Move the counter back, so the next attempt only downloads again what this one did
+        let resume_part = rewind_counter(&mut f, download_entry.file_size as u64, resume_part, parts_amount, rewound)?;
+        let mut state = self.state.lock_unpoisoned();
+        state.download_size.0 = state.download_size.0.saturating_sub((download_entry.file_size - std::cmp::min(part_size * resume_part, download_entry.file_size)) as u64);
         drop(state);
-        if !finished_hash {
-          if old_download_size != download_size {
-            println!("Comparing files, total to be downloaded: {:.1} MB", (download_size.1 as f64)*0.000_001);
-          }
-          if old_hashes_checked != hashes_checked {
-            println!("Checked {} out of {} hashes.", hashes_checked.0, hashes_checked.1);
-          }
-        } else {
-          if old_download_size != download_size {
-            println!("Downloaded {:.1}/{:.1} MB, speed: {}/s", (download_size.0 as f64)*0.000_001, (download_size.1 as f64)*0.000_001, convert((download_size.0 - old_download_size.0) as f64));
-          }
-          if patch_files != old_patch_files {
-            println!("Patched {}/{} files", patch_files.0, patch_files.1);
-          }
-        }
-        old_download_size = download_size;
-        old_patch_files = patch_files;
-        old_hashes_checked = hashes_checked;
+        return Err(Error::hash_mismatch(format!("File \"{}\"'s hash ({}) did not match with the one provided in Instructions.json ({})", &download_entry.file_path, &hash, &download_entry.file_hash), mirror.address.to_string()));
       }
-    });
+    }
+    //Remove the progress counter at the end of the file, the download is complete
+    f.set_len(download_entry.file_size as u64)?;
+    Ok(())
   }
 
+  ///
+  /// Downloads the file in parts like `download_file`, `intra_file_parallelism` parts at a time.
+  /// With `multi_source` the parts are spread over every enabled mirror, at least one part per mirror at a time, and a part that fails is retried on the next mirror.
+  /// The parts that are complete are kept track of in a part map next to the file rather than with a counter at its end, as they complete out of order.
+  ///
+  fn download_file_parallel(&self, mirror: &Mirror, download_url: &str, download_entry: &DownloadEntry, first_attempt: bool, cancelled: &Arc<AtomicBool>) -> Result<(), Error> {
+    let parts_path = format!("{}.parts", &download_entry.file_path);
+    let mut f = match OpenOptions::new().read(true).write(true).create(true).open(&download_entry.file_path) {
+      Ok(file) => file,
+      Err(e) => {
+        return Err(format!("Couldn't open delta_file \"{}\": {:?}", &download_entry.file_path, e).into());
+      }
+    };
+    let parts_amount : usize = download_entry.file_size / PART_SIZE + if download_entry.file_size % PART_SIZE > 0 {1} else {0};
+    let part_len = |part: usize| std::cmp::min(PART_SIZE, download_entry.file_size - part * PART_SIZE);
+    let len = f.metadata()?.len();
+    let mut complete_parts = 0;
+    if !std::path::Path::new(&parts_path).exists() {
+      if len == download_entry.file_size as u64 && self.patch_options.hasher.hash(&download_entry.file_path)? == download_entry.file_hash {
+        self.state.lock_unpoisoned().download_size.0 += download_entry.file_size as u64;
+        return Ok(());
+      }
+      //Left over from a download in sequence, which counted its complete parts at the end of the file
+      if len == (download_entry.file_size + 4) as u64 {
+        complete_parts = read_resume_part(&mut f, download_entry.file_size as u64, parts_amount);
+      }
+    }
+    if len != download_entry.file_size as u64 {
+      f.set_len(download_entry.file_size as u64)?;
+    }
+    let part_map = PartMap::open(&parts_path, parts_amount, complete_parts)?;
+    let missing = part_map.missing();
+    if first_attempt {
+      let complete_size : usize = (0..parts_amount).filter(|&part| part_map.is_complete(part)).map(part_len).sum();
+      if missing.len() != parts_amount {
+        println!("Resuming download \"{}\", {} out of {} parts are left", &download_entry.file_path, missing.len(), parts_amount);
+      }
+      self.count_resumed(download_entry, complete_size as u64);
+    }
+
+    let part_hashes = if download_entry.part_hashes.len() == parts_amount { download_entry.part_hashes.clone() } else { Vec::new() };
+    //The mirror the download was started on comes first, the others only help out with multi_source
+    let mut sources = vec![mirror.clone()];
+    if self.multi_source {
+      sources.extend(self.mirrors.mirrors.iter().filter(|other| *other.enabled.lock_unpoisoned() && other.address != mirror.address).cloned());
+    }
+    let threads = std::cmp::min(std::cmp::max(self.intra_file_parallelism, if self.multi_source { sources.len() } else { 1 }), missing.len());
+    //Popped from the back, so the parts are requested in order
+    let queue = Mutex::new(missing.into_iter().rev().collect::<Vec<usize>>());
+    //Failed attempts by part, a part is given up on once every source failed it
+    let part_failures = Mutex::new(HashMap::new());
+    let output = Mutex::new((f, part_map));
+    let failure : Mutex<Option<Error>> = Mutex::new(None);
+    rayon::scope(|s| {
+      for thread in 0..threads {
+        let (sources, queue, part_failures, output, failure, part_hashes) = (&sources, &queue, &part_failures, &output, &failure, &part_hashes);
+        s.spawn(move |_| {
+          let mut source = thread % sources.len();
+          loop {
+            if failure.lock_unpoisoned().is_some() || cancelled.load(Ordering::SeqCst) {
+              return;
+            }
+            let part = match queue.lock_unpoisoned().pop() {
+              Some(part) => part,
+              None => return
+            };
+            let part_mirror = &sources[source];
+            let part_url = url_on_mirror(download_url, mirror, part_mirror);
+            let _in_use = if source == 0 { None } else { Some(part_mirror.acquire()) };
+            let result = self.download_part(part_mirror, &part_url, part * PART_SIZE, part_len(part), cancelled).and_then(|data| {
+              if !part_hashes.is_empty() && hex::encode_upper(Sha256::digest(&data)) != part_hashes[part] {
+                let mut state = self.state.lock_unpoisoned();
+                state.download_size.0 = state.download_size.0.saturating_sub(data.len() as u64);
+                return Err(Error::hash_mismatch(format!("Part {} out of {} of \"{}\" did not match its hash", part, parts_amount, &download_entry.file_path), part_mirror.address.to_string()));
+              }
+              let mut output = output.lock_unpoisoned();
+              let (ref mut f, ref mut part_map) = *output;
+              f.seek(SeekFrom::Start((part * PART_SIZE) as u64))?;
+              f.write_all(&data)?;
+              part_map.complete(part)
+            });
+            if let Err(e) = result {
+              let mut part_failures = part_failures.lock_unpoisoned();
+              let failures = part_failures.entry(part).or_insert(0);
+              *failures += 1;
+              if *failures < sources.len() && !cancelled.load(Ordering::SeqCst) {
+                println!("Part {} of {} failed on {}, retrying it on another mirror: {}", part, download_url, &part_mirror.address, e);
+                queue.lock_unpoisoned().push(part);
+                source = (source + 1) % sources.len();
+                continue;
+              }
+              let mut failure = failure.lock_unpoisoned();
+              if failure.is_none() {
+                *failure = Some(e);
+              }
+            }
+          }
+        });
+      }
+    });
+    if let Some(e) = failure.into_inner().unwrap_or_else(|e| e.into_inner()) {
+      return Err(e);
+    }
+    if cancelled.load(Ordering::SeqCst) {
+      return Err(Error::new("The download was cancelled".to_string()));
+    }
+    let (f, _) = output.into_inner().unwrap_or_else(|e| e.into_inner());
+    f.sync_all()?;
+    drop(f);
+    std::fs::remove_file(&parts_path)?;
+
+    //Let's make sure the downloaded file matches the Hash found in Instructions.json, unless every part was verified already
+    if part_hashes.is_empty() {
+      let hash = self.patch_options.hasher.hash(&download_entry.file_path)?;
+      if hash != download_entry.file_hash {
+        let mut state = self.state.lock_unpoisoned();
+        state.download_size.0 = state.download_size.0.saturating_sub(download_entry.file_size as u64);
+        drop(state);
+        return Err(Error::hash_mismatch(format!("File \"{}\"'s hash ({}) did not match with the one provided in Instructions.json ({})", &download_entry.file_path, &hash, &download_entry.file_hash), mirror.address.to_string()));
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Downloads `len` bytes starting at `start` with a Range request, on a connection of its own.
+  /// The bytes that arrived are taken off the progress again if the part is incomplete.
+  ///
+  fn download_part(&self, mirror: &Mirror, download_url: &str, start: usize, len: usize, cancelled: &Arc<AtomicBool>) -> Result<Vec<u8>, Error> {
+    let received = Arc::new(Mutex::new(Vec::with_capacity(len)));
+    let result = if let Some(source) = downloader::local_path(download_url) {
+      downloader::read_local(&source, start as u64, Some(len)).map(|data| {
+        self.transfer(mirror, cancelled, len as u64).on_chunk(data.len());
+        *received.lock_unpoisoned() = data;
+      })
+    } else {
+      let writer = BufWriter::new(SharedBuffer(received.clone()), |_, _| {});
+      let transfer = self.transfer(mirror, cancelled, len as u64);
+      let url = download_url.parse::<hyper::Uri>()?;
+      let (stream, proxied) = self.connect_for(mirror, &url)?;
+      let (connection_builder, mut req) = self.start_request(mirror, &url, proxied);
+      let future = connection_builder.handshake(tokio::net::TcpStream::from_std(stream, &tokio_reactor::Handle::default())?).and_then(move |(mut client, conn)| {
+        req.header("Range", format!("bytes={}-{}", start, start + len - 1));
+        let req = req.body(hyper::Body::empty()).expect(concat!(module_path!(),":",file!(),":",line!()));
+        let res = client.send_request(req).and_then(move |res| process_response(res, writer, transfer));
+        let mut conn = Some(conn);
+        let until_upgrade = futures::future::poll_fn(move || {
+          try_ready!(conn.as_mut().expect(concat!(module_path!(),":",file!(),":",line!())).poll_without_shutdown());
+          Ok(futures::Async::Ready(conn.take().expect(concat!(module_path!(),":",file!(),":",line!()))))
+        });
+        res.join(until_upgrade)
+      }).map(|(result, _conn)| result);
+      tokio::runtime::current_thread::Runtime::new()?.block_on(future).unwrap_or_else(|e| Err(e.into()))
+    };
+    let data = std::mem::replace(&mut *received.lock_unpoisoned(), Vec::new());
+    let result = match result {
+      Ok(()) if data.len() == len => return Ok(data),
+      Ok(()) => Err(format!("Expected {} bytes from {} starting at {}, received {}. Does the mirror support Range requests?", len, download_url, start, data.len()).into()),
+      Err(e) => Err(e)
+    };
+    let mut state = self.state.lock_unpoisoned();
+    state.download_size.0 = state.download_size.0.saturating_sub(data.len() as u64);
+    result
+  }
+
+  ///
+  /// The progress reporting of a single transfer of `size` bytes from `mirror`.
+  ///
+  fn transfer(&self, mirror: &Mirror, cancelled: &Arc<AtomicBool>, size: u64) -> Transfer {
+    Transfer {
+      state: self.state.clone(),
+      throttle: self.throttle.clone(),
+      mirror_throughput: self.mirror_throughput.clone(),
+      mirror: mirror.address.to_string(),
+      minimum_speed: if mirror.is_local() { 0.0 } else { mirror.speed * 1000.0 * self.slow_mirror_threshold },
+      part_started: Instant::now(),
+      part_bytes: 0,
+      part_waited: Duration::from_secs(0),
+      slow_parts: 0,
+      budget: if self.min_acceptable_speed == 0 || mirror.is_local() { None } else { Some(Duration::from_secs_f64(size as f64 / self.min_acceptable_speed as f64)) },
+      first_chunk: None,
+      waited: Duration::from_secs(0),
+      min_free_space: self.min_free_space,
+      space_path: self.patch_path(""),
+      unchecked_bytes: self.unchecked_space.clone(),
+      out_of_space: self.out_of_space.clone(),
+      cancelled: cancelled.clone(),
+      paused: self.paused.clone(),
+      released: Arc::new(AtomicBool::new(false)),
+      corrupt: Arc::new(AtomicBool::new(false)),
+      over_budget: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  ///
+  /// Spawns magical unicorns, only usefull for testing
+  /// Does nothing while a thread from an earlier call is still polling.
+  ///
+  pub fn poll_progress(&self) {
+    let mut progress_thread = self.progress_thread.lock_unpoisoned();
+    if progress_thread.as_ref().map_or(false, |handle| !handle.is_finished()) {
+      return;
+    }
+    let state = self.state.clone();
+    let shutting_down = self.shutting_down.clone();
+    let event_senders = self.event_senders.clone();
+    let handle = std::thread::spawn(move || {
+      let mut finished_hash : bool;
+      let mut finished_patching = false;
+      let mut old_download_size : (u64, u64) = (0, 0);
+      let mut old_patch_files : (u64, u64) = (0, 0);
+      let mut old_hashes_checked : (u64, u64) = (0, 0);
+      let mut old_hash_progress : (u64, u64) = (0, 0);
+      let mut old_instructions_processed : (u64, u64) = (0, 0);
+      while !finished_patching && !shutting_down.load(Ordering::SeqCst) {
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        let state = state.lock_unpoisoned();
+        finished_hash = state.finished_hash;
+        finished_patching = state.finished_patching;
+        let download_size : (u64, u64) = state.download_size;
+        let patch_files : (u64, u64) = state.patch_files;
+        let hashes_checked : (u64, u64) = state.hashes_checked;
+        let hash_progress : (u64, u64) = state.hash_progress;
+        let instructions_processed : (u64, u64) = state.instructions_processed;
+        //Receivers that were dropped are forgotten
+        event_senders.lock_unpoisoned().retain(|sender| sender.send(state.clone()).is_ok());
+        drop(state);
+        if !finished_hash {
+          if old_instructions_processed != instructions_processed {
+            println!("Analyzed {} out of {} instructions.", instructions_processed.0, instructions_processed.1);
+          }
+          if old_download_size != download_size {
+            println!("Comparing files, total to be downloaded: {:.1} MB", (download_size.1 as f64)*0.000_001);
+          }
+          if old_hashes_checked != hashes_checked {
+            println!("Checked {} out of {} hashes.", hashes_checked.0, hashes_checked.1);
+          }
+          if old_hash_progress != hash_progress {
+            println!("Hashed {} out of {} files.", hash_progress.0, hash_progress.1);
+          }
+        } else {
+          if old_download_size != download_size {
+            println!("Downloaded {:.1}/{:.1} MB, speed: {}/s", (download_size.0 as f64)*0.000_001, (download_size.1 as f64)*0.000_001, convert(download_size.0.saturating_sub(old_download_size.0) as f64));
+          }
+          if patch_files != old_patch_files {
+            println!("Patched {}/{} files", patch_files.0, patch_files.1);
+          }
+        }
+        old_download_size = download_size;
+        old_patch_files = patch_files;
+        old_hashes_checked = hashes_checked;
+        old_hash_progress = hash_progress;
+        old_instructions_processed = instructions_processed;
+      }
+      //Closes the channels of progress_events()
+      event_senders.lock_unpoisoned().clear();
+    });
+    *progress_thread = Some(handle);
+  }
+
+  ///
+  /// A channel that receives a snapshot of the progress every time the progress thread polls, about once a second, starting the thread if it isn't running, see `poll_progress()`.
+  /// The channel is closed when the thread ends, after patching or on `shutdown()`.
+  ///
+  pub fn progress_events(&self) -> std::sync::mpsc::Receiver<Progress> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    self.event_senders.lock_unpoisoned().push(sender);
+    self.poll_progress();
+    receiver
+  }
+
+  ///
+  /// Stops the progress thread and waits for it to exit, closing the channels of `progress_events()`, then removes the patcher folder if nothing is left in it.
+  /// Use this instead of relying on `Drop` when the files need to be free again straight away.
+  ///
+  pub fn shutdown(self) -> Result<(), Error> {
+    self.shutting_down.store(true, Ordering::SeqCst);
+    let progress_thread = self.progress_thread.lock_unpoisoned().take();
+    if let Some(handle) = progress_thread {
+      if handle.join().is_err() {
+        return Err("The progress thread panicked".to_string().into());
+      }
+    }
+    self.event_senders.lock_unpoisoned().clear();
+    if let Some(location) = &self.renegadex_location {
+      let patch_dir_path = format!("{}{}", location, PATCH_SUBDIR);
+      let is_empty = std::fs::read_dir(&patch_dir_path).map(|mut iter| iter.next().is_none()).unwrap_or(false);
+      if is_empty {
+        std::fs::remove_dir(&patch_dir_path)?;
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  ///
   ///
   ///
-  ///
-  ///
   pub fn get_progress(&self) -> Arc<Mutex<Progress>> {
     self.state.clone()
   }
+
+  ///
+  /// Blocks until `download()` returns and passes on its result, or gives up after `timeout`.
+  /// Use `get_completion()` to wait from another thread while `download()` is running.
+  ///
+  pub fn wait_until_done(&self, timeout: Option<Duration>) -> Result<(), Error> {
+    self.completion.wait_until_done(timeout)
+  }
+
+  ///
+  /// Sets how much of `Progress::overall_progress()` hashing, downloading and patching each take up.
+  ///
+  pub fn set_phase_weights(&mut self, phase_weights: PhaseWeights) {
+    self.state.lock_unpoisoned().phase_weights = phase_weights;
+  }
+
+  ///
+  /// How much of the whole update is done, between 0 and 1, see `Progress::overall_progress()`.
+  ///
+  pub fn overall_progress(&self) -> f32 {
+    self.state.lock_unpoisoned().overall_progress()
+  }
+
+  pub fn get_completion(&self) -> Arc<Completion> {
+    self.completion.clone()
+  }
+
+  ///
+  /// What the last benchmark of the mirrors measured, and why the mirrors that got disabled were, in the order the mirrors are used in.
+  ///
+  pub fn benchmark_results(&self) -> &[BenchmarkResult] {
+    &self.mirrors.benchmark_results
+  }
+
+  ///
+  /// The files that are being downloaded or patched right now, relative to the RenegadeX location.
+  /// A file that one worker downloads while another one patches it is listed once.
+  ///
+  pub fn current_files(&self) -> Vec<String> {
+    let mut current_files = self.state.lock_unpoisoned().current_files.clone();
+    let mut seen = HashSet::new();
+    current_files.retain(|path| seen.insert(path.clone()));
+    match &self.renegadex_location {
+      Some(location) => current_files.into_iter().map(|path| path.strip_prefix(location.as_str()).map_or(path.clone(), |path| path.to_string())).collect(),
+      None => current_files
+    }
+  }
+
+  ///
+  /// Returns the current download speed of every mirror that has been used, in bytes per second.
+  ///
+  pub fn mirror_throughput(&self) -> HashMap<String, f64> {
+    let mut mirror_throughput = self.mirror_throughput.lock_unpoisoned();
+    mirror_throughput.iter_mut().map(|(address, throughput)| (address.clone(), throughput.bytes_per_second())).collect()
+  }
+
+  ///
+  /// Renders the progress of the current update in the Prometheus text exposition format, to be served on a metrics endpoint.
+  ///
+  #[cfg(feature = "metrics")]
+  pub fn gather_metrics(&self) -> String {
+    let progress = self.state.lock_unpoisoned().clone();
+    let mut mirror_failures : Vec<(String, u64)> = self.mirror_failures.lock_unpoisoned().iter().map(|(address, failures)| (address.clone(), *failures)).collect();
+    mirror_failures.sort();
+    let active_downloads : usize = self.mirrors.mirrors.iter().map(|mirror| mirror.in_use.load(Ordering::SeqCst)).sum();
+    let mut metrics = metrics::MetricsWriter::new();
+    metrics.metric("patcher_bytes_downloaded_total", "counter", "Bytes downloaded by the current update.", progress.download_size.0);
+    metrics.metric("patcher_files_patched_total", "counter", "Files patched by the current update.", progress.patch_files.0);
+    metrics.labelled_metric("patcher_mirror_failures_total", "counter", "Failed download attempts, by mirror.", "mirror", &mirror_failures);
+    metrics.metric("patcher_active_downloads", "gauge", "Downloads in progress.", active_downloads as u64);
+    metrics.finish()
+  }
+}
+
+///
+/// The shared state a single transfer reports to while its body is streamed in.
+///
+struct Transfer {
+  state: Arc<Mutex<Progress>>,
+  throttle: Arc<Mutex<Throttle>>,
+  mirror_throughput: Arc<Mutex<HashMap<String, Throughput>>>,
+  mirror: String,
+  minimum_speed: f64, //Bytes per second a part has to reach, 0 disables the check
+  part_started: Instant,
+  part_bytes: u64,
+  part_waited: Duration, //Time spent waiting for the throttle or while paused, which doesn't count against the mirror
+  slow_parts: usize,
+  budget: Option<Duration>, //How long the whole transfer may take, see set_min_acceptable_speed
+  first_chunk: Option<Instant>,
+  waited: Duration, //Like part_waited, for the whole transfer
+  min_free_space: u64, //See set_min_free_space, 0 disables the check
+  space_path: String,
+  unchecked_bytes: Arc<AtomicU64>, //Received by every transfer since the free space was last checked, the first chunk is checked straight away
+  out_of_space: Arc<Mutex<Option<u64>>>,
+  cancelled: Arc<AtomicBool>,
+  paused: Arc<PauseFlag>,
+  released: Arc<AtomicBool>, //Set when the connection was closed because the download stayed paused for too long
+  corrupt: Arc<AtomicBool>, //Set when a part didn't match its hash, the rest of the body would be downloaded again anyway
+  over_budget: Arc<AtomicBool>, //Set when the transfer was abandoned for using up its budget rather than for a few slow parts
+}
+
+/// The size of the parts downloads are split into, each of which can be resumed and verified on its own.
+const PART_SIZE : usize = 1_000_000;
+/// The amount of slow parts in a row after which a mirror is abandoned.
+const SLOW_PARTS : usize = 3;
+/// How long a paused download keeps its connection open.
+const PAUSE_GRACE : Duration = Duration::from_secs(30);
+/// How many bytes the downloads receive between two checks of the free space.
+const SPACE_CHECK_INTERVAL : u64 = 64_000_000;
+
+impl Transfer {
+  ///
+  /// Records a received chunk, returns false once the mirror has been too slow for too long, has used up the budget of the transfer, the drive is too full or the download got cancelled.
+  /// Blocks while the downloads are paused, and returns false as well if that takes longer than `PAUSE_GRACE`.
+  ///
+  fn on_chunk(&mut self, len: usize) -> bool {
+    if self.cancelled.load(Ordering::SeqCst) {
+      return false;
+    }
+    let first_chunk = *self.first_chunk.get_or_insert_with(Instant::now);
+    let pause_started = Instant::now();
+    if self.paused.wait(Some(PAUSE_GRACE)) {
+      self.released.store(true, Ordering::SeqCst);
+      return false;
+    }
+    //Time spent paused doesn't count against the mirror
+    self.part_waited += pause_started.elapsed();
+    self.waited += pause_started.elapsed();
+    let mut state = self.state.lock_unpoisoned();
+    state.download_size.0 += len as u64;
+    drop(state);
+    let mut mirror_throughput = self.mirror_throughput.lock_unpoisoned();
+    mirror_throughput.entry(self.mirror.clone()).or_insert_with(Throughput::new).record(len as u64);
+    drop(mirror_throughput);
+    //Every download has its own runtime, so sleeping here only holds back this download
+    let wait = self.throttle.lock_unpoisoned().consume(len as u64);
+    if wait > Duration::from_secs(0) {
+      std::thread::sleep(wait);
+    }
+    self.part_bytes += len as u64;
+    self.part_waited += wait;
+    self.waited += wait;
+    if !self.has_free_space(len) {
+      return false;
+    }
+    if let Some(budget) = self.budget {
+      if first_chunk.elapsed().checked_sub(self.waited).map_or(false, |elapsed| elapsed > budget) {
+        self.over_budget.store(true, Ordering::SeqCst);
+        return false;
+      }
+    }
+    if self.minimum_speed <= 0.0 || self.part_bytes < 10u64.pow(6) {
+      return true;
+    }
+    let elapsed = self.part_started.elapsed().checked_sub(self.part_waited).unwrap_or_else(|| Duration::from_secs(0));
+    let speed = self.part_bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    self.slow_parts = if speed < self.minimum_speed { self.slow_parts + 1 } else { 0 };
+    self.part_started = Instant::now();
+    self.part_bytes = 0;
+    self.part_waited = Duration::from_secs(0);
+    self.slow_parts < SLOW_PARTS
+  }
+
+  ///
+  /// Checks the free space on the drive once every `SPACE_CHECK_INTERVAL` bytes of all transfers together, and whether another transfer found it too full already.
+  ///
+  fn has_free_space(&mut self, len: usize) -> bool {
+    if self.min_free_space == 0 {
+      return true;
+    }
+    if self.out_of_space.lock_unpoisoned().is_some() {
+      return false;
+    }
+    if self.unchecked_bytes.fetch_add(len as u64, Ordering::SeqCst) + (len as u64) < SPACE_CHECK_INTERVAL {
+      return true;
+    }
+    if self.unchecked_bytes.swap(0, Ordering::SeqCst) < SPACE_CHECK_INTERVAL {
+      //Another transfer got to check it
+      return true;
+    }
+    match diagnostics::available_space(&self.space_path) {
+      Ok(available) if available < self.min_free_space => {
+        self.out_of_space.lock_unpoisoned().get_or_insert(available);
+        false
+      },
+      Ok(_) => true,
+      Err(e) => {
+        //Better to carry on than to stop every download over a check that doesn't work here
+        println!("Couldn't check the free space on \"{}\": {}", &self.space_path, e);
+        true
+      }
+    }
+  }
 }
 
-fn process_response<W: Write, F: FnMut(&mut W, &mut u64)>(res: hyper::Response<hyper::Body>, mut writer: downloader::BufWriter<W, F>, unlocked_state: Arc<Mutex<Progress>>, f: std::fs::File, trunc_size: u64) 
+fn process_response<W: Write, F: FnMut(&mut W, &mut u64)>(res: hyper::Response<hyper::Body>, mut writer: downloader::BufWriter<W, F>, mut transfer: Transfer) 
 -> impl Future<Item = Result<(), traits::Error>, Error = hyper::Error> {
   use hyper::rt::*;
-  let abort_in_error = res.status() != 200 && res.status() != 206;
+  let status = res.status();
+  //Some CDNs compress ranged responses despite being asked not to, which would be written to the file as is
+  let content_encoding = res.headers().get(hyper::header::CONTENT_ENCODING).map(|value| value.to_str().unwrap_or("unknown").to_string()).filter(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+  let abort_in_error = (status != 200 && status != 206) || content_encoding.is_some();
+  let retry_after = match status.as_u16() {
+    429 | 503 => res.headers().get(hyper::header::RETRY_AFTER).and_then(|value| value.to_str().ok()).map(|retry_after| {
+      retry::retry_delay(retry_after, res.headers().get(hyper::header::DATE).and_then(|value| value.to_str().ok()))
+    }),
+    _ => None
+  };
+  //Set when the mirror got too slow, the rest of the body is dropped while the parts written so far are kept
+  let abandoned = Arc::new(AtomicBool::new(false));
+  let abandoned_check = abandoned.clone();
+  let abandoned_result = abandoned.clone();
+  let mirror = transfer.mirror.clone();
+  let cancelled = transfer.cancelled.clone();
+  let released = transfer.released.clone();
+  let out_of_space = transfer.out_of_space.clone();
+  let space_path = transfer.space_path.clone();
+  let min_free_space = transfer.min_free_space;
+  //The part that didn't match its hash is reported after the body, see download_file
+  let corrupt = transfer.corrupt.clone();
+  let over_budget = transfer.over_budget.clone();
 
-  res.into_body().for_each(move |chunk| {
+  res.into_body().take_while(move |_| futures::future::ok(!abandoned_check.load(Ordering::SeqCst) && !corrupt.load(Ordering::SeqCst))).for_each(move |chunk| {
     if !abort_in_error {
       let ret = writer.write_all(&chunk).map_err(|e| panic!("Writer encountered an error: {}", e));
-      let mut state = unlocked_state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-      state.download_size.0 += chunk.len() as u64;
-      drop(state);
+      if !transfer.on_chunk(chunk.len()) {
+        abandoned.store(true, Ordering::SeqCst);
+      }
       ret
     } else {
       let mut vec = Vec::new();
@@ -919,97 +3365,2502 @@ fn process_response<W: Write, F: FnMut(&mut W, &mut u64)>(res: hyper::Response<h
       ret
     }
   }).and_then(move |_| {
-    if !abort_in_error {
-      f.set_len(trunc_size).expect(concat!(module_path!(),":",file!(),":",line!()));
-      drop(f);
+    if cancelled.load(Ordering::SeqCst) {
+      Ok(Err(Error::new("The download was cancelled".to_string())))
+    } else if released.load(Ordering::SeqCst) {
+      let mut error = Error::new(format!("The download from {} was paused for too long, closed the connection", mirror));
+      error.kind = ErrorKind::Paused;
+      Ok(Err(error))
+    } else if let Some(available) = (*out_of_space.lock_unpoisoned()).filter(|_| abandoned_result.load(Ordering::SeqCst)) {
+      Ok(Err(Error::insufficient_space(space_path, available, min_free_space)))
+    } else if abandoned_result.load(Ordering::SeqCst) {
+      let mut error = Error::new(format!("Mirror {} became too slow, switching to another mirror", mirror));
+      //A few slow parts may just be a bad moment for this download, the retry goes to another mirror anyway
+      error.remove_mirror = over_budget.load(Ordering::SeqCst);
+      Ok(Err(error))
+    } else if let Some(content_encoding) = content_encoding {
+      let mut error = Error::new(format!("Mirror {} sent a response with Content-Encoding {} (status code {}), expected the file as is", mirror, content_encoding, status));
+      error.remove_mirror = true;
+      Ok(Err(error))
+    } else if !abort_in_error {
       Ok(Ok(()))
+    } else if let Some(retry_after) = retry_after {
+      let mut error = Error::new(format!("Mirror {} asked to retry after {} seconds (status code {})", mirror, retry_after.as_secs(), status));
+      error.kind = ErrorKind::RateLimited { retry_after };
+      Ok(Err(error))
     } else {
-      Ok(Err("Unexpected response: found status code 200 or 206!".into()))
+      let mut error = Error::new(format!("Unexpected response: expected status code 200 or 206, found {}!", status));
+      error.remove_mirror = true;
+      Ok(Err(error))
     }
   })
 }
 
-pub fn convert(num: f64) -> String {
-  let negative = if num.is_sign_positive() { "" } else { "-" };
-  let num = num.abs();
-  let units = ["B", "kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
-  if num < 1_f64 {
-    return format!("{}{} {}", negative, num, "B");
+/*
+ * Copies a file of a local mirror from `start` on into the writer, reporting to the transfer like process_response does
+ */
+fn copy_local<W: Write, F: FnMut(&mut W, &mut u64)>(source: &std::path::Path, start: u64, mut writer: downloader::BufWriter<W, F>, mut transfer: Transfer) -> Result<(), Error> {
+  let mut file = match std::fs::File::open(source) {
+    Ok(file) => file,
+    Err(e) => {
+      let mut error = Error::new(format!("Couldn't open \"{}\": {}", source.display(), e));
+      error.remove_mirror = true;
+      return Err(error);
+    }
+  };
+  file.seek(SeekFrom::Start(start))?;
+  let mut buf = vec![0u8; 64 * 1024];
+  loop {
+    let read = file.read(&mut buf)?;
+    if read == 0 {
+      break;
+    }
+    writer.write_all(&buf[..read])?;
+    if transfer.corrupt.load(Ordering::SeqCst) {
+      break;
+    }
+    if !transfer.on_chunk(read) {
+      if transfer.released.load(Ordering::SeqCst) {
+        let mut error = Error::new(format!("The download from {} was paused for too long", transfer.mirror));
+        error.kind = ErrorKind::Paused;
+        return Err(error);
+      }
+      if let Some(available) = *transfer.out_of_space.lock_unpoisoned() {
+        return Err(Error::insufficient_space(transfer.space_path.clone(), available, transfer.min_free_space));
+      }
+      return Err(Error::new("The download was cancelled".to_string()));
+    }
   }
-  let delimiter = 1000_f64;
-  let exponent = std::cmp::min((num.ln() / delimiter.ln()).floor() as i32, (units.len() - 1) as i32);
-  let pretty_bytes = format!("{:.2}", num / delimiter.powi(exponent)).parse::<f64>().expect(concat!(module_path!(),":",file!(),":",line!())) * 1_f64;
-  let unit = units[exponent as usize];
-  format!("{}{} {}", negative, pretty_bytes, unit)
+  writer.flush()?;
+  Ok(())
 }
 
 /*
- * Applies the vcdiff patch file to the target file.
- * 
- * -------------- par --------------------------------------------------
- * | DeltaQueue | --> | apply patch to all files that match this Delta |
- * --------------     --------------------------------------------------
+ * A writer into a buffer that stays reachable after the writer has been moved into a future
  */
-fn apply_patch(patch_entry: &PatchEntry, state: Arc<Mutex<Progress>>) -> Result<(), Error> {
-  let mut dir_path = patch_entry.target_path.clone();
-  dir_path.truncate(patch_entry.target_path.rfind('/').expect(concat!(module_path!(),":",file!(),":",line!())));
-  DirBuilder::new().recursive(true).create(dir_path).expect(concat!(module_path!(),":",file!(),":",line!()));
-  if patch_entry.has_source {
-    let source_path = format!("{}.vcdiff_src", &patch_entry.target_path);
-    std::fs::rename(&patch_entry.target_path, &source_path).expect(concat!(module_path!(),":",file!(),":",line!()));
-    xdelta::decode_file(Some(&source_path), &patch_entry.delta_path, &patch_entry.target_path);
-    std::fs::remove_file(&source_path).expect(concat!(module_path!(),":",file!(),":",line!()));
-  } else {
-    //there is supposed to be no source file, so make sure it doesn't exist either!
-    match std::fs::remove_file(&patch_entry.target_path) {
-      Ok(()) => (),
-      Err(_e) => ()
-    };
-    xdelta::decode_file(None, &patch_entry.delta_path, &patch_entry.target_path);
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.lock_unpoisoned().extend_from_slice(buf);
+    Ok(buf.len())
   }
-  let hash = get_hash(&patch_entry.target_path);
-  if hash != patch_entry.target_hash {
-    return Err(format!("Hash for file {} is incorrect!\nGot hash: {}\nExpected hash: {}", &patch_entry.target_path, &hash, &patch_entry.target_hash).into());
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
   }
-  let mut state = state.lock().expect(concat!(module_path!(),":",file!(),":",line!()));
-  state.patch_files.0 += 1;
-  drop(state);
+}
+
+/*
+ * Whether the other side still has a connection open, without consuming anything it sent
+ */
+fn is_open(stream: &std::net::TcpStream) -> bool {
+  if stream.set_nonblocking(true).is_err() {
+    return false;
+  }
+  let mut buf = [0u8; 1];
+  let open = match stream.peek(&mut buf) {
+    Ok(0) => false,
+    Ok(_) => true,
+    Err(e) => e.kind() == std::io::ErrorKind::WouldBlock
+  };
+  stream.set_nonblocking(false).is_ok() && open
+}
+
+/*
+ * Gives a patched file the permissions instructions.json has for it, xdelta doesn't set the executable bit.
+ * Without a mode in instructions.json, shell scripts and extension-less files in Binaries/ are made executable.
+ */
+#[cfg(unix)]
+fn set_mode(target_path: &str, mode: Option<u32>) -> Result<(), Error> {
+  use std::os::unix::fs::PermissionsExt;
+  let mode = match mode {
+    Some(mode) => mode,
+    None if is_executable(target_path) => 0o755,
+    None => return Ok(())
+  };
+  std::fs::set_permissions(target_path, std::fs::Permissions::from_mode(mode))?;
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_target_path: &str, _mode: Option<u32>) -> Result<(), Error> {
   Ok(())
 }
 
+#[cfg(unix)]
+fn is_executable(target_path: &str) -> bool {
+  let path = std::path::Path::new(target_path);
+  match path.extension().map(|extension| extension.to_string_lossy().to_lowercase()) {
+    Some(extension) => extension == "sh" || extension == "x86_64",
+    None => target_path.contains("/Binaries/")
+  }
+}
 
 /*
- * Opens a file and calculates it's SHA256 hash
+ * Turns a location into the form every path is built from: slashes only, collapsed, and exactly one at the end.
+ * A leading double slash is kept, it starts a UNC path on Windows. An empty location is the working directory.
  */
-fn get_hash(file_path: &str) -> String {
-  let mut file = OpenOptions::new().read(true).open(file_path).expect(concat!(module_path!(),":",file!(),":",line!()));
-  let mut sha256 = Sha256::new();
-  std::io::copy(&mut file, &mut sha256).expect(concat!(module_path!(),":",file!(),":",line!()));
-  hex::encode_upper(sha256.result())
+fn normalize_location(location: &str) -> String {
+  let location = location.replace("\\", "/");
+  if location.is_empty() {
+    return "./".to_string();
+  }
+  let unc = location.starts_with("//") && !location.trim_start_matches('/').is_empty();
+  let parts : Vec<&str> = location.split('/').filter(|part| !part.is_empty()).collect();
+  let prefix = if unc { "//" } else if location.starts_with('/') { "/" } else { "" };
+  if parts.is_empty() {
+    return prefix.to_string();
+  }
+  format!("{}{}/", prefix, parts.join("/"))
 }
 
-#[cfg(test)]
-mod tests {
-  use super::*;
-  #[test]
-  fn downloader() {
-    let mut patcher : Downloader = Downloader::new();
-    patcher.set_location("C:/RenegadeX/".to_string());
-    patcher.set_version_url("https://static.renegade-x.com/launcher_data/version/release.json".to_string());
-    patcher.retrieve_mirrors().expect(concat!(module_path!(),":",file!(),":",line!()));
-    patcher.remove_unversioned().expect(concat!(module_path!(),":",file!(),":",line!()));
-    match patcher.update_available().expect(concat!(module_path!(),":",file!(),":",line!())) {
-      Update::UpToDate => {
-        println!("Game up to date!");
-        patcher.poll_progress();
-        patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
-      },
-      Update::Resume | Update::Delta | Update::Full | Update::Unknown => {
-        println!("Update available!");
-        patcher.poll_progress();
-        patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
-      }
+/*
+ * Checks that the installation directory can be written to by creating and removing a file in it, so a read-only installation fails early with a clear error.
+ * A directory that doesn't exist yet is left alone, it gets created along with the files.
+ */
+fn check_writable(location: &str) -> Result<(), Error> {
+  let probe_path = format!("{}patcher.probe", location);
+  match OpenOptions::new().write(true).create(true).open(&probe_path) {
+    Ok(_file) => {
+      std::fs::remove_file(&probe_path).ok();
+      Ok(())
+    },
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied || e.kind() == std::io::ErrorKind::ReadOnlyFilesystem => {
+      let mut error = Error::new(format!("The installation at \"{}\" can't be written to ({}), run the patcher as a user that may change it", location, e));
+      error.kind = ErrorKind::ReadOnlyInstall { path: location.to_string() };
+      Err(error)
+    },
+    Err(e) => Err(e.into())
+  }
+}
+
+/*
+ * Parses instructions.json, install_path turns the Path of each entry into the path of the installed file
+ */
+fn parse_instructions<F: Fn(&str) -> String>(text: &str, install_path: F) -> Result<Vec<Instruction>, Error> {
+  let instructions_data = match json::parse(text) {
+    Ok(result) => result,
+    Err(e) => return Err(format!("Invalid JSON: {}", e).into())
+  };
+  let mut instructions = Vec::new();
+  for instruction in instructions_data.members() {
+    let path = match instruction["Path"].as_str() {
+      Some(path) => path.replace("\\", "/"),
+      None => return Err(Error::new("instructions.json: an entry has no Path".to_string()))
     };
-    assert!(true);
+    if !is_contained(&path) {
+      let mut error = Error::new(format!("instructions.json: \"{}\" is outside of the installation, refusing to write to it", &path));
+      error.kind = ErrorKind::PathTraversal { path };
+      return Err(error);
+    }
+    let size = |name: &str| instruction[name].as_usize().ok_or_else(|| Error::new(format!("instructions.json: {} of \"{}\" is missing or not a number", name, &path)));
+    instructions.push(Instruction {
+      path:                install_path(&path),
+      old_hash:            instruction["OldHash"].as_string_option(),
+      new_hash:            instruction["NewHash"].as_string_option(),
+      compressed_hash:     instruction["CompressedHash"].as_string_option(),
+      delta_hash:          instruction["DeltaHash"].as_string_option(),
+      full_replace_size:   size("FullReplaceSize")?,
+      delta_size:          size("DeltaSize")?,
+      has_delta:           instruction["HasDelta"].as_bool().ok_or_else(|| Error::new(format!("instructions.json: HasDelta of \"{}\" is missing", &path)))?,
+      compressed_part_hashes: instruction["CompressedPartHashes"].members().map(|hash| hash.as_string()).collect(),
+      delta_part_hashes:   instruction["DeltaPartHashes"].members().map(|hash| hash.as_string()).collect(),
+      //Either a number or a string of octal digits like "755"
+      mode:                instruction["Mode"].as_u32().or_else(|| instruction["Mode"].as_str().and_then(|mode| u32::from_str_radix(mode, 8).ok())),
+    });
+  }
+  Ok(instructions)
+}
+
+/*
+ * Whether a Path from instructions.json stays inside the installation. Checked on the path itself rather than on the disk, most files don't exist yet.
+ */
+fn is_contained(path: &str) -> bool {
+  !path.starts_with('/') && !path.contains(':') && std::path::Path::new(path).components().all(|component| matches!(component, std::path::Component::Normal(_) | std::path::Component::CurDir))
+}
+
+/**
+ Makes every downloader hash files and connect to mirrors on a pool of `num_threads` threads of the patcher's own, named `patcher-<index>`, instead of on rayon's global pool.
+ Keeps the patcher from competing with an application that uses the global pool itself. Call it once before starting any downloader, downloading and patching always have pools of their own.
+*/
+pub fn configure_thread_pool(num_threads: usize) -> Result<(), Error> {
+  let pool = rayon::ThreadPoolBuilder::new().num_threads(std::cmp::max(num_threads, 1)).thread_name(|index| format!("patcher-{}", index)).build();
+  match pool {
+    Ok(pool) => {
+      *THREAD_POOL.lock_unpoisoned() = Some(Arc::new(pool));
+      Ok(())
+    },
+    Err(e) => Err(format!("Couldn't start the thread pool: {}", e).into())
+  }
+}
+
+/**
+ Compares two versions of instructions.json, e.g. to show what changed between two releases. Doesn't touch the network or the disk.
+ Entries without a NewHash are files that don't exist in that version.
+*/
+pub fn diff_manifests(old: &str, new: &str) -> Result<ManifestDiff, Error> {
+  let files = |text: &str| -> Result<BTreeMap<String, (String, usize)>, Error> {
+    Ok(parse_instructions(text, |path| path.to_string())?.into_iter().filter_map(|instruction| {
+      let size = instruction.full_replace_size;
+      instruction.new_hash.map(|hash| (instruction.path, (hash, size)))
+    }).collect())
+  };
+  let (old_files, new_files) = (files(old)?, files(new)?);
+  let mut diff = ManifestDiff::default();
+  for (path, (hash, size)) in new_files.iter() {
+    match old_files.get(path) {
+      None => diff.added.push(ManifestChange { path: path.clone(), old_size: None, new_size: Some(*size) }),
+      Some((old_hash, old_size)) if old_hash != hash => diff.changed.push(ManifestChange { path: path.clone(), old_size: Some(*old_size), new_size: Some(*size) }),
+      Some(_) => {}
+    }
+  }
+  for (path, (_, size)) in old_files.iter().filter(|(path, _)| !new_files.contains_key(*path)) {
+    diff.removed.push(ManifestChange { path: path.clone(), old_size: Some(*size), new_size: None });
+  }
+  Ok(diff)
+}
+
+pub fn convert(num: f64) -> String {
+  let negative = if num.is_sign_positive() { "" } else { "-" };
+  let num = num.abs();
+  let units = ["B", "kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
+  if num < 1_f64 {
+    return format!("{}{} {}", negative, num, "B");
+  }
+  let delimiter = 1000_f64;
+  let exponent = std::cmp::min((num.ln() / delimiter.ln()).floor() as i32, (units.len() - 1) as i32);
+  let pretty_bytes = format!("{:.2}", num / delimiter.powi(exponent)).parse::<f64>().expect(concat!(module_path!(),":",file!(),":",line!())) * 1_f64;
+  let unit = units[exponent as usize];
+  format!("{}{} {}", negative, pretty_bytes, unit)
+}
+
+/// Maps a path from instructions.json, like `UDKGame/CookedPC/RenX_Game.u`, to where the file is kept inside the installation directory.
+pub type PathMapper = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Runs after a download patched all of its files, see `set_post_patch_hook()`.
+pub type PostPatchHook = Box<dyn Fn(&[FileChange]) -> Result<(), Error> + Send + Sync>;
+
+/// Decides whether the files instructions.json deletes may be removed, see `set_deletion_confirm()`.
+pub type DeletionConfirm = Box<dyn Fn(&[String]) -> bool + Send + Sync>;
+
+/// Calculates the hash of a file as upper-case hex, the way instructions.json lists them.
+pub type HashFunction = Box<dyn Fn(&std::path::Path) -> Result<String, Error> + Send + Sync>;
+
+/*
+ * The hash function used by everything that verifies files, SHA256 unless set_hasher was used
+ */
+#[derive(Clone)]
+enum Hasher {
+  Sha256,
+  Custom(Arc<dyn Fn(&std::path::Path) -> Result<String, Error> + Send + Sync>),
+}
+
+impl Hasher {
+  fn sha256() -> Hasher {
+    Hasher::Sha256
+  }
+
+  fn hash(&self, file_path: &str) -> Result<String, Error> {
+    match self {
+      Hasher::Sha256 => get_hash(std::path::Path::new(file_path)),
+      Hasher::Custom(hash) => hash(std::path::Path::new(file_path)).map(|hash| hash.to_uppercase())
+    }
+  }
+
+  /*
+   * The hash of the first `len` bytes of a file, e.g. of a download that still has its progress counter at the end.
+   * A custom hash function only takes whole files, so for one of those the bytes are copied into a file of their own first.
+   */
+  fn hash_prefix(&self, file_path: &str, len: u64) -> Result<String, Error> {
+    let file = OpenOptions::new().read(true).open(file_path)?;
+    match self {
+      Hasher::Sha256 => {
+        let mut sha256 = Sha256::new();
+        std::io::copy(&mut file.take(len), &mut sha256)?;
+        Ok(hex::encode_upper(sha256.result()))
+      },
+      Hasher::Custom(_) => {
+        let copy_path = format!("{}.verify", file_path);
+        let copied = std::fs::File::create(&copy_path).and_then(|mut copy| std::io::copy(&mut file.take(len), &mut copy));
+        let result = match copied {
+          Ok(_) => self.hash(&copy_path),
+          Err(e) => Err(e.into())
+        };
+        std::fs::remove_file(&copy_path).ok();
+        result
+      }
+    }
+  }
+}
+
+impl std::fmt::Debug for Hasher {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "Hasher")
+  }
+}
+
+#[derive(Debug, Clone)]
+struct PatchOptions {
+  symlink_policy: SymlinkPolicy,
+  verification_level: VerificationLevel,
+  verify_deltas: bool,
+  hasher: Hasher,
+  verify_signatures: bool,
+  write_strategy: WriteStrategy,
+  run_id: String, //Suffix of the sources moved aside by this downloader, so they can't be mistaken for those of another run
+}
+
+impl PatchOptions {
+  fn new() -> PatchOptions {
+    PatchOptions {
+      symlink_policy: SymlinkPolicy::Skip,
+      verification_level: VerificationLevel::Full,
+      verify_deltas: false,
+      hasher: Hasher::sha256(),
+      verify_signatures: false,
+      write_strategy: WriteStrategy::Copy,
+      run_id: format!("{}-{}", std::process::id(), SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_millis())),
+    }
+  }
+
+  fn should_verify(&self, target_path: &str) -> bool {
+    match self.verification_level {
+      VerificationLevel::Full => true,
+      VerificationLevel::None => false,
+      VerificationLevel::Sampled(percentage) => {
+        let extension = std::path::Path::new(target_path).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        let executable = matches!(extension.as_ref().map(|extension| extension.as_str()), Some("exe") | Some("dll") | Some("so"));
+        executable || rand::random::<f64>() * 100.0 < percentage as f64
+      }
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+enum PatchOutcome {
+  Applied,
+  /// The target was left alone, e.g. because it is a symbolic link.
+  Skipped,
+  /// The source file no longer matches the hash it had when the delta was chosen, or the delta produced the wrong file.
+  SourceChanged,
+}
+
+/**
+ Lists a file in `Progress::current_files` for as long as it's alive.
+*/
+struct CurrentFile<'a> {
+  state: &'a Mutex<Progress>,
+  path: String,
+}
+
+impl<'a> CurrentFile<'a> {
+  fn enter(state: &'a Mutex<Progress>, path: &str) -> CurrentFile<'a> {
+    state.lock_unpoisoned().current_files.push(path.to_string());
+    CurrentFile { state, path: path.to_string() }
+  }
+}
+
+impl<'a> Drop for CurrentFile<'a> {
+  fn drop(&mut self) {
+    let mut state = self.state.lock_unpoisoned();
+    if let Some(index) = state.current_files.iter().position(|path| path == &self.path) {
+      state.current_files.remove(index);
+    }
+  }
+}
+
+/*
+ * Follows a chain of symbolic links to the path at its end, which doesn't have to exist yet.
+ * A chain that loops back on itself, or is longer than MAX_SYMLINK_HOPS, is an error.
+ */
+fn resolve_symlinks(path: &str) -> Result<String, Error> {
+  let mut visited = HashSet::new();
+  let mut path = std::path::PathBuf::from(path);
+  while std::fs::symlink_metadata(&path).map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false) {
+    if !visited.insert(path.clone()) || visited.len() > MAX_SYMLINK_HOPS {
+      return Err(format!("\"{}\" is part of a loop of symbolic links", path.display()).into());
+    }
+    //A relative link is relative to the directory the link is in
+    let link_target = std::fs::read_link(&path)?;
+    path = path.parent().map_or(link_target.clone(), |parent| parent.join(&link_target));
+  }
+  Ok(path.to_string_lossy().replace("\\", "/"))
+}
+
+/*
+ * Applies the vcdiff patch file to the target file.
+ * 
+ * -------------- par --------------------------------------------------
+ * | DeltaQueue | --> | apply patch to all files that match this Delta |
+ * --------------     --------------------------------------------------
+ */
+fn apply_patch(patch_entry: &PatchEntry, options: &PatchOptions, state: Arc<Mutex<Progress>>) -> Result<PatchOutcome, Error> {
+  let is_symlink = std::fs::symlink_metadata(&patch_entry.target_path).map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false);
+  if is_symlink {
+    match options.symlink_policy {
+      SymlinkPolicy::Follow => {
+        let mut resolved_entry = patch_entry.clone();
+        resolved_entry.target_path = resolve_symlinks(&patch_entry.target_path)?;
+        return apply_patch(&resolved_entry, options, state);
+      },
+      SymlinkPolicy::Replace => {},
+      SymlinkPolicy::Skip => {
+        println!("\"{}\" is a symbolic link, skipping it.", &patch_entry.target_path);
+        return Ok(PatchOutcome::Skipped);
+      },
+      SymlinkPolicy::Error => {
+        return Err(format!("\"{}\" is a symbolic link, refusing to patch it", &patch_entry.target_path).into());
+      }
+    }
+  }
+  let _current_file = CurrentFile::enter(&state, &patch_entry.target_path);
+  let mut dir_path = patch_entry.target_path.clone();
+  dir_path.truncate(patch_entry.target_path.rfind('/').expect(concat!(module_path!(),":",file!(),":",line!())));
+  DirBuilder::new().recursive(true).create(dir_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+  let signed_before = options.verify_signatures && signature::is_signed(&patch_entry.target_path);
+  let verify_target = || -> Result<(), Error> {
+    let hash = options.hasher.hash(&patch_entry.target_path)?;
+    if hash != patch_entry.target_hash {
+      return Err(format!("Hash for file {} is incorrect!\nGot hash: {}\nExpected hash: {}", &patch_entry.target_path, &hash, &patch_entry.target_hash).into());
+    }
+    Ok(())
+  };
+  if patch_entry.kind == DownloadKind::Delta {
+    //Something else (e.g. an antivirus) may have touched the file since check_hashes looked at it.
+    match &patch_entry.source_hash {
+      Some(source_hash) => {
+        if &options.hasher.hash(&patch_entry.target_path)? != source_hash {
+          return Ok(PatchOutcome::SourceChanged);
+        }
+      },
+      None if options.verify_deltas => {
+        return Err(format!("The source hash of \"{}\" is unknown, refusing to apply a delta to it", &patch_entry.target_path).into());
+      },
+      None => {}
+    }
+    //Moved aside next to the target rather than into the patcher folder, so the rename stays on one volume and never turns into a copy
+    let source_path = format!("{}{}.{}", &patch_entry.target_path, SOURCE_SUFFIX, &options.run_id);
+    std::fs::rename(&patch_entry.target_path, &source_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+    xdelta::decode_file(Some(&source_path), &patch_entry.delta_path, &patch_entry.target_path);
+    if options.verify_deltas || options.should_verify(&patch_entry.target_path) {
+      if let Err(e) = verify_target() {
+        //The delta was made for a different source, put the source back so the file gets patched by a full download instead
+        println!("{}", e);
+        std::fs::remove_file(&patch_entry.target_path).ok();
+        std::fs::rename(&source_path, &patch_entry.target_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+        return Ok(PatchOutcome::SourceChanged);
+      }
+    }
+    std::fs::remove_file(&source_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+  } else {
+    //there is supposed to be no source file, so make sure it doesn't exist either!
+    match std::fs::remove_file(&patch_entry.target_path) {
+      Ok(()) => (),
+      Err(_e) => ()
+    };
+    match patch_entry.kind {
+      //Copied unless the patcher folder is on the same volume and no other file has the same content
+      DownloadKind::RawFull if options.write_strategy == WriteStrategy::Move => {
+        if let Err(e) = std::fs::rename(&patch_entry.delta_path, &patch_entry.target_path) {
+          println!("Couldn't move \"{}\" into place, copying it instead: {}", &patch_entry.delta_path, e);
+          std::fs::copy(&patch_entry.delta_path, &patch_entry.target_path)?;
+        }
+      },
+      DownloadKind::RawFull => { std::fs::copy(&patch_entry.delta_path, &patch_entry.target_path)?; },
+      _ => xdelta::decode_file(None, &patch_entry.delta_path, &patch_entry.target_path)
+    }
+    if options.should_verify(&patch_entry.target_path) {
+      verify_target()?;
+    }
+  }
+  if signed_before {
+    signature::check_signed(&patch_entry.target_path)?;
+  }
+  set_mode(&patch_entry.target_path, patch_entry.mode)?;
+  let mut state = state.lock_unpoisoned();
+  state.patch_files.0 += 1;
+  drop(state);
+  Ok(PatchOutcome::Applied)
+}
+
+
+/*
+ * Whether files in the patcher folder can be renamed into the installation, tried with an empty file as the volumes can't be compared portably.
+ * A rename between volumes fails rather than copying, so anything but success means the files have to be copied.
+ */
+fn detect_write_strategy(patch_dir: &str, location: &str) -> WriteStrategy {
+  let probe = format!("{}write_strategy_probe", patch_dir);
+  let moved = format!("{}write_strategy_probe", location);
+  if std::fs::write(&probe, b"").is_err() {
+    return WriteStrategy::Copy;
+  }
+  match std::fs::rename(&probe, &moved) {
+    Ok(()) => {
+      std::fs::remove_file(&moved).ok();
+      WriteStrategy::Move
+    },
+    Err(e) => {
+      println!("The patcher folder is on another volume than the installation, files will be copied into place: {}", e);
+      std::fs::remove_file(&probe).ok();
+      WriteStrategy::Copy
+    }
+  }
+}
+
+/// Added to the name of a file that is being patched with a delta while it's moved aside, followed by the run that moved it.
+const SOURCE_SUFFIX : &str = ".vcdiff_src";
+
+/*
+ * The sources that interrupted delta patches left behind, by the target they were moved aside from.
+ * Each run names them differently, so the directories of the targets are looked through, each of them once.
+ */
+fn leftover_sources<'a>(target_paths: impl Iterator<Item = &'a str>) -> HashMap<String, Vec<String>> {
+  let directories : HashSet<&str> = target_paths.filter_map(|path| path.rfind(|c: char| c == '/' || c == '\\').map(|index| &path[..=index])).collect();
+  let mut sources : HashMap<String, Vec<String>> = HashMap::new();
+  for directory in directories {
+    let entries = match std::fs::read_dir(directory) {
+      Ok(entries) => entries,
+      Err(_e) => continue
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+      let file_name = entry.file_name().to_string_lossy().to_string();
+      if let Some(index) = file_name.find(SOURCE_SUFFIX) {
+        sources.entry(format!("{}{}", directory, &file_name[..index])).or_insert_with(Vec::new).push(format!("{}{}", directory, &file_name));
+      }
+    }
+  }
+  sources
+}
+
+/*
+ * Reads the part counter at the end of a partially downloaded file.
+ * An unreadable or implausible counter means the download starts over.
+ */
+fn read_resume_part(file: &mut std::fs::File, entry_size: u64, parts_amount: usize) -> usize {
+  let resume_part = (read_counter(file, entry_size) & !REWOUND) as usize;
+  if resume_part > parts_amount {
+    println!("Ignoring implausible resume part {} out of {}", resume_part, parts_amount);
+    return 0;
+  }
+  resume_part
+}
+
+/*
+ * The url of the same download on another mirror, whose patch path and full or delta path may differ
+ */
+fn url_on_mirror(download_url: &str, from: &Mirror, to: &Mirror) -> String {
+  if from.address == to.address {
+    return download_url.to_string();
+  }
+  for (from_path, to_path) in [(&from.full_path, &to.full_path), (&from.delta_path, &to.delta_path)].iter() {
+    let prefix = format!("{}/{}/", &from.address, from_path);
+    if let Some(key) = download_url.strip_prefix(&prefix) {
+      return format!("{}/{}/{}", &to.address, to_path, key);
+    }
+  }
+  download_url.replacen(from.address.as_str(), to.address.as_str(), 1)
+}
+
+/*
+ * The hash of instructions.json as release.json has it
+ */
+fn instructions_hash(instructions: &[u8]) -> String {
+  let mut sha256 = Sha256::new();
+  sha256.input(instructions);
+  hex::encode_upper(sha256.result())
+}
+
+/*
+ * Set in the progress counter once it has been rewound after a failed hash check, see rewind_counter
+ */
+const REWOUND : u32 = 1 << 31;
+
+/*
+ * The progress counter at the end of a partial download, 0 if there is none
+ */
+fn read_counter(file: &mut std::fs::File, entry_size: u64) -> u32 {
+  let mut buf = [0,0,0,0];
+  if file.seek(SeekFrom::Start(entry_size)).is_err() || file.read_exact(&mut buf).is_err() {
+    return 0;
+  }
+  u32::from_be_bytes(buf)
+}
+
+/*
+ * Moves the progress counter at the end of a download that failed its hash check back to the part the attempt resumed from.
+ * The parts before it came from an earlier attempt and are trusted only once: if the counter had been rewound already, or nothing was downloaded since, it starts over.
+ * Returns the part the next attempt resumes from.
+ */
+fn rewind_counter(file: &mut std::fs::File, entry_size: u64, resume_part: usize, parts_amount: usize, rewound: bool) -> Result<usize, Error> {
+  let resume_part = if rewound || resume_part >= parts_amount { 0 } else { resume_part };
+  let counter = if resume_part == 0 { 0 } else { resume_part as u32 | REWOUND };
+  file.set_len(entry_size + 4)?;
+  file.seek(SeekFrom::Start(entry_size))?;
+  file.write_all(&counter.to_be_bytes())?;
+  Ok(resume_part)
+}
+
+/*
+ * The key of a delta download, it's used as the file name on the mirror and in the patcher folder
+ */
+fn delta_key(new_hash: &str, old_hash: &str) -> String {
+  format!("{}_from_{}", new_hash, old_hash)
+}
+
+/*
+ * The key of a full file download, it's used as the file name on the mirror and in the patcher folder
+ */
+fn full_key(new_hash: &str) -> String {
+  new_hash.to_string()
+}
+
+/*
+ * The kind of an instruction's full download and the hash the download is checked against.
+ * Without a CompressedHash, or with one equal to NewHash, the file on the mirror is the new file itself.
+ */
+fn full_download(instruction: &Instruction) -> Result<(DownloadKind, String), Error> {
+  let new_hash = new_hash(instruction)?;
+  Ok(match instruction.compressed_hash.clone() {
+    Some(compressed_hash) if !compressed_hash.eq_ignore_ascii_case(&new_hash) => (DownloadKind::CompressedFull, compressed_hash),
+    _ => (DownloadKind::RawFull, new_hash)
+  })
+}
+
+/*
+ * The NewHash of an instruction that is downloaded, only deletions come without one
+ */
+fn new_hash(instruction: &Instruction) -> Result<String, Error> {
+  instruction.new_hash.clone().ok_or_else(|| Error::new(format!("instructions.json: NewHash of \"{}\" is missing, it can't be downloaded", &instruction.path)))
+}
+
+/*
+ * Checks a single part of a partially downloaded file against its hash
+ */
+fn part_matches(file: &mut std::fs::File, part: usize, part_size: usize, file_size: u64, expected_hash: &str) -> bool {
+  let start = (part * part_size) as u64;
+  let len = std::cmp::min(part_size as u64, file_size.saturating_sub(start));
+  if file.seek(SeekFrom::Start(start)).is_err() {
+    return false;
+  }
+  let mut sha256 = Sha256::new();
+  match std::io::copy(&mut file.by_ref().take(len), &mut sha256) {
+    Ok(copied) if copied == len => hex::encode_upper(sha256.result()) == expected_hash,
+    _ => false
+  }
+}
+
+/*
+ * Opens a file and calculates it's SHA256 hash
+ */
+fn get_hash(file_path: &std::path::Path) -> Result<String, Error> {
+  let mut file = OpenOptions::new().read(true).open(file_path)?;
+  let mut sha256 = Sha256::new();
+  std::io::copy(&mut file, &mut sha256)?;
+  Ok(hex::encode_upper(sha256.result()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  fn sha256(data: &[u8]) -> String {
+    let mut sha256 = Sha256::new();
+    sha256.input(data);
+    hex::encode_upper(sha256.result())
+  }
+
+  /*
+   * Encodes an integer the way VCDIFF does, 7 bits per byte with the most significant byte first
+   */
+  fn varint(mut value: usize) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+      bytes.push((value & 0x7f) as u8 | 0x80);
+      value >>= 7;
+    }
+    bytes.reverse();
+    bytes
+  }
+
+  /*
+   * Builds a VCDIFF file without a source, consisting of a single ADD of the whole content
+   */
+  fn vcdiff(content: &[u8]) -> Vec<u8> {
+    let instructions = [vec![0x01], varint(content.len())].concat();
+    let delta = [varint(content.len()), vec![0x00], varint(content.len()), varint(instructions.len()), varint(0), content.to_vec(), instructions].concat();
+    [vec![0xd6, 0xc3, 0xc4, 0x00, 0x00, 0x00], varint(delta.len()), delta].concat()
+  }
+
+  /*
+   * Serves the given files over HTTP on a random local port, by the last segment of the requested path.
+   * Returns the url of the server.
+   */
+  fn mock_server(files: Vec<(String, Vec<u8>)>) -> String {
+    mock_server_with(files, |_request, body| match body {
+      Some(body) => [format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes(), body.clone()].concat(),
+      None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+    })
+  }
+
+  /*
+   * Like mock_server, but the response to every request is made by `respond`, out of the request and the file that was asked for
+   */
+  fn mock_server_with<R: Fn(&str, Option<&Vec<u8>>) -> Vec<u8> + Send + 'static>(files: Vec<(String, Vec<u8>)>, respond: R) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let address = format!("http://{}/", listener.local_addr().expect(concat!(module_path!(),":",file!(),":",line!())));
+    let files : HashMap<String, Vec<u8>> = files.into_iter().collect();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = match stream {
+          Ok(stream) => stream,
+          Err(_e) => continue
+        };
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+          match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+          }
+        }
+        let request = String::from_utf8_lossy(&request).to_string();
+        let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+        let name = path.rsplit('/').next().unwrap_or("").to_string();
+        stream.write_all(&respond(&request, files.get(&name))).ok();
+      }
+    });
+    address
+  }
+
+  /*
+   * Answers a request with the part of the body its Range header asks for, or with everything if it has none
+   */
+  fn ranged_response(request: &str, body: Option<&Vec<u8>>) -> Vec<u8> {
+    let body = match body {
+      Some(body) => body,
+      None => return b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+    };
+    let range = request.lines().find_map(|line| line.to_lowercase().strip_prefix("range: bytes=").map(|range| range.to_string()));
+    match range {
+      Some(range) => {
+        let mut bounds = range.trim().splitn(2, '-');
+        let start : usize = bounds.next().and_then(|start| start.parse().ok()).unwrap_or(0);
+        let end : usize = bounds.next().and_then(|end| end.parse().ok()).map_or(body.len(), |end: usize| std::cmp::min(end + 1, body.len()));
+        [format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n", end - start, start, end - 1, body.len()).into_bytes(), body[start..end].to_vec()].concat()
+      },
+      None => [format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes(), body.clone()].concat()
+    }
+  }
+
+  /*
+   * The entry of instructions.json for a file without an old version, which is downloaded in full as a VCDIFF of its content
+   */
+  fn instruction_entry(path: &str, content: &[u8]) -> String {
+    let full_file = vcdiff(content);
+    format!(r#"{{ "Path": "{}", "OldHash": null, "NewHash": "{}", "CompressedHash": "{}", "DeltaHash": null, "FullReplaceSize": {}, "DeltaSize": 0, "HasDelta": false }}"#, path, sha256(content), sha256(&full_file), full_file.len())
+  }
+
+  /*
+   * instructions.json for the given files, by their path in the manifest and their content
+   */
+  fn instructions_for(files: &[(&str, &[u8])]) -> String {
+    format!("[{}]", files.iter().map(|(path, content)| instruction_entry(path, content)).collect::<Vec<String>>().join(", "))
+  }
+
+  /*
+   * What a mirror serves for the given files: their instructions.json and the full download of each of them
+   */
+  fn served_files(files: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+    let mut served = vec![("instructions.json".to_string(), instructions_for(files).into_bytes())];
+    served.extend(files.iter().map(|(_, content)| (sha256(content), vcdiff(content))));
+    served
+  }
+
+  /*
+   * A release.json of version 5877 that hashes the given instructions.json and lists the given mirrors, the launcher is on the first of them
+   */
+  fn release_for(instructions: &str, mirrors: &[&str]) -> String {
+    let mirror_list = mirrors.iter().map(|mirror| format!(r#"{{ "url": "{}" }}"#, mirror)).collect::<Vec<String>>().join(", ");
+    format!(r#"{{
+      "launcher": {{ "version_name": "0.8.0", "version_number": 80, "patch_url": "{}launcher.zip", "patch_hash": "ABCD" }},
+      "game": {{ "version_number": 5877, "instructions_hash": "{}", "patch_path": "patches/", "mirrors": [ {} ] }}
+    }}"#, mirrors[0], sha256(instructions.as_bytes()), mirror_list)
+  }
+
+  /*
+   * Starts a mock server with a release that consists of the given files, returns its release.json
+   */
+  fn mock_release_of(files: &[(&str, &[u8])]) -> String {
+    let server = mock_server(served_files(files));
+    release_for(&instructions_for(files), &[&server])
+  }
+
+  /*
+   * Starts a mock server with a release that consists of DefaultRenegadeX.ini with the given content, returns its release.json
+   */
+  fn mock_release(content: &[u8]) -> String {
+    mock_release_of(&[(r"UDKGame\\Config\\DefaultRenegadeX.ini", content)])
+  }
+
+  /*
+   * A downloader for an installation in a new temporary directory, which is removed when the returned TempDir is dropped
+   */
+  fn patcher_in_tempdir() -> (tempfile::TempDir, Downloader) {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut patcher = Downloader::new();
+    patcher.set_location(dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string());
+    (dir, patcher)
+  }
+
+  #[test]
+  fn download_pipeline_against_mock_server() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let release_json = mock_release(&content);
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    match patcher.update_available().expect(concat!(module_path!(),":",file!(),":",line!())) {
+      Update::Full => {},
+      _ => panic!("Expected a full download for an empty directory")
+    }
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.wait_until_done(Some(Duration::from_secs(0))).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let target_path = dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini");
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    assert!(!dir.path().join("patcher").exists());
+    match patcher.update_available().expect(concat!(module_path!(),":",file!(),":",line!())) {
+      Update::UpToDate => {},
+      _ => panic!("Expected the game to be up to date after downloading")
+    }
+  }
+
+  #[test]
+  fn download_creates_the_installation_directory() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let location = dir.path().join("Games").join("RenegadeX");
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let mut patcher = Downloader::new();
+    patcher.set_location(location.to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string());
+    patcher.with_release_json(&mock_release(&content)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(location.join("UDKGame").join("Config").join("DefaultRenegadeX.ini")).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+  }
+
+  #[test]
+  fn staged_update_applies_files_after_downloading() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let files : [(&str, &[u8]); 2] = [
+      ("UDKGame/Config/DefaultRenegadeX.ini", b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n"),
+      ("UDKGame/CookedPC/RenX_Game.u", b"content of RenX_Game.u"),
+    ];
+    let instructions = instructions_for(&files);
+    //Whether any of the files was in place already when a request came in
+    let applied_early = Arc::new(Mutex::new(Vec::new()));
+    let server = {
+      let applied_early = applied_early.clone();
+      let targets = vec![dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini"), dir.path().join("UDKGame").join("CookedPC").join("RenX_Game.u")];
+      mock_server_with(served_files(&files), move |request, body| {
+        applied_early.lock_unpoisoned().push(targets.iter().any(|target| target.exists()));
+        ranged_response(request, body)
+      })
+    };
+    let release_json = release_for(&instructions, &[&server]);
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_update_strategy(UpdateStrategy::Staged);
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(dir.path().join("UDKGame").join("CookedPC").join("RenX_Game.u")).expect(concat!(module_path!(),":",file!(),":",line!())), files[1].1);
+    let applied_early = applied_early.lock_unpoisoned();
+    assert!(applied_early.len() >= 3);
+    assert!(applied_early.iter().all(|applied| !applied));
+  }
+
+  #[test]
+  fn staged_update_applies_nothing_if_a_download_fails() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let files : [(&str, &[u8]); 2] = [
+      ("UDKGame/Config/DefaultRenegadeX.ini", b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n"),
+      ("UDKGame/CookedPC/RenX_Game.u", b"content of RenX_Game.u"),
+    ];
+    let instructions = instructions_for(&files);
+    //RenX_Game.u is missing from the mirror
+    let mut served = served_files(&files);
+    served.retain(|(name, _)| name != &sha256(files[1].1));
+    let server = mock_server(served);
+    let release_json = release_for(&instructions, &[&server]);
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_update_strategy(UpdateStrategy::Staged);
+    let error = patcher.download().expect_err("Expected the missing file to fail the update");
+    assert!(matches!(error.kind, traits::ErrorKind::DownloadFailed { .. }));
+    assert!(!dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini").exists());
+  }
+
+  #[test]
+  fn prefetched_files_are_applied_later() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let content = b"content of RenX_Game.u".to_vec();
+    let files : [(&str, &[u8]); 1] = [("UDKGame/CookedPC/RenX_Game.u", &content)];
+    let instructions = instructions_for(&files);
+    let key = sha256(&content);
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let server = {
+      let requests = requests.clone();
+      mock_server_with(served_files(&files), move |request, body| {
+        requests.lock_unpoisoned().push(request.lines().next().unwrap_or("").to_string());
+        ranged_response(request, body)
+      })
+    };
+    let release_json = release_for(&instructions, &[&server]);
+
+    let target = dir.path().join("UDKGame").join("CookedPC").join("RenX_Game.u");
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.prefetch().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(!target.exists());
+    assert!(dir.path().join("patcher").join(&key).exists());
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&target).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    assert_eq!(requests.lock_unpoisoned().iter().filter(|request| request.contains(&key)).count(), 1);
+  }
+
+  #[cfg(feature = "torrent")]
+  #[test]
+  fn full_downloads_come_from_the_torrent() {
+    struct CopyTransport(Vec<(String, Vec<u8>)>);
+    impl TorrentTransport for CopyTransport {
+      fn fetch(&self, link: &str, dir: &std::path::Path) -> Result<std::path::PathBuf, Error> {
+        assert_eq!(link, "magnet:?xt=urn:btih:5877");
+        let content = dir.join("RenegadeX-5877");
+        std::fs::create_dir_all(content.join("full"))?;
+        for (name, data) in self.0.iter() {
+          std::fs::write(content.join("full").join(name), data)?;
+        }
+        Ok(content)
+      }
+    }
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let content = b"content of RenX_Game.u".to_vec();
+    let instructions = instructions_for(&[("UDKGame/CookedPC/RenX_Game.u", &content[..])]);
+    let key = sha256(&content);
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let server = {
+      let requests = requests.clone();
+      mock_server_with(vec![("instructions.json".to_string(), instructions.clone().into_bytes())], move |request, body| {
+        requests.lock_unpoisoned().push(request.lines().next().unwrap_or("").to_string());
+        ranged_response(request, body)
+      })
+    };
+    let release_json = release_for(&instructions, &[&server]).replace("\"patch_path\"", "\"torrent\": \"magnet:?xt=urn:btih:5877\", \"patch_path\"");
+
+    patcher.set_torrent_transport(Box::new(CopyTransport(vec![(key.clone(), vcdiff(&content))])));
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target = dir.path().join("UDKGame").join("CookedPC").join("RenX_Game.u");
+    assert_eq!(std::fs::read(&target).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    assert!(!requests.lock_unpoisoned().iter().any(|request| request.contains(&key)));
+  }
+
+  #[test]
+  fn instructions_are_verified_against_release_json() {
+    let instructions = r#"[{ "Path": "UDKGame\\Config\\DefaultRenegadeX.ini", "OldHash": null, "NewHash": null, "CompressedHash": null, "DeltaHash": null, "FullReplaceSize": 0, "DeltaSize": 0, "HasDelta": false }]"#;
+    let mut patcher = Downloader::new();
+    assert_eq!(patcher.instructions_hash(), None);
+    assert!(!patcher.verify_instructions(instructions));
+    patcher.with_release_json(&release_for(instructions, &["http://127.0.0.1:1/"])).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(patcher.instructions_hash(), Some(sha256(instructions.as_bytes()).as_str()));
+    assert!(patcher.verify_instructions(instructions));
+    assert!(!patcher.verify_instructions(&instructions.replace("0,", "1,")));
+  }
+
+  #[test]
+  fn stale_leftovers_are_removed() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let target_path = format!("{}RenX_Game.u", &location);
+    std::fs::write(&target_path, b"new").expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.with_release_json(&mock_release(b"")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::create_dir_all(format!("{}patcher/", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(format!("{}patcher/{}", &location, sha256(b"new")), b"full file").expect(concat!(module_path!(),":",file!(),":",line!()));
+    //Until instructions.json is known the leftovers are assumed to be needed
+    assert!(matches!(patcher.update_available(), Ok(Update::Resume)));
+    patcher.instructions = vec![instruction(&target_path, &sha256(b"old"), &sha256(b"new"))];
+    std::fs::write(&target_path, b"old").expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(matches!(patcher.update_available(), Ok(Update::Resume)));
+    //The file got updated, but the patcher folder wasn't cleaned up
+    std::fs::write(&target_path, b"new").expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(!matches!(patcher.update_available(), Ok(Update::Resume)));
+    //Left for download() to remove, which holds the installation lock
+    assert!(dir.path().join("patcher").exists());
+    patcher.remove_stale_leftovers();
+    assert!(!dir.path().join("patcher").exists());
+  }
+
+  #[test]
+  fn version_check_needs_no_files() {
+    let mut patcher = Downloader::new();
+    assert!(patcher.update_available_for_version("5877"));
+    patcher.with_release_json(&mock_release(b"")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(!patcher.update_available_for_version("5877"));
+    assert!(!patcher.update_available_for_version(" 5877\n"));
+    assert!(patcher.update_available_for_version("5876"));
+  }
+
+  #[test]
+  fn download_returns_early_when_up_to_date() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    std::fs::create_dir_all(dir.path().join("UDKGame").join("Config")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini"), &content).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.with_release_json(&mock_release(&content)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(patcher.get_progress().lock_unpoisoned().no_update_needed);
+    assert!(patcher.instructions.is_empty());
+
+    patcher.set_force_update(true);
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(!patcher.get_progress().lock_unpoisoned().no_update_needed);
+    assert_eq!(patcher.instructions.len(), 1);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn read_only_install_fails_early() {
+    use std::os::unix::fs::PermissionsExt;
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    if std::fs::write(dir.path().join("probe"), b"").is_ok() {
+      //Running as root, the permissions don't apply
+      return;
+    }
+    patcher.with_release_json(&mock_release(&content)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let update = patcher.update_available();
+    let error = patcher.download().expect_err("Expected the read-only installation to be refused");
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(update.expect_err("Expected update_available to refuse the read-only installation").contains("can't be written to"));
+    match error.kind {
+      ErrorKind::ReadOnlyInstall { path } => assert!(path.starts_with(dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())))),
+      kind => panic!("Expected a ReadOnlyInstall error, got {:?}", kind)
+    }
+    assert!(patcher.instructions.is_empty());
+  }
+
+  #[test]
+  fn overall_progress_never_goes_back() {
+    let mut progress = Progress::new();
+    assert_eq!(progress.overall_progress(), 0.0);
+    progress.phase = Phase::CheckingHashes;
+    progress.hash_progress = (5, 10);
+    assert!((progress.overall_progress() - 0.1).abs() < 1e-6);
+    //More files turned up, the hashing fraction drops but the overall progress stays
+    progress.hash_progress = (5, 20);
+    assert!((progress.overall_progress() - 0.1).abs() < 1e-6);
+    let copy = progress.clone();
+    progress.hash_progress = (20, 20);
+    progress.finished_hash = true;
+    progress.total_known = true;
+    progress.phase = Phase::Downloading;
+    progress.download_size = (50, 100);
+    progress.patch_files = (0, 4);
+    assert!((progress.overall_progress() - 0.55).abs() < 1e-6);
+    assert!((copy.overall_progress() - 0.55).abs() < 1e-6);
+    progress.phase = Phase::Finished;
+    assert_eq!(progress.overall_progress(), 1.0);
+  }
+
+  #[test]
+  fn diagnose_reports_every_check() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let release_server = mock_server(vec![("release.json".to_string(), mock_release(&content).into_bytes())]);
+    let location = format!("{}/", dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())));
+
+    let mut patcher = Downloader::new();
+    let report = patcher.diagnose();
+    assert!(report.release_reachable.is_err());
+    assert!(report.install_writable.is_err());
+    patcher.set_version_url(format!("{}release.json", &release_server));
+    patcher.set_location(location.clone());
+    let report = patcher.diagnose();
+    assert_eq!(report.release_reachable, Ok("version 5877, mirrors listed: 1".to_string()));
+    //The mock server doesn't have a file to benchmark with
+    assert!(report.mirrors_responding.is_err());
+    assert!(report.install_writable.is_ok());
+    assert!(report.installed_version.is_err());
+    std::fs::create_dir_all(format!("{}UDKGame/Config", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(format!("{}UDKGame/Config/DefaultRenegadeX.ini", &location), &content).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(patcher.diagnose().installed_version, Ok("5877".to_string()));
+  }
+
+  #[test]
+  fn download_fraction_waits_for_the_total() {
+    let mut progress = Progress::new();
+    progress.download_size = (50, 200);
+    assert_eq!(progress.download_fraction(), None);
+    progress.total_known = true;
+    assert_eq!(progress.download_fraction(), Some(0.25));
+    progress.download_size = (0, 0);
+    assert_eq!(progress.download_fraction(), Some(1.0));
+  }
+
+  #[test]
+  fn patched_files_are_added_to_the_hash_cache() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let cache_dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let release_json = mock_release(&content);
+
+    patcher.set_hash_cache(cache_dir.path().join("hashes.json").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string());
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.update_available().expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let file_path = patcher.instructions[0].path.clone();
+    let metadata = std::fs::metadata(&file_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let cache = HashCache::load(cache_dir.path().join("hashes.json").to_str().expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert_eq!(cache.get(&file_path, &metadata), Some(sha256(&content)));
+  }
+
+  #[test]
+  fn post_patch_hook_sees_the_patched_files() {
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let run = |fail: bool| -> (tempfile::TempDir, Result<(), Error>, Vec<FileChange>) {
+      let (dir, mut patcher) = patcher_in_tempdir();
+      let changes = Arc::new(Mutex::new(Vec::new()));
+      patcher.with_release_json(&mock_release(&content)).expect(concat!(module_path!(),":",file!(),":",line!()));
+      {
+        let changes = changes.clone();
+        patcher.set_post_patch_hook(Box::new(move |patched: &[FileChange]| {
+          changes.lock_unpoisoned().extend_from_slice(patched);
+          if fail { Err(Error::new("Couldn't sign the binaries".to_string())) } else { Ok(()) }
+        }));
+      }
+      patcher.update_available().expect(concat!(module_path!(),":",file!(),":",line!()));
+      let result = patcher.download();
+      let changes = changes.lock_unpoisoned().clone();
+      (dir, result, changes)
+    };
+
+    let (dir, result, changes) = run(false);
+    result.expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(changes.len(), 1);
+    assert!(changes[0].path.ends_with("UDKGame/Config/DefaultRenegadeX.ini"));
+    assert_eq!(changes[0].new_hash, sha256(&content));
+    assert!(!dir.path().join("patcher").exists());
+
+    let (dir, result, changes) = run(true);
+    assert!(format!("{}", result.expect_err("Expected the hook's error")).contains("Couldn't sign the binaries"));
+    assert_eq!(changes.len(), 1);
+    assert!(dir.path().join("patcher").exists());
+  }
+
+  #[test]
+  fn deletions_can_be_turned_down() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let obsolete = dir.path().join("Rx_Obsolete.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&obsolete, b"obsolete").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut deletion = instruction(&obsolete, "AAAA", "BBBB");
+    deletion.new_hash = None;
+
+    patcher.instructions = vec![deletion];
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    {
+      let asked = asked.clone();
+      patcher.set_deletion_confirm(Box::new(move |files: &[String]| {
+        asked.lock_unpoisoned().extend_from_slice(files);
+        false
+      }));
+    }
+    patcher.process_instructions().expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.delete_files().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(*asked.lock_unpoisoned(), vec![obsolete.clone()]);
+    assert!(std::path::Path::new(&obsolete).exists());
+
+    patcher.set_deletion_confirm(Box::new(|_files: &[String]| true));
+    patcher.process_instructions().expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.delete_files().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(!std::path::Path::new(&obsolete).exists());
+  }
+
+  #[test]
+  fn download_from_a_local_mirror() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let mirror_dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let files : [(&str, &[u8]); 1] = [(r"UDKGame\\Config\\DefaultRenegadeX.ini", &content)];
+    let instructions = instructions_for(&files);
+    std::fs::create_dir_all(mirror_dir.path().join("patches").join("full")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    for (name, data) in served_files(&files) {
+      let path = if name == "instructions.json" { mirror_dir.path().join("patches").join(name) } else { mirror_dir.path().join("patches").join("full").join(name) };
+      std::fs::write(path, data).expect(concat!(module_path!(),":",file!(),":",line!()));
+    }
+    let mirror_url = url::Url::from_directory_path(mirror_dir.path()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let release_json = release_for(&instructions, &[mirror_url.as_str()]);
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(patcher.mirrors.get_mirror().is_local());
+    patcher.update_available().expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.download().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target_path = dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini");
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+  }
+
+  #[test]
+  fn manifest_with_missing_files_is_refused() {
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let release_json = mock_release(&content);
+    let (dir, mut patcher) = patcher_in_tempdir();
+    patcher.with_release_json(&release_json.replace("\"patch_path\"", "\"file_count\": 2, \"patch_path\"")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let error = patcher.retrieve_instructions().expect_err("Expected the manifest to be refused");
+    assert!(format!("{}", error).contains("lists 1 files"));
+    assert!(patcher.instructions.is_empty());
+
+    let mut patcher = Downloader::new();
+    patcher.set_location(dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string());
+    patcher.with_release_json(&release_json.replace("\"patch_path\"", "\"file_count\": 1, \"patch_path\"")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.retrieve_instructions().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(patcher.instructions.len(), 1);
+  }
+
+  #[test]
+  fn spawned_download_can_be_joined_or_cancelled() {
+    let content = b"[RenX_Game.Rx_Game]\nGameVersionNumber=5877\n".to_vec();
+    let spawn = || -> (tempfile::TempDir, UpdateHandle) {
+      let (dir, mut patcher) = patcher_in_tempdir();
+      patcher.with_release_json(&mock_release(&content)).expect(concat!(module_path!(),":",file!(),":",line!()));
+      (dir, patcher.spawn_download())
+    };
+    let (dir, handle) = spawn();
+    handle.join().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini")).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+
+    let (_dir, handle) = spawn();
+    handle.cancel();
+    //A cancelled update never finishes patching, but it has ended
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !handle.is_finished() {
+      assert!(Instant::now() < deadline, "Expected the cancelled update to end");
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(handle.join().is_err());
+  }
+
+  #[test]
+  fn paused_download_waits_for_resume() {
+    let content : Vec<u8> = (0..2 * PART_SIZE).map(|i| (i % 251) as u8).collect();
+    let files = [(r"UDKGame\\Config\\DefaultRenegadeX.ini", &content[..])];
+    let file_requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let server = {
+      let file_requests = file_requests.clone();
+      let file_name = sha256(&content);
+      mock_server_with(served_files(&files), move |request, body| {
+        if request.lines().next().map_or(false, |line| line.contains(&file_name)) {
+          file_requests.fetch_add(1, Ordering::SeqCst);
+        }
+        ranged_response(request, body)
+      })
+    };
+    let (dir, mut patcher) = patcher_in_tempdir();
+    patcher.with_release_json(&release_for(&instructions_for(&files), &[&server])).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.pause();
+    let handle = patcher.spawn_download();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while handle.progress().phase != Phase::Downloading {
+      assert!(Instant::now() < deadline, "Expected the update to get to the downloads");
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(file_requests.load(Ordering::SeqCst), 0);
+    assert_eq!(handle.progress().download_size.0, 0);
+    assert!(!handle.is_finished());
+    handle.resume();
+    handle.join().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(dir.path().join("UDKGame").join("Config").join("DefaultRenegadeX.ini")).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+  }
+
+  #[test]
+  fn warmed_connections_are_reused() {
+    let server = mock_server(Vec::new());
+    let release_json = release_for("", &[&server]);
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.warm_connections().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mirror = patcher.mirrors.get_mirror();
+    let warmed = patcher.warm_connections.lock_unpoisoned().get(mirror.address.as_str()).map(|streams| streams[0].local_addr().expect(concat!(module_path!(),":",file!(),":",line!())));
+    let stream = patcher.connect(&mirror).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(Some(stream.local_addr().expect(concat!(module_path!(),":",file!(),":",line!()))), warmed);
+    //Nothing is left to reuse, so the next download connects again
+    let stream = patcher.connect(&mirror).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_ne!(Some(stream.local_addr().expect(concat!(module_path!(),":",file!(),":",line!()))), warmed);
+  }
+
+  #[test]
+  fn parts_of_a_file_are_spread_over_mirrors() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content : Vec<u8> = (0..2_500_000u32).map(|i| (i % 251) as u8).collect();
+    let mut corrupt = content.clone();
+    corrupt.iter_mut().for_each(|byte| *byte ^= 1);
+    let (good_requests, corrupt_requests) = (Arc::new(std::sync::atomic::AtomicUsize::new(0)), Arc::new(std::sync::atomic::AtomicUsize::new(0)));
+    let good = {
+      let good_requests = good_requests.clone();
+      mock_server_with(vec![("CONTENT".to_string(), content.clone())], move |request, body| {
+        good_requests.fetch_add(1, Ordering::SeqCst);
+        //Slow enough that the other mirror gets a part as well
+        std::thread::sleep(Duration::from_millis(100));
+        ranged_response(request, body)
+      })
+    };
+    let bad = {
+      let corrupt_requests = corrupt_requests.clone();
+      mock_server_with(vec![("CONTENT".to_string(), corrupt)], move |request, body| {
+        corrupt_requests.fetch_add(1, Ordering::SeqCst);
+        ranged_response(request, body)
+      })
+    };
+    let release_json = release_for("", &[&good, &bad]).replace(&format!(r#""url": "{}" }}"#, &bad), &format!(r#""url": "{}", "full_path": "complete" }}"#, &bad));
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_multi_source(true);
+    let file_path = dir.path().join("CONTENT").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let download_entry = DownloadEntry {
+      file_path: file_path.clone(),
+      file_size: content.len(),
+      file_hash: sha256(&content),
+      part_hashes: content.chunks(PART_SIZE).map(sha256).collect(),
+      patch_entries: Vec::new(),
+      resumed_size: 0,
+    };
+    let mirror = patcher.mirrors.mirrors[0].clone();
+    assert_eq!(url_on_mirror(&format!("{}/full/CONTENT", &mirror.address), &mirror, &patcher.mirrors.mirrors[1]), format!("{}/complete/CONTENT", &patcher.mirrors.mirrors[1].address));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    //Every part the corrupt mirror sends is downloaded again from the other one
+    patcher.download_file_parallel(&mirror, &format!("{}/full/CONTENT", &mirror.address), &download_entry, true, &cancelled).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&file_path).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    assert_eq!(corrupt_requests.load(Ordering::SeqCst), 1);
+    assert_eq!(good_requests.load(Ordering::SeqCst), 3);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size.0, content.len() as u64);
+  }
+
+  #[test]
+  fn parts_of_a_file_are_downloaded_in_parallel() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content : Vec<u8> = (0..2_500_000u32).map(|i| (i % 251) as u8).collect();
+    let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let server = {
+      let requests = requests.clone();
+      mock_server_with(vec![("CONTENT".to_string(), content.clone())], move |request, body| {
+        requests.fetch_add(1, Ordering::SeqCst);
+        ranged_response(request, body)
+      })
+    };
+    let release_json = release_for("", &[&server]);
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_intra_file_parallelism(2);
+    let file_path = dir.path().join("CONTENT").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let download_entry = DownloadEntry {
+      file_path: file_path.clone(),
+      file_size: content.len(),
+      file_hash: sha256(&content),
+      part_hashes: content.chunks(PART_SIZE).map(sha256).collect(),
+      patch_entries: Vec::new(),
+      resumed_size: 0,
+    };
+    //The first part is there already from an earlier attempt
+    std::fs::write(&file_path, &content[..PART_SIZE]).expect(concat!(module_path!(),":",file!(),":",line!()));
+    PartMap::open(&format!("{}.parts", &file_path), 3, 1).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mirror = patcher.mirrors.get_mirror();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    patcher.download_file_parallel(&mirror, &format!("{}full/CONTENT", &server), &download_entry, true, &cancelled).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&file_path).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    assert!(!std::path::Path::new(&format!("{}.parts", &file_path)).exists());
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size.0, content.len() as u64);
+  }
+
+  #[test]
+  fn compressed_ranges_are_rejected() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let server = {
+      let requests = requests.clone();
+      mock_server_with(vec![("CONTENT".to_string(), vec![0u8; 1000])], move |request, _body| {
+        requests.lock_unpoisoned().push(request.to_lowercase());
+        b"HTTP/1.1 206 Partial Content\r\nContent-Encoding: gzip\r\nContent-Length: 20\r\nConnection: close\r\n\r\n\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\x63\x60\x18\x05\x00\x00\x00\x00\x00\x00".to_vec()
+      })
+    };
+    let release_json = release_for("", &[&server]);
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mirror = patcher.mirrors.get_mirror();
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    let error = patcher.download_part(&mirror, &format!("{}full/CONTENT", &server), 0, 1000, &cancelled).expect_err("Expected the compressed range to be rejected");
+    assert!(format!("{}", error).contains("Content-Encoding gzip"));
+    assert!(error.remove_mirror);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size.0, 0);
+    assert!(requests.lock_unpoisoned()[0].contains("accept-encoding: identity"));
+
+    let mut received = Vec::new();
+    assert!(download_file_resumable(format!("{}instructions.json", &server), Duration::from_secs(10), true, &mut received).is_err());
+    assert!(received.is_empty());
+  }
+
+  #[test]
+  fn resumable_download_handles_ignored_range() {
+    let server = mock_server(vec![("instructions.json".to_string(), b"[]".to_vec())]);
+    //The mock server answers every request in full, so the bytes received earlier have to be replaced rather than appended to
+    let mut received = b"[".to_vec();
+    download_file_resumable(format!("{}instructions.json", &server), Duration::from_secs(10), true, &mut received).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(received, b"[]");
+    let mut received = Vec::new();
+    assert!(download_file_resumable(format!("{}missing.json", &server), Duration::from_secs(10), true, &mut received).is_err());
+    assert!(received.is_empty());
+  }
+
+  #[test]
+  fn failed_download_lists_every_attempt() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let (first, second) = (mock_server(Vec::new()), mock_server(Vec::new()));
+    let release_json = release_for("", &[&first, &second]);
+    std::fs::create_dir_all(format!("{}patcher/", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_full_download(&instruction("UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    let download_entry = download_hashmap.get("BBBB").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let error = patcher.download_and_patch("BBBB", download_entry).expect_err("Expected the download to fail");
+    match error.kind {
+      traits::ErrorKind::DownloadFailed { key, size, attempts } => {
+        assert_eq!(key, "BBBB");
+        assert_eq!(size, 1000);
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts.iter().any(|attempt| attempt.url.starts_with(&first)));
+        assert!(attempts.iter().any(|attempt| attempt.url.starts_with(&second)));
+        assert!(attempts.iter().all(|attempt| attempt.error.contains("404")));
+      },
+      kind => panic!("Expected a DownloadFailed error, got {:?}", kind)
+    }
+  }
+
+  #[test]
+  fn download_stops_when_the_drive_gets_full() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let server = mock_server(vec![("BBBB".to_string(), vec![0u8; 1000])]);
+    let release_json = release_for("", &[&server]);
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    //No drive has this much space left
+    patcher.set_min_free_space(u64::MAX);
+    patcher.queue_full_download(&instruction("UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let error = patcher.download_files().expect_err("Expected the download to stop");
+    match error.kind {
+      traits::ErrorKind::InsufficientSpace { required, .. } => assert_eq!(required, u64::MAX),
+      kind => panic!("Expected an InsufficientSpace error, got {:?}", kind)
+    }
+    //Kept to be resumed, and nothing was patched
+    assert!(std::path::Path::new(&format!("{}patcher/BBBB", &location)).exists());
+    assert!(!dir.path().join("UDKGame").exists());
+  }
+
+  #[test]
+  fn retries_are_limited_per_mirror() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let busy = || mock_server_with(Vec::new(), |_request, _body| b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec());
+    let (first, second) = (busy(), busy());
+    let release_json = release_for("", &[&first, &second]);
+    std::fs::create_dir_all(format!("{}patcher/", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_retry_policy(RetryPolicy { per_mirror: 2, total: 8, ..RetryPolicy::default() });
+    patcher.queue_full_download(&instruction("UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    let download_entry = download_hashmap.get("BBBB").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let error = patcher.download_and_patch("BBBB", download_entry).expect_err("Expected the download to fail");
+    match error.kind {
+      traits::ErrorKind::DownloadFailed { attempts, .. } => {
+        let mirrors : Vec<&str> = attempts.iter().map(|attempt| if attempt.url.starts_with(&first) { "first" } else { "second" }).collect();
+        //Alternating between the mirrors, until both of them used up their attempts
+        assert_eq!(mirrors.len(), 4);
+        assert_eq!(mirrors.iter().filter(|mirror| **mirror == "first").count(), 2);
+        assert_ne!(mirrors[0], mirrors[1]);
+        assert!(attempts.iter().all(|attempt| attempt.error.contains("503")));
+      },
+      kind => panic!("Expected a DownloadFailed error, got {:?}", kind)
+    }
+  }
+
+  #[test]
+  fn mirror_serving_corrupt_files_is_removed() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let content = b"intact content of RenX_Game.u".to_vec();
+    let corrupt = b"broken content of RenX_Game.u".to_vec();
+    let (bad, good) = (mock_server(vec![("CONTENT".to_string(), corrupt)]), mock_server(vec![("CONTENT".to_string(), content.clone())]));
+    let release_json = release_for("", &[&bad, &good]);
+    std::fs::create_dir_all(format!("{}patcher/", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    //Always picks the first mirror that can be picked, which is the bad one until it has failed
+    patcher.set_mirror_selector(Box::new(|_mirrors: &[Mirror]| 0));
+    let mut instruction = instruction("UDKGame/CookedPC/RenX_Game.u", "AAAA", "CONTENT");
+    instruction.compressed_hash = Some(sha256(&content));
+    instruction.full_replace_size = content.len();
+    patcher.queue_full_download(&instruction).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    let download_entry = download_hashmap.get("CONTENT").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.download_and_patch("CONTENT", download_entry).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(format!("{}patcher/CONTENT", &location)).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    let enabled : Vec<bool> = patcher.mirrors.mirrors.iter().map(|mirror| *mirror.enabled.lock_unpoisoned()).collect();
+    assert_eq!(enabled, vec![false, true]);
+  }
+
+  #[test]
+  fn hash_mismatch_can_remove_a_mirror_straight_away() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let content = b"intact content of RenX_Game.u".to_vec();
+    let mut flipped = content.clone();
+    flipped[7] ^= 1;
+    let bad = mock_server(vec![("CONTENT".to_string(), flipped)]);
+    let busy = mock_server_with(Vec::new(), |_request, _body| b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec());
+    let release_json = release_for("", &[&bad, &busy]);
+    std::fs::create_dir_all(format!("{}patcher/", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_mirror_selector(Box::new(|_mirrors: &[Mirror]| 0));
+    patcher.set_retry_policy(RetryPolicy { total: 3, remove_on_hash_mismatch: true, ..RetryPolicy::default() });
+    let mut instruction = instruction("UDKGame/CookedPC/RenX_Game.u", "AAAA", "CONTENT");
+    instruction.compressed_hash = Some(sha256(&content));
+    instruction.full_replace_size = content.len();
+    patcher.queue_full_download(&instruction).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    let download_entry = download_hashmap.get("CONTENT").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let error = patcher.download_and_patch("CONTENT", download_entry).expect_err("Expected the download to fail");
+    match error.kind {
+      traits::ErrorKind::DownloadFailed { attempts, .. } => {
+        //The busy mirror fails too, but is only busy, so it gets the retries
+        let mirrors : Vec<bool> = attempts.iter().map(|attempt| attempt.url.starts_with(&bad)).collect();
+        assert_eq!(mirrors, vec![true, false, false]);
+      },
+      kind => panic!("Expected a DownloadFailed error, got {:?}", kind)
+    }
+    let enabled : Vec<bool> = patcher.mirrors.mirrors.iter().map(|mirror| *mirror.enabled.lock_unpoisoned()).collect();
+    assert_eq!(enabled, vec![false, true]);
+  }
+
+  #[test]
+  fn verify_since_skips_older_files() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let intact_path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let corrupt_path = dir.path().join("Rx_Pawn.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let missing_path = dir.path().join("Rx_Vehicle.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&intact_path, b"game").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(&corrupt_path, b"truncated").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mut patcher = Downloader::new();
+    patcher.instructions = vec![
+      instruction(&intact_path, "AAAA", &sha256(b"game")),
+      instruction(&corrupt_path, "AAAA", &sha256(b"pawn")),
+      instruction(&missing_path, "AAAA", &sha256(b"vehicle")),
+    ];
+    let corrupt_files = patcher.verify_since(std::time::UNIX_EPOCH).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(corrupt_files, vec![
+      CorruptFile { path: corrupt_path.clone(), expected_hash: sha256(b"pawn"), actual_hash: Some(sha256(b"truncated")) },
+      CorruptFile { path: missing_path.clone(), expected_hash: sha256(b"vehicle"), actual_hash: None },
+    ]);
+    let later = SystemTime::now() + Duration::from_secs(3600);
+    let corrupt_files = patcher.verify_since(later).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(corrupt_files.into_iter().map(|file| file.path).collect::<Vec<String>>(), vec![missing_path]);
+  }
+
+  #[test]
+  fn previous_manifest_skips_unchanged_files() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    let entry = |path: &str, new_hash: &str| format!(r#"{{ "Path": "{}", "OldHash": null, "NewHash": "{}", "CompressedHash": "{}", "DeltaHash": null, "FullReplaceSize": 1000, "DeltaSize": 0, "HasDelta": false }}"#, path, new_hash, new_hash);
+    let previous = format!("[{}, {}]", entry("RenX_Game.u", "AAAA"), entry("Rx_Pawn.u", "CCCC"));
+    let current = format!("[{}, {}]", entry("RenX_Game.u", "AAAA"), entry("Rx_Pawn.u", "DDDD"));
+    //Neither file matches its NewHash, only the one that changed is hashed and found out
+    std::fs::write(format!("{}RenX_Game.u", &location), b"modified").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(format!("{}Rx_Pawn.u", &location), b"modified").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.set_previous_manifest(&previous);
+    patcher.instructions = parse_instructions(&current, |path| patcher.install_path(path)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.process_instructions().expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.check_hashes().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    assert_eq!(download_hashmap.keys().cloned().collect::<Vec<String>>(), vec![full_key("DDDD")]);
+    assert_eq!(patcher.state.lock_unpoisoned().hashes_checked, (2, 2));
+    assert_eq!(patcher.state.lock_unpoisoned().hash_progress, (2, 2));
+  }
+
+  #[test]
+  fn single_files_are_verified() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    std::fs::write(format!("{}RenX_Game.u", &location), b"game").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(format!("{}Rx_Pawn.u", &location), b"truncated").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    assert!(patcher.verify_file("RenX_Game.u").is_err());
+    patcher.instructions = vec![
+      instruction(&format!("{}RenX_Game.u", &location), "AAAA", &sha256(b"game")),
+      instruction(&format!("{}Rx_Pawn.u", &location), "AAAA", &sha256(b"pawn")),
+    ];
+    assert!(patcher.verify_file("RenX_Game.u").expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert!(!patcher.verify_file("Rx_Pawn.u").expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert!(patcher.verify_file("Rx_Vehicle.u").is_err());
+  }
+
+  #[test]
+  fn instructions_are_exposed_as_views() {
+    let mut patcher = Downloader::new();
+    assert_eq!(patcher.instructions().count(), 0);
+    patcher.instructions = vec![instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")];
+    assert_eq!(patcher.instructions().collect::<Vec<InstructionView>>(), vec![InstructionView {
+      path: "/RenegadeX/UDKGame/CookedPC/RenX_Game.u",
+      old_hash: Some("AAAA"),
+      new_hash: Some("BBBB"),
+      full_replace_size: 1000,
+      delta_size: 100,
+      has_delta: true,
+    }]);
+  }
+
+  #[test]
+  fn locations_are_normalized() {
+    let cases = [
+      ("/RenegadeX", "/RenegadeX/"),
+      ("/RenegadeX/", "/RenegadeX/"),
+      ("/RenegadeX//", "/RenegadeX/"),
+      ("/Games//RenegadeX///", "/Games/RenegadeX/"),
+      ("/", "/"),
+      ("C:\\Games\\RenegadeX", "C:/Games/RenegadeX/"),
+      ("C:\\", "C:/"),
+      ("\\\\server\\share\\RenegadeX", "//server/share/RenegadeX/"),
+      ("RenegadeX", "RenegadeX/"),
+      ("", "./"),
+    ];
+    for (location, normalized) in cases.iter() {
+      let mut patcher = Downloader::new();
+      patcher.set_location(location.to_string());
+      assert_eq!(patcher.renegadex_location.as_ref().map(|location| location.as_str()), Some(*normalized), "location {:?}", location);
+      assert_eq!(patcher.patch_path("BBBB"), format!("{}patcher/BBBB", normalized));
+      assert_eq!(patcher.install_path("/UDKGame/CookedPC/RenX_Game.u"), format!("{}UDKGame/CookedPC/RenX_Game.u", normalized));
+    }
+  }
+
+  #[test]
+  fn path_mapper_rewrites_install_paths() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX".to_string());
+    assert_eq!(patcher.install_path("UDKGame\\CookedPC\\RenX_Game.u"), "/RenegadeX/UDKGame/CookedPC/RenX_Game.u");
+    patcher.set_path_mapper(Box::new(|path: &str| path.replacen("UDKGame/", "Game/", 1)));
+    assert_eq!(patcher.install_path("UDKGame\\CookedPC\\RenX_Game.u"), "/RenegadeX/Game/CookedPC/RenX_Game.u");
+    assert_eq!(patcher.install_path("Binaries/Win64/UDK.exe"), "/RenegadeX/Binaries/Win64/UDK.exe");
+  }
+
+  #[test]
+  fn paths_outside_of_the_installation_are_refused() {
+    let entry = |path: &str| format!(r#"[{{ "Path": "{}", "OldHash": null, "NewHash": "BBBB", "CompressedHash": null, "DeltaHash": null, "FullReplaceSize": 1, "DeltaSize": 0, "HasDelta": false }}]"#, path);
+    for path in &["..\\\\..\\\\Windows\\\\explorer.exe", "UDKGame/../../.bashrc", "/etc/passwd", "C:\\\\Windows\\\\explorer.exe"] {
+      let error = parse_instructions(&entry(path), |path| path.to_string()).expect_err("Expected the path to be refused");
+      assert_eq!(error.kind, ErrorKind::PathTraversal { path: path.replace("\\\\", "/") });
+    }
+    let instructions = parse_instructions(&entry("UDKGame\\\\CookedPC\\\\..RenX_Game.u"), |path| path.to_string()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(instructions[0].path, "UDKGame/CookedPC/..RenX_Game.u");
+  }
+
+  #[test]
+  fn manifests_are_compared_by_new_hash() {
+    let entry = |path: &str, new_hash: Option<&str>, size: usize| -> String {
+      format!(r#"{{ "Path": "{}", "OldHash": null, "NewHash": {}, "CompressedHash": null, "DeltaHash": null, "FullReplaceSize": {}, "DeltaSize": 0, "HasDelta": false }}"#, path, new_hash.map_or("null".to_string(), |hash| format!("\"{}\"", hash)), size)
+    };
+    let old = format!("[{}, {}, {}]", entry("UDKGame\\\\CookedPC\\\\RenX_Game.u", Some("AAAA"), 100), entry("UDKGame/CookedPC/Rx_Pawn.u", Some("BBBB"), 50), entry("UDKGame/CookedPC/Old.upk", Some("CCCC"), 10));
+    let new = format!("[{}, {}, {}, {}]", entry("UDKGame/CookedPC/RenX_Game.u", Some("DDDD"), 120), entry("UDKGame/CookedPC/Rx_Pawn.u", Some("BBBB"), 50), entry("UDKGame/CookedPC/Old.upk", None, 0), entry("UDKGame/CookedPC/New.upk", Some("EEEE"), 30));
+    let diff = diff_manifests(&old, &new).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(diff.added, vec![ManifestChange { path: "UDKGame/CookedPC/New.upk".to_string(), old_size: None, new_size: Some(30) }]);
+    assert_eq!(diff.removed, vec![ManifestChange { path: "UDKGame/CookedPC/Old.upk".to_string(), old_size: Some(10), new_size: None }]);
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].path, "UDKGame/CookedPC/RenX_Game.u");
+    assert_eq!(diff.changed[0].size_delta(), 20);
+    assert!(diff_manifests("[{ \"Path\": \"a\" }]", &new).is_err());
+  }
+
+  #[test]
+  fn downloader_survives_a_panic_while_locked() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX".to_string());
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+      let _download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+      let _state = patcher.state.lock_unpoisoned();
+      panic!("Panicking while holding the locks");
+    }));
+    assert!(panicked.is_err());
+    assert!(patcher.download_hashmap.is_poisoned());
+    assert!(patcher.pending_downloads().is_empty());
+    patcher.reset();
+    assert!(patcher.queue_full_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert_eq!(patcher.pending_downloads().len(), 1);
+    assert_eq!(patcher.get_progress().lock_unpoisoned().download_size.1, 1000);
+  }
+
+  #[test]
+  fn resumed_downloads_count_as_done_while_planning() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.borrow().clone();
+    std::fs::create_dir_all(format!("{}patcher/", &location)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    //Two out of three parts were downloaded by an earlier run, the counter at the end says so
+    let file_size = 2 * PART_SIZE + PART_SIZE / 2;
+    let mut partial = vec![0u8; file_size];
+    partial.extend_from_slice(&2u32.to_be_bytes());
+    std::fs::write(format!("{}patcher/BBBB", &location), &partial).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mut resumed = instruction("UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB");
+    resumed.full_replace_size = file_size;
+    patcher.queue_full_download(&resumed).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_full_download(&instruction("UDKGame/CookedPC/RenX_UI.u", "AAAA", "CCCC")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut progress = patcher.get_progress().lock_unpoisoned().clone();
+    assert_eq!(progress.download_size, ((2 * PART_SIZE) as u64, (file_size + 1000) as u64));
+    progress.total_known = true;
+    assert_eq!(progress.download_fraction(), Some((2 * PART_SIZE) as f64 / (file_size + 1000) as f64));
+  }
+
+  #[test]
+  fn served_size_replaces_manifest_size() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX".to_string());
+    patcher.queue_full_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    let download_entry = download_hashmap.get("BBBB").expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(patcher.resized_entry("http://localhost/full/BBBB", download_entry, 1000).is_none());
+    let resized_entry = patcher.resized_entry("http://localhost/full/BBBB", download_entry, 1200).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(resized_entry.file_size, 1200);
+    assert_eq!(resized_entry.file_hash, download_entry.file_hash);
+    assert_eq!(resized_entry.patch_entries.len(), 1);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size.1, 1200);
+  }
+
+  fn instruction(path: &str, old_hash: &str, new_hash: &str) -> Instruction {
+    Instruction {
+      path: path.to_string(),
+      old_hash: Some(old_hash.to_string()),
+      new_hash: Some(new_hash.to_string()),
+      compressed_hash: Some(format!("{}_COMPRESSED", new_hash)),
+      delta_hash: Some(format!("{}_DELTA", new_hash)),
+      full_replace_size: 1000,
+      delta_size: 100,
+      has_delta: true,
+      compressed_part_hashes: Vec::new(),
+      delta_part_hashes: Vec::new(),
+      mode: None,
+    }
+  }
+
+  #[test]
+  fn sampled_verification_always_checks_executables() {
+    let options = PatchOptions { verification_level: VerificationLevel::Sampled(0), ..PatchOptions::new() };
+    assert!(options.should_verify("/RenegadeX/Binaries/Win64/UDK.exe"));
+    assert!(options.should_verify("/RenegadeX/Binaries/Win64/steam_api64.DLL"));
+    assert!(!options.should_verify("/RenegadeX/UDKGame/CookedPC/RenX_Game.u"));
+    let options = PatchOptions { verification_level: VerificationLevel::Sampled(100), ..PatchOptions::new() };
+    assert!(options.should_verify("/RenegadeX/UDKGame/CookedPC/RenX_Game.u"));
+  }
+
+  #[test]
+  fn slow_mirror_is_abandoned_after_its_budget() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content : Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    //Sends 50 KB/s, so the whole file takes two seconds
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let server = format!("http://{}/", listener.local_addr().expect(concat!(module_path!(),":",file!(),":",line!())));
+    {
+      let content = content.clone();
+      std::thread::spawn(move || {
+        for stream in listener.incoming() {
+          let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_e) => continue
+          };
+          let mut request = Vec::new();
+          let mut buf = [0u8; 1024];
+          while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buf) {
+              Ok(0) | Err(_) => break,
+              Ok(read) => request.extend_from_slice(&buf[..read]),
+            }
+          }
+          stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", content.len()).as_bytes()).ok();
+          for chunk in content.chunks(5_000) {
+            if stream.write_all(chunk).is_err() {
+              break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+          }
+        }
+      });
+    }
+    let release_json = release_for("", &[&server]);
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.set_min_acceptable_speed(500_000);
+    let download_entry = DownloadEntry {
+      file_path: dir.path().join("CONTENT").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string(),
+      file_size: content.len(),
+      file_hash: sha256(&content),
+      part_hashes: Vec::new(),
+      patch_entries: Vec::new(),
+      resumed_size: 0,
+    };
+    let mirror = patcher.mirrors.get_mirror();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+    let error = patcher.download_file(&mirror, &format!("{}full/CONTENT", &server), &download_entry, true, &cancelled).expect_err("Expected the slow mirror to be abandoned");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(error.remove_mirror);
+    assert!(error.to_string().contains("too slow"));
+  }
+
+  #[test]
+  fn slow_parts_abandon_only_the_transfer() {
+    let mut patcher = Downloader::new();
+    patcher.set_slow_mirror_threshold(1.0);
+    let mut mirror = Mirror::parse("http://127.0.0.1:1/", "").expect(concat!(module_path!(),":",file!(),":",line!()));
+    //No connection gets close to a benchmark this fast
+    mirror.speed = 1e12;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mut transfer = patcher.transfer(&mirror, &cancelled, (SLOW_PARTS * PART_SIZE) as u64);
+    for _ in 1..SLOW_PARTS {
+      assert!(transfer.on_chunk(PART_SIZE));
+    }
+    assert!(!transfer.on_chunk(PART_SIZE));
+    assert!(!transfer.over_budget.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn corrupt_complete_download_keeps_its_good_parts() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content : Vec<u8> = (0..2 * PART_SIZE + PART_SIZE / 2).map(|i| (i % 251) as u8).collect();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let server = {
+      let requests = requests.clone();
+      let content = content.clone();
+      mock_server_with(Vec::new(), move |request, _body| {
+        requests.lock_unpoisoned().push(request.to_string());
+        ranged_response(request, Some(&content))
+      })
+    };
+    let release_json = release_for("", &[&server]);
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let file_path = dir.path().join("CONTENT").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let download_entry = DownloadEntry {
+      file_path: file_path.clone(),
+      file_size: content.len(),
+      file_hash: sha256(&content),
+      part_hashes: content.chunks(PART_SIZE).map(sha256).collect(),
+      patch_entries: Vec::new(),
+      resumed_size: 0,
+    };
+    //Downloaded in full by an earlier attempt, but the second part is corrupt
+    let mut corrupt = content.clone();
+    corrupt[PART_SIZE + 10] ^= 1;
+    std::fs::write(&file_path, &corrupt).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mirror = patcher.mirrors.get_mirror();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    patcher.download_file(&mirror, &format!("{}full/CONTENT", &server), &download_entry, true, &cancelled).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&file_path).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    let requests = requests.lock_unpoisoned();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].contains(&format!("bytes={}-", PART_SIZE)));
+  }
+
+  #[test]
+  fn failed_hash_check_keeps_the_resume_counter() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let content : Vec<u8> = (0..2 * PART_SIZE + PART_SIZE / 2).map(|i| (i % 251) as u8).collect();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let server = {
+      let requests = requests.clone();
+      let content = content.clone();
+      mock_server_with(Vec::new(), move |request, _body| {
+        let mut requests = requests.lock_unpoisoned();
+        requests.push(request.to_string());
+        //The first response is corrupt, the next ones aren't
+        let mut body = content.clone();
+        if requests.len() == 1 {
+          body[2 * PART_SIZE] ^= 1;
+        }
+        ranged_response(request, Some(&body))
+      })
+    };
+    let release_json = release_for("", &[&server]);
+    let mut patcher = Downloader::new();
+    patcher.with_release_json(&release_json).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let file_path = dir.path().join("CONTENT").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let download_entry = DownloadEntry {
+      file_path: file_path.clone(),
+      file_size: content.len(),
+      file_hash: sha256(&content),
+      part_hashes: Vec::new(),
+      patch_entries: Vec::new(),
+      resumed_size: 0,
+    };
+    //The size of the download whenever it gets hashed, the counter is still at its end then
+    let hashed_sizes = Arc::new(Mutex::new(Vec::new()));
+    {
+      let hashed_sizes = hashed_sizes.clone();
+      let file_path = file_path.clone();
+      patcher.set_hasher(Box::new(move |path| {
+        hashed_sizes.lock_unpoisoned().push(std::fs::metadata(&file_path)?.len());
+        get_hash(path)
+      }));
+    }
+    //The first part is there already from an earlier attempt
+    let mut partial = content.clone();
+    partial.extend_from_slice(&1u32.to_be_bytes());
+    std::fs::write(&file_path, &partial).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    let mirror = patcher.mirrors.get_mirror();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let error = patcher.download_file(&mirror, &format!("{}full/CONTENT", &server), &download_entry, true, &cancelled).expect_err("Expected the hash check to fail");
+    assert!(matches!(error.kind, traits::ErrorKind::HashMismatch { .. }));
+    let mut file = std::fs::File::open(&file_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(read_resume_part(&mut file, content.len() as u64, 3), 1);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size.0, PART_SIZE as u64);
+
+    patcher.download_file(&mirror, &format!("{}full/CONTENT", &server), &download_entry, false, &cancelled).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&file_path).expect(concat!(module_path!(),":",file!(),":",line!())), content);
+    assert_eq!(*hashed_sizes.lock_unpoisoned(), vec![(content.len() + 4) as u64; 2]);
+    let requests = requests.lock_unpoisoned();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|request| request.contains(&format!("bytes={}-", PART_SIZE))));
+  }
+
+  #[test]
+  fn implausible_resume_part_starts_over() {
+    let mut file = tempfile::tempfile().expect(concat!(module_path!(),":",file!(),":",line!()));
+    file.write_all(&[0u8; 2_500_000]).expect(concat!(module_path!(),":",file!(),":",line!()));
+    file.write_all(&2u32.to_be_bytes()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(read_resume_part(&mut file, 2_500_000, 3), 2);
+    file.seek(SeekFrom::Start(2_500_000)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    file.write_all(&[0xde, 0xad, 0xbe, 0xef]).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(read_resume_part(&mut file, 2_500_000, 3), 0);
+    assert_eq!(read_resume_part(&mut file, 2_500_002, 3), 0);
+  }
+
+  #[test]
+  fn prune_unknown_files_keeps_known_and_ignored_files() {
+    let (_dir, mut patcher) = patcher_in_tempdir();
+    let location = patcher.renegadex_location.clone().expect(concat!(module_path!(),":",file!(),":",line!()));
+    for relative_path in ["UDKGame/CookedPC/RenX_Game.u", "UDKGame/CookedPC/Old_Map.udk", "UDKGame/Config/UDKGame.ini", "Binaries/old.dll"].iter() {
+      let path = format!("{}{}", &location, relative_path);
+      DirBuilder::new().recursive(true).create(&path[..path.rfind('/').expect(concat!(module_path!(),":",file!(),":",line!()))]).expect(concat!(module_path!(),":",file!(),":",line!()));
+      std::fs::write(&path, b"data").expect(concat!(module_path!(),":",file!(),":",line!()));
+    }
+    patcher.instructions.push(instruction(&format!("{}UDKGame/CookedPC/RenX_Game.u", &location), "AAAA", "BBBB"));
+    let expected = vec![format!("{}Binaries/old.dll", &location), format!("{}UDKGame/CookedPC/Old_Map.udk", &location)];
+    assert_eq!(patcher.prune_unknown_files(true).expect(concat!(module_path!(),":",file!(),":",line!())), expected);
+    assert!(std::path::Path::new(&expected[0]).exists());
+    assert_eq!(patcher.prune_unknown_files(false).expect(concat!(module_path!(),":",file!(),":",line!())), expected);
+    assert!(!std::path::Path::new(&expected[0]).exists());
+    assert!(std::path::Path::new(&format!("{}UDKGame/Config/UDKGame.ini", &location)).exists());
+    assert!(std::path::Path::new(&format!("{}UDKGame/CookedPC/RenX_Game.u", &location)).exists());
+  }
+
+  #[test]
+  fn prune_ratio_keeps_the_fastest_mirror() {
+    let mut patcher = Downloader::new();
+    patcher.set_mirror_prune_ratio(Some(0.0));
+    assert_eq!(patcher.mirrors.prune_ratio, Some(1.0));
+    patcher.set_mirror_prune_ratio(Some(-2.0));
+    assert_eq!(patcher.mirrors.prune_ratio, Some(1.0));
+    patcher.set_mirror_prune_ratio(Some(2.5));
+    assert_eq!(patcher.mirrors.prune_ratio, Some(2.5));
+    patcher.set_mirror_prune_ratio(None);
+    assert_eq!(patcher.mirrors.prune_ratio, None);
+  }
+
+  #[test]
+  fn hashing_runs_on_the_configured_thread_pool() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut patcher = Downloader::new();
+    patcher.instructions = (0..4).map(|index| {
+      let path = dir.path().join(format!("Rx_{}.u", index)).to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+      std::fs::write(&path, b"content").expect(concat!(module_path!(),":",file!(),":",line!()));
+      instruction(&path, "AAAA", "BBBB")
+    }).collect();
+    let thread_names = Arc::new(Mutex::new(Vec::new()));
+    {
+      let thread_names = thread_names.clone();
+      patcher.set_hasher(Box::new(move |_path| {
+        thread_names.lock_unpoisoned().push(std::thread::current().name().unwrap_or("").to_string());
+        Ok("BBBB".to_string())
+      }));
+    }
+    configure_thread_pool(2).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(patcher.verify_since(std::time::UNIX_EPOCH).expect(concat!(module_path!(),":",file!(),":",line!())).is_empty());
+    let thread_names = thread_names.lock_unpoisoned();
+    assert_eq!(thread_names.len(), 4);
+    assert!(thread_names.iter().all(|name| name == "patcher-0" || name == "patcher-1"));
+  }
+
+  #[test]
+  fn untrusted_metadata_hashes_every_time() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&path, b"content").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let hashed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut patcher = Downloader::new();
+    {
+      let hashed = hashed.clone();
+      patcher.set_hasher(Box::new(move |_path| {
+        hashed.fetch_add(1, Ordering::SeqCst);
+        Ok("BBBB".to_string())
+      }));
+    }
+    patcher.set_hash_cache(dir.path().join("hashes.json").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string());
+    let new_hash = Some("BBBB".to_string());
+    for _ in 0..2 {
+      assert_eq!(patcher.cached_hash(&path, new_hash.as_ref()).expect(concat!(module_path!(),":",file!(),":",line!())), "BBBB");
+    }
+    assert_eq!(hashed.load(Ordering::SeqCst), 1);
+    patcher.set_trust_metadata(false);
+    for _ in 0..2 {
+      assert_eq!(patcher.cached_hash(&path, new_hash.as_ref()).expect(concat!(module_path!(),":",file!(),":",line!())), "BBBB");
+    }
+    assert_eq!(hashed.load(Ordering::SeqCst), 3);
+  }
+
+  #[test]
+  fn custom_hasher_is_upper_cased() {
+    let mut patcher = Downloader::new();
+    patcher.set_hasher(Box::new(|path| Ok(format!("{}_cafe", path.to_string_lossy()))));
+    assert_eq!(patcher.patch_options.hasher.hash("a").expect(concat!(module_path!(),":",file!(),":",line!())), "A_CAFE");
+    let missing = PatchOptions::new().hasher.hash("/nonexistent/RenX_Game.u");
+    assert!(missing.is_err());
+  }
+
+  #[test]
+  fn download_keys_have_a_fixed_format() {
+    assert_eq!(delta_key("BBBB", "AAAA"), "BBBB_from_AAAA");
+    assert_eq!(full_key("BBBB"), "BBBB");
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX/".to_string());
+    assert!(patcher.queue_delta_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!())));
+    let download_hashmap = patcher.download_hashmap.lock_unpoisoned();
+    let download_entry = &download_hashmap["BBBB_from_AAAA"];
+    assert_eq!(download_entry.file_path, "/RenegadeX/patcher/BBBB_from_AAAA");
+    drop(download_hashmap);
+    assert_eq!(patcher.patch_dir(), std::path::PathBuf::from("/RenegadeX/patcher/"));
+  }
+
+  #[test]
+  fn malformed_instructions_are_not_queued_as_deltas() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX/".to_string());
+    let mut without_delta_hash = instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB");
+    without_delta_hash.delta_hash = None;
+    assert!(patcher.queue_delta_download(&without_delta_hash).expect(concat!(module_path!(),":",file!(),":",line!())));
+    let pending = patcher.pending_downloads();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].key, "BBBB");
+    assert!(!pending[0].delta);
+
+    let mut without_new_hash = instruction("/RenegadeX/UDKGame/CookedPC/RenX_UI.u", "AAAA", "CCCC");
+    without_new_hash.new_hash = None;
+    assert!(patcher.queue_delta_download(&without_new_hash).is_err());
+    assert!(patcher.queue_full_download(&without_new_hash).is_err());
+    assert_eq!(patcher.pending_downloads().len(), 1);
+  }
+
+  #[test]
+  fn target_is_only_queued_once() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX/".to_string());
+    let instruction = instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB");
+    assert!(patcher.queue_full_download(&instruction).expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert!(!patcher.queue_delta_download(&instruction).expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert!(!patcher.queue_full_download(&instruction).expect(concat!(module_path!(),":",file!(),":",line!())));
+    let pending = patcher.pending_downloads();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].key, "BBBB");
+    assert_eq!(pending[0].target_paths, vec!["/RenegadeX/UDKGame/CookedPC/RenX_Game.u".to_string()]);
+    let state = patcher.state.lock_unpoisoned();
+    assert_eq!(state.patch_files, (0, 1));
+    assert_eq!(state.download_size, (0, 1000));
+  }
+
+  #[test]
+  fn cancelled_downloads_are_not_counted() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX/".to_string());
+    patcher.queue_full_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_full_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Map.u", "CCCC", "DDDD")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(patcher.state.lock_unpoisoned().download_size, (0, 2000));
+    let download_entry = patcher.download_hashmap.lock_unpoisoned().remove("BBBB").expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.skip_cancelled("BBBB", &download_entry).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let state = patcher.state.lock_unpoisoned();
+    assert_eq!(state.download_size, (0, 1000));
+    assert_eq!(state.patch_files, (0, 1));
+  }
+
+  #[test]
+  fn shared_content_is_downloaded_once() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX/".to_string());
+    patcher.queue_full_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "CCCC")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_delta_download(&instruction("/RenegadeX/UDKGame/CookedPC/Copy/RenX_Game.u", "BBBB", "CCCC")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.dedup_downloads();
+    let pending = patcher.pending_downloads();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].key, "CCCC");
+    assert_eq!(pending[0].target_paths.len(), 2);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size, (0, 1000));
+
+    //Two deltas that add up to more than the full file
+    patcher.reset();
+    let first = Instruction { delta_size: 600, ..instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "CCCC") };
+    let second = Instruction { delta_size: 600, ..instruction("/RenegadeX/UDKGame/CookedPC/Copy/RenX_Game.u", "BBBB", "CCCC") };
+    patcher.instructions = vec![first.clone(), second.clone()];
+    patcher.queue_delta_download(&first).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_delta_download(&second).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.dedup_downloads();
+    let pending = patcher.pending_downloads();
+    assert_eq!(pending.len(), 1);
+    assert!(!pending[0].delta);
+    assert_eq!(pending[0].target_paths.len(), 2);
+    assert_eq!(patcher.state.lock_unpoisoned().download_size, (0, 1000));
+    assert_eq!(patcher.state.lock_unpoisoned().patch_files, (0, 2));
+  }
+
+  #[test]
+  fn interrupted_delta_sources_are_put_back() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let target_path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&target_path, b"half patched").expect(concat!(module_path!(),":",file!(),":",line!()));
+    //Left behind by two earlier runs, only one of them is still the old file
+    std::fs::write(format!("{}.vcdiff_src.1-0", &target_path), b"old").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(format!("{}.vcdiff_src.2-0", &target_path), b"other").expect(concat!(module_path!(),":",file!(),":",line!()));
+    *patcher.hash_queue.lock_unpoisoned() = vec![instruction(&target_path, &sha256(b"old"), &sha256(b"new"))];
+    patcher.check_hashes().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"old");
+    assert!(!std::path::Path::new(&format!("{}.vcdiff_src.1-0", &target_path)).exists());
+    assert!(!std::path::Path::new(&format!("{}.vcdiff_src.2-0", &target_path)).exists());
+    let pending = patcher.pending_downloads();
+    assert_eq!(pending.len(), 1);
+    assert!(pending[0].delta);
+  }
+
+  #[test]
+  fn user_modified_files_are_kept_or_backed_up() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let target_path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&target_path, b"modded").expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.set_user_modified_policy(UserModifiedPolicy::Skip);
+    *patcher.hash_queue.lock_unpoisoned() = vec![instruction(&target_path, "AAAA", "BBBB")];
+    patcher.check_hashes().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(patcher.pending_downloads().is_empty());
+
+    patcher.reset();
+    patcher.set_user_modified_policy(UserModifiedPolicy::Backup);
+    *patcher.hash_queue.lock_unpoisoned() = vec![instruction(&target_path, "AAAA", "BBBB")];
+    patcher.check_hashes().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(patcher.pending_downloads().len(), 1);
+    assert!(!std::path::Path::new(&target_path).exists());
+    assert_eq!(std::fs::read(format!("{}.bak", &target_path)).expect(concat!(module_path!(),":",file!(),":",line!())), b"modded");
+  }
+
+  #[test]
+  fn user_modified_files_that_cannot_be_backed_up_are_kept() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let target_path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&target_path, b"modded").expect(concat!(module_path!(),":",file!(),":",line!()));
+    //A file can't be renamed over a directory that isn't empty
+    std::fs::create_dir_all(format!("{}.bak/taken", &target_path)).expect(concat!(module_path!(),":",file!(),":",line!()));
+
+    patcher.set_user_modified_policy(UserModifiedPolicy::Backup);
+    *patcher.hash_queue.lock_unpoisoned() = vec![instruction(&target_path, "AAAA", "BBBB")];
+    patcher.check_hashes().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(patcher.pending_downloads().is_empty());
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"modded");
+  }
+
+  #[test]
+  fn http2_requests_carry_the_whole_url() {
+    let url = "http://localhost:8080/patches/full/AAAA".parse::<hyper::Uri>().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut mirror = Mirror::parse("http://localhost:8080/", "patches/").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut patcher = Downloader::new();
+    let req = patcher.start_request(&mirror, &url, false).1.body(()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(req.uri().to_string(), "/patches/full/AAAA");
+    assert_eq!(req.headers()["host"], "localhost");
+    //Auto goes by what the benchmark found
+    mirror.http2 = true;
+    let req = patcher.start_request(&mirror, &url, false).1.body(()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(req.uri(), &url);
+    assert!(req.headers().get("host").is_none());
+    patcher.set_http_version(HttpVersion::Http1);
+    let req = patcher.start_request(&mirror, &url, false).1.body(()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(req.uri().to_string(), "/patches/full/AAAA");
+    mirror.http2 = false;
+    patcher.set_http_version(HttpVersion::Http2);
+    let req = patcher.start_request(&mirror, &url, false).1.body(()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(req.uri(), &url);
+    assert!(req.headers().get("host").is_none());
+    //Proxies are spoken to with HTTP/1.1
+    let req = patcher.start_request(&mirror, &url, true).1.body(()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(req.uri(), &url);
+    assert_eq!(req.headers()["host"], "localhost");
+  }
+
+  #[test]
+  fn progress_is_polled_by_one_thread() {
+    let patcher = Downloader::new();
+    patcher.poll_progress();
+    let first = patcher.progress_thread.lock_unpoisoned().as_ref().map(|handle| handle.thread().id());
+    patcher.poll_progress();
+    assert_eq!(patcher.progress_thread.lock_unpoisoned().as_ref().map(|handle| handle.thread().id()), first);
+    patcher.shutdown().expect(concat!(module_path!(),":",file!(),":",line!()));
+  }
+
+  #[test]
+  fn progress_events_are_received_until_shutdown() {
+    let patcher = Downloader::new();
+    patcher.state.lock_unpoisoned().hash_progress = (1, 2);
+    let events = patcher.progress_events();
+    let progress = events.recv_timeout(Duration::from_secs(5)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(progress.hash_progress, (1, 2));
+    patcher.shutdown().expect(concat!(module_path!(),":",file!(),":",line!()));
+    //Whatever was sent before the shutdown can still be received, then the channel is closed
+    while let Ok(_progress) = events.try_recv() {}
+    assert!(matches!(events.try_recv(), Err(std::sync::mpsc::TryRecvError::Disconnected)));
+  }
+
+  #[test]
+  fn current_files_are_listed_while_busy() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/games/RenegadeX".to_string());
+    let game = CurrentFile::enter(&patcher.state, "/games/RenegadeX/UDKGame/CookedPC/RenX_Game.u");
+    let pawn = CurrentFile::enter(&patcher.state, "/games/RenegadeX/UDKGame/CookedPC/Rx_Pawn.u");
+    let patching_pawn = CurrentFile::enter(&patcher.state, "/games/RenegadeX/UDKGame/CookedPC/Rx_Pawn.u");
+    assert_eq!(patcher.current_files(), vec!["UDKGame/CookedPC/RenX_Game.u".to_string(), "UDKGame/CookedPC/Rx_Pawn.u".to_string()]);
+    drop(game);
+    drop(pawn);
+    assert_eq!(patcher.current_files(), vec!["UDKGame/CookedPC/Rx_Pawn.u".to_string()]);
+    drop(patching_pawn);
+    assert!(patcher.current_files().is_empty());
+  }
+
+  #[test]
+  fn up_to_date_files_are_counted() {
+    let (dir, mut patcher) = patcher_in_tempdir();
+    let current_path = dir.path().join("RenX_Game.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let outdated_path = dir.path().join("Rx_Pawn.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&current_path, b"game").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(&outdated_path, b"old pawn").expect(concat!(module_path!(),":",file!(),":",line!()));
+    *patcher.hash_queue.lock_unpoisoned() = vec![instruction(&current_path, "AAAA", &sha256(b"game")), instruction(&outdated_path, &sha256(b"old pawn"), &sha256(b"pawn"))];
+    patcher.check_hashes().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let state = patcher.state.lock_unpoisoned();
+    assert_eq!(state.files_up_to_date, 1);
+    assert_eq!(state.patch_files.1, 1);
+  }
+
+  #[test]
+  fn delta_bytes_are_applied_and_verified() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target_path = dir.path().join("RenX_Game.u");
+    let patcher = Downloader::new();
+    patcher.apply_delta_bytes(&target_path, None, &vcdiff(b"game"), &sha256(b"game").to_lowercase()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"game");
+    assert!(patcher.apply_delta_bytes(&target_path, None, &vcdiff(b"wrong"), &sha256(b"game")).is_err());
+    assert!(!dir.path().join("RenX_Game.u.vcdiff_delta").exists());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn patched_files_get_their_mode() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mode = |path: &std::path::Path| std::fs::metadata(path).expect(concat!(module_path!(),":",file!(),":",line!())).permissions().mode() & 0o777;
+    let script = dir.path().join("Binaries").join("Linux").join("RenegadeX");
+    let config = dir.path().join("UDKGame").join("Config").join("DefaultGame.ini");
+    let patcher = Downloader::new();
+    patcher.apply_delta_bytes(&script, None, &vcdiff(b"#!/bin/sh"), &sha256(b"#!/bin/sh")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.apply_delta_bytes(&config, None, &vcdiff(b"[Game]"), &sha256(b"[Game]")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(mode(&script), 0o755);
+    assert_eq!(mode(&config) & 0o111, 0);
+    set_mode(config.to_str().expect(concat!(module_path!(),":",file!(),":",line!())), Some(0o700)).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(mode(&config), 0o700);
+  }
+
+  #[test]
+  fn apply_patch_detects_changed_source() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target_path = dir.path().join("Rx_Pawn.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    std::fs::write(&target_path, b"original").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let patch_entry = PatchEntry {
+      target_path: target_path.clone(),
+      delta_path: format!("{}_delta", &target_path),
+      kind: DownloadKind::Delta,
+      source_hash: Some(get_hash(std::path::Path::new(&target_path)).expect(concat!(module_path!(),":",file!(),":",line!()))),
+      target_hash: "".to_string(),
+      mode: None,
+    };
+    std::fs::write(&target_path, b"modified after hashing").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let state = Arc::new(Mutex::new(Progress::new()));
+    let outcome = apply_patch(&patch_entry, &PatchOptions::new(), state.clone()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(outcome, PatchOutcome::SourceChanged);
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"modified after hashing");
+    assert_eq!(state.lock_unpoisoned().patch_files.0, 0);
+  }
+
+  #[test]
+  fn delta_verification_restores_mismatched_source() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target_path = dir.path().join("Rx_Pawn.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let delta_path = format!("{}_delta", &target_path);
+    std::fs::write(&target_path, b"wrong source").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(&delta_path, vcdiff(b"wrong result")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut patch_entry = PatchEntry {
+      target_path: target_path.clone(),
+      delta_path,
+      kind: DownloadKind::Delta,
+      source_hash: None,
+      target_hash: sha256(b"right result"),
+      mode: None,
+    };
+    let options = PatchOptions { verification_level: VerificationLevel::None, verify_deltas: true, ..PatchOptions::new() };
+    let state = Arc::new(Mutex::new(Progress::new()));
+    assert!(apply_patch(&patch_entry, &options, state.clone()).is_err());
+
+    patch_entry.source_hash = Some(sha256(b"wrong source"));
+    let outcome = apply_patch(&patch_entry, &options, state.clone()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(outcome, PatchOutcome::SourceChanged);
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"wrong source");
+    assert!(!std::path::Path::new(&format!("{}.vcdiff_src.{}", &target_path, &options.run_id)).exists());
+    assert_eq!(state.lock_unpoisoned().patch_files.0, 0);
+  }
+
+  #[test]
+  fn download_kind_decides_the_expected_hashes() {
+    let mut patcher = Downloader::new();
+    patcher.set_location("/RenegadeX/".to_string());
+    let mut raw = instruction("/RenegadeX/UDKGame/CookedPC/RenX_Game.u", "AAAA", "BBBB");
+    raw.compressed_hash = None;
+    let mut raw_with_hash = instruction("/RenegadeX/UDKGame/CookedPC/RenX_Maps.u", "AAAA", "CCCC");
+    raw_with_hash.compressed_hash = Some("cccc".to_string());
+    patcher.queue_full_download(&raw).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_full_download(&raw_with_hash).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_full_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_UI.u", "AAAA", "DDDD")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    patcher.queue_delta_download(&instruction("/RenegadeX/UDKGame/CookedPC/RenX_Weapons.u", "AAAA", "EEEE")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let pending : HashMap<String, PendingDownload> = patcher.pending_downloads().into_iter().map(|download| (download.key.clone(), download)).collect();
+    assert_eq!((pending["BBBB"].kind, pending["BBBB"].hash.as_str()), (DownloadKind::RawFull, "BBBB"));
+    assert_eq!((pending["CCCC"].kind, pending["CCCC"].hash.as_str()), (DownloadKind::RawFull, "CCCC"));
+    assert_eq!((pending["DDDD"].kind, pending["DDDD"].hash.as_str()), (DownloadKind::CompressedFull, "DDDD_COMPRESSED"));
+    assert_eq!((pending["EEEE_from_AAAA"].kind, pending["EEEE_from_AAAA"].hash.as_str()), (DownloadKind::Delta, "EEEE_DELTA"));
+    assert!(pending["EEEE_from_AAAA"].delta);
+  }
+
+  #[test]
+  fn raw_full_download_is_copied_into_place() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target_path = dir.path().join("Rx_Pawn.u").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let download_path = format!("{}_full", &target_path);
+    std::fs::write(&target_path, b"old content").expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::write(&download_path, b"new content").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut patch_entry = PatchEntry {
+      target_path: target_path.clone(),
+      delta_path: download_path.clone(),
+      kind: DownloadKind::RawFull,
+      source_hash: None,
+      target_hash: sha256(b"new content"),
+      mode: None,
+    };
+    let options = PatchOptions { verification_level: VerificationLevel::Full, ..PatchOptions::new() };
+    let state = Arc::new(Mutex::new(Progress::new()));
+    assert_eq!(apply_patch(&patch_entry, &options, state.clone()).expect(concat!(module_path!(),":",file!(),":",line!())), PatchOutcome::Applied);
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"new content");
+    assert!(std::path::Path::new(&download_path).exists());
+    patch_entry.target_hash = sha256(b"other content");
+    assert!(apply_patch(&patch_entry, &options, state.clone()).is_err());
+  }
+
+  #[test]
+  fn raw_full_download_is_moved_on_the_same_volume() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let location = format!("{}/", dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())));
+    let patch_dir = format!("{}{}", &location, PATCH_SUBDIR);
+    std::fs::create_dir(&patch_dir).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(detect_write_strategy(&patch_dir, &location), WriteStrategy::Move);
+    assert!(std::fs::read_dir(&patch_dir).expect(concat!(module_path!(),":",file!(),":",line!())).next().is_none());
+    //Renaming into a directory that isn't there fails like renaming to another volume does
+    assert_eq!(detect_write_strategy(&patch_dir, &format!("{}missing/", &location)), WriteStrategy::Copy);
+
+    let target_path = format!("{}Rx_Pawn.u", &location);
+    let download_path = format!("{}Rx_Pawn_full", &patch_dir);
+    std::fs::write(&download_path, b"new content").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let patch_entry = PatchEntry {
+      target_path: target_path.clone(),
+      delta_path: download_path.clone(),
+      kind: DownloadKind::RawFull,
+      source_hash: None,
+      target_hash: sha256(b"new content"),
+      mode: None,
+    };
+    let options = PatchOptions { write_strategy: WriteStrategy::Move, ..PatchOptions::new() };
+    let state = Arc::new(Mutex::new(Progress::new()));
+    assert_eq!(apply_patch(&patch_entry, &options, state.clone()).expect(concat!(module_path!(),":",file!(),":",line!())), PatchOutcome::Applied);
+    assert_eq!(std::fs::read(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"new content");
+    assert!(!std::path::Path::new(&download_path).exists());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn apply_patch_leaves_symlinks_alone() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let shared_path = dir.path().join("shared.upk");
+    std::fs::write(&shared_path, b"shared asset").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let target_path = dir.path().join("CookedPC").join("shared.upk");
+    std::fs::create_dir(dir.path().join("CookedPC")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::os::unix::fs::symlink(&shared_path, &target_path).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let patch_entry = PatchEntry {
+      target_path: target_path.to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string(),
+      delta_path: dir.path().join("delta").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string(),
+      kind: DownloadKind::CompressedFull,
+      source_hash: None,
+      target_hash: "".to_string(),
+      mode: None,
+    };
+    let state = Arc::new(Mutex::new(Progress::new()));
+    let outcome = apply_patch(&patch_entry, &PatchOptions::new(), state.clone()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(outcome, PatchOutcome::Skipped);
+    assert!(apply_patch(&patch_entry, &PatchOptions { symlink_policy: SymlinkPolicy::Error, ..PatchOptions::new() }, state.clone()).is_err());
+    assert!(std::fs::symlink_metadata(&target_path).expect(concat!(module_path!(),":",file!(),":",line!())).file_type().is_symlink());
+    assert_eq!(std::fs::read(&shared_path).expect(concat!(module_path!(),":",file!(),":",line!())), b"shared asset");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlink_loops_are_refused() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::os::unix::fs::symlink("second.upk", dir.path().join("first.upk")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::os::unix::fs::symlink("first.upk", dir.path().join("second.upk")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::os::unix::fs::symlink("shared.upk", dir.path().join("linked.upk")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let path = |name: &str| dir.path().join(name).to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    assert!(resolve_symlinks(&path("first.upk")).is_err());
+    assert_eq!(resolve_symlinks(&path("linked.upk")).expect(concat!(module_path!(),":",file!(),":",line!())), path("shared.upk"));
   }
 }
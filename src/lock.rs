@@ -0,0 +1,117 @@
+use std::io::{Seek, SeekFrom, Write};
+use crate::traits::{Error, ErrorKind};
+
+/// Kept in the installation directory while a patcher is working on it.
+pub const LOCK_FILE : &str = "patcher.lock";
+
+/**
+ Keeps other patchers from working on the same installation, e.g. a second launcher instance.
+ The lock is an advisory lock held on the lock file by the operating system, so it goes away with its owner even after a crash. The file holds the process id of the owner for the error message.
+ The file itself stays behind, removing it would let a patcher that opened it just before lock a file nobody else sees anymore.
+*/
+pub struct InstallLock {
+  file: std::fs::File,
+}
+
+impl InstallLock {
+  pub fn acquire(location: &str) -> Result<InstallLock, Error> {
+    //A fresh installation doesn't have its directory yet
+    std::fs::create_dir_all(location)?;
+    let path = format!("{}{}", location, LOCK_FILE);
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+    if !try_lock(&file)? {
+      let owner = std::fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+      let mut error = Error::new(format!("Another patcher (process {}) is working on this installation", owner.map_or("unknown".to_string(), |pid| pid.to_string())));
+      error.kind = ErrorKind::AlreadyRunning;
+      return Err(error);
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(std::process::id().to_string().as_bytes())?;
+    Ok(InstallLock { file })
+  }
+}
+
+impl Drop for InstallLock {
+  fn drop(&mut self) {
+    //The lock itself is released when the file is closed
+    self.file.set_len(0).ok();
+  }
+}
+
+/*
+ * Takes an exclusive lock on the whole file without waiting, returns false if another handle holds it already.
+ */
+#[cfg(unix)]
+fn try_lock(file: &std::fs::File) -> Result<bool, Error> {
+  use std::os::unix::io::AsRawFd;
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+    return Ok(true);
+  }
+  let error = std::io::Error::last_os_error();
+  match error.raw_os_error() {
+    Some(code) if code == libc::EWOULDBLOCK => Ok(false),
+    _ => Err(error.into())
+  }
+}
+
+#[cfg(windows)]
+fn try_lock(file: &std::fs::File) -> Result<bool, Error> {
+  use std::os::windows::io::AsRawHandle;
+  #[repr(C)]
+  struct Overlapped {
+    internal: usize,
+    internal_high: usize,
+    offset: u32,
+    offset_high: u32,
+    event: *mut std::ffi::c_void,
+  }
+  extern "system" {
+    fn LockFileEx(file: *mut std::ffi::c_void, flags: u32, reserved: u32, bytes_low: u32, bytes_high: u32, overlapped: *mut Overlapped) -> i32;
+  }
+  const LOCKFILE_FAIL_IMMEDIATELY : u32 = 0x1;
+  const LOCKFILE_EXCLUSIVE_LOCK : u32 = 0x2;
+  const ERROR_LOCK_VIOLATION : i32 = 33;
+  let mut overlapped = Overlapped { internal: 0, internal_high: 0, offset: 0, offset_high: 0, event: std::ptr::null_mut() };
+  if unsafe { LockFileEx(file.as_raw_handle() as *mut std::ffi::c_void, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY, 0, u32::MAX, u32::MAX, &mut overlapped) } != 0 {
+    return Ok(true);
+  }
+  let error = std::io::Error::last_os_error();
+  match error.raw_os_error() {
+    Some(ERROR_LOCK_VIOLATION) => Ok(false),
+    _ => Err(error.into())
+  }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn try_lock(_file: &std::fs::File) -> Result<bool, Error> {
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn second_lock_is_refused() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let location = format!("{}/", dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())));
+    let lock = InstallLock::acquire(&location).expect(concat!(module_path!(),":",file!(),":",line!()));
+    match InstallLock::acquire(&location) {
+      Err(e) => assert_eq!(e.kind, ErrorKind::AlreadyRunning),
+      Ok(_) => panic!("Expected the installation to be locked")
+    }
+    drop(lock);
+    assert!(InstallLock::acquire(&location).is_ok());
+  }
+
+  #[test]
+  fn leftover_lock_file_is_taken_over() {
+    //What a crashed patcher leaves behind, its process is gone and so is its lock
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let location = format!("{}/", dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())));
+    std::fs::write(format!("{}{}", &location, LOCK_FILE), b"4294967295").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let _lock = InstallLock::acquire(&location).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(std::fs::read_to_string(format!("{}{}", &location, LOCK_FILE)).expect(concat!(module_path!(),":",file!(),":",line!())), std::process::id().to_string());
+  }
+}
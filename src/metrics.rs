@@ -0,0 +1,70 @@
+use std::fmt::Write;
+
+/**
+ Builds a page of metrics in the Prometheus text exposition format, see `Downloader::gather_metrics()`.
+*/
+pub struct MetricsWriter {
+  text: String,
+}
+
+impl MetricsWriter {
+  pub fn new() -> MetricsWriter {
+    MetricsWriter {
+      text: String::new(),
+    }
+  }
+
+  /**
+   Adds a metric with a single sample, `kind` is either "counter" or "gauge".
+  */
+  pub fn metric(&mut self, name: &str, kind: &str, help: &str, value: u64) {
+    self.header(name, kind, help);
+    writeln!(self.text, "{} {}", name, value).expect(concat!(module_path!(),":",file!(),":",line!()));
+  }
+
+  /**
+   Adds a metric with a sample for every value of `label`.
+  */
+  pub fn labelled_metric(&mut self, name: &str, kind: &str, help: &str, label: &str, samples: &[(String, u64)]) {
+    self.header(name, kind, help);
+    for (label_value, value) in samples {
+      writeln!(self.text, "{}{{{}=\"{}\"}} {}", name, label, escape(label_value), value).expect(concat!(module_path!(),":",file!(),":",line!()));
+    }
+  }
+
+  pub fn finish(self) -> String {
+    self.text
+  }
+
+  fn header(&mut self, name: &str, kind: &str, help: &str) {
+    writeln!(self.text, "# HELP {} {}", name, help).expect(concat!(module_path!(),":",file!(),":",line!()));
+    writeln!(self.text, "# TYPE {} {}", name, kind).expect(concat!(module_path!(),":",file!(),":",line!()));
+  }
+}
+
+/*
+ * Escapes a label value, mirror addresses can't contain these but the format requires it
+ */
+fn escape(label_value: &str) -> String {
+  label_value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn metrics_are_rendered_as_text() {
+    let mut metrics = MetricsWriter::new();
+    metrics.metric("patcher_active_downloads", "gauge", "Downloads in progress.", 2);
+    metrics.labelled_metric("patcher_mirror_failures_total", "counter", "Failed download attempts, by mirror.", "mirror", &[("http://a\"b/".to_string(), 3)]);
+    assert_eq!(metrics.finish(), concat!(
+      "# HELP patcher_active_downloads Downloads in progress.\n",
+      "# TYPE patcher_active_downloads gauge\n",
+      "patcher_active_downloads 2\n",
+      "# HELP patcher_mirror_failures_total Failed download attempts, by mirror.\n",
+      "# TYPE patcher_mirror_failures_total counter\n",
+      "patcher_mirror_failures_total{mirror=\"http://a\\\"b/\"} 3\n",
+    ));
+  }
+}
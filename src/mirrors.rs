@@ -1,8 +1,15 @@
 use std::time::{Duration, Instant};
-use crate::downloader::download_file;
-use crate::traits::{AsString,Error};
+use std::collections::VecDeque;
+use crate::downloader::{download_file, local_path};
+use crate::certificate::{check_certificate, CertificateStatus};
+use crate::traits::{AsString,Error,LockUnpoisoned};
+use crate::retry::Backoff;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::ToSocketAddrs;
+use std::io::{Read, Write};
+use rand::Rng;
+use rayon::prelude::*;
 
 #[derive(Debug, Clone)]
 pub struct Mirror {
@@ -11,6 +18,258 @@ pub struct Mirror {
   pub ping: f64,
   pub enabled: Arc<Mutex<bool>>,
   pub ip: SocketAddrs,//Vec<std::net::SocketAddr>,
+  pub certificate: Option<CertificateStatus>, //Only checked for https mirrors when requested
+  pub in_use: Arc<AtomicUsize>, //Downloads from the mirror that are in progress, shared by its clones
+  pub full_path: Arc<String>, //The directory under the patch path with full downloads
+  pub delta_path: Arc<String>, //The directory under the patch path with delta downloads
+  pub http2: bool, //Whether the mirror answered the HTTP/2 connection preface in the benchmark, see HttpVersion::Auto
+}
+
+impl Mirror {
+  /**
+  Creates a mirror from an url found in release.json and resolves its address.
+  Urls without a scheme are treated as http, so a plain `host:port` works too.
+  A `file://` url is a local mirror, a directory laid out like the patch path of a mirror.
+  */
+  pub fn parse(mirror: &str, patch_path: &str) -> Result<Mirror, Error> {
+    let (mirror, url) = parse_mirror_url(mirror)?;
+    let ip = if url.scheme() == "file" { Vec::new() } else { url.socket_addrs(|| None)? };
+    Ok(Mirror {
+      address: Arc::new(format!("{}{}", &mirror, patch_path)),
+      ip: ip.into(),
+      speed: 1.0,
+      ping: 1000.0,
+      enabled: Arc::new(Mutex::new(false)),
+      certificate: None,
+      in_use: Arc::new(AtomicUsize::new(0)),
+      full_path: Arc::new("full".to_string()),
+      delta_path: Arc::new("delta".to_string()),
+      http2: false,
+    })
+  }
+
+  /**
+  Whether the mirror is a directory on this machine rather than a server.
+  */
+  pub fn is_local(&self) -> bool {
+    self.address.starts_with("file://")
+  }
+
+  /**
+  Counts a download from the mirror as in progress until the returned guard is dropped.
+  */
+  pub(crate) fn acquire(&self) -> MirrorUse {
+    self.in_use.fetch_add(1, Ordering::SeqCst);
+    MirrorUse(self.in_use.clone())
+  }
+}
+
+pub(crate) struct MirrorUse(Arc<AtomicUsize>);
+
+impl Drop for MirrorUse {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/**
+Downloads and parses release.json.
+*/
+pub fn fetch_release(location: &str, respect_env_proxy: bool) -> Result<ReleaseInfo, Error> {
+  fetch_release_within(location, Duration::from_secs(10), respect_env_proxy)
+}
+
+/**
+Downloads and parses release.json, giving up on the request after `timeout`.
+*/
+pub fn fetch_release_within(location: &str, timeout: Duration, respect_env_proxy: bool) -> Result<ReleaseInfo, Error> {
+  let mut release_json = match download_file(location.to_string(), timeout, respect_env_proxy) {
+    Ok(result) => result,
+    Err(e) => return Err(format!("Is your internet down? {}", e).into())
+  };
+  let release_json_response = match release_json.text() {
+    Ok(result) => result,
+    Err(e) => return Err(format!("mirrors.rs: Corrupted response: {}", e).into())
+  };
+  ReleaseInfo::parse(&release_json_response)
+}
+
+/**
+Checks the url of a mirror without resolving it, returning the url with its scheme filled in.
+*/
+fn parse_mirror_url(mirror: &str) -> Result<(String, url::Url), Error> {
+  let mirror = if mirror.contains("://") { mirror.to_string() } else { format!("http://{}", mirror) };
+  let url = match mirror.parse::<url::Url>() {
+    Ok(url) => url,
+    Err(e) => return Err(format!("Invalid mirror url \"{}\": {}", &mirror, e).into())
+  };
+  match url.scheme() {
+    "http" | "https" | "file" => Ok((mirror, url)),
+    scheme => Err(format!("Unsupported scheme \"{}\" for mirror \"{}\"", scheme, &mirror).into())
+  }
+}
+
+/// What benchmarking a mirror measured, and whether the mirror is used because of it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BenchmarkResult {
+  pub address: String,
+  /// In bytes per millisecond, 0 if the benchmark failed.
+  pub speed: f64,
+  /// In milliseconds.
+  pub ping: f64,
+  pub succeeded: bool,
+  pub enabled: bool,
+  /// Why the mirror got disabled, None if it's enabled.
+  pub reason: Option<String>,
+}
+
+/// A mirror as listed in release.json, with the paths it overrides for itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorSource {
+  pub url: String,
+  pub patch_path: Option<String>,
+  pub full_path: Option<String>,
+  pub delta_path: Option<String>,
+}
+
+/// The contents of release.json that the patcher relies on.
+#[derive(Clone)]
+pub struct ReleaseInfo {
+  pub version_number: u64,
+  pub instructions_hash: String,
+  pub patch_path: String,
+  /// The directories under the patch path with full and delta downloads, "full" and "delta" unless release.json says otherwise.
+  pub full_path: String,
+  pub delta_path: String,
+  pub mirrors: Vec<MirrorSource>,
+  pub torrent: Option<String>,
+  /// The amount of entries instructions.json should have, if release.json says.
+  pub expected_file_count: Option<usize>,
+  /// The size of instructions.json in bytes, if release.json says.
+  pub instructions_size: Option<u64>,
+  pub launcher_info: LauncherInfo,
+}
+
+impl ReleaseInfo {
+  /**
+  Parses and validates release.json, without resolving or contacting any of the mirrors.
+  */
+  pub fn parse(text: &str) -> Result<ReleaseInfo, Error> {
+    let release_data = match json::parse(text) {
+      Ok(result) => result,
+      Err(e) => return Err(format!("mirrors.rs: Invalid JSON: {}", e).into())
+    };
+    let string = |value: &json::JsonValue, name: &str| -> Result<String, Error> {
+      match value.as_string_option() {
+        Some(string) => Ok(string),
+        None => Err(format!("release.json: \"{}\" is missing or not a string", name).into())
+      }
+    };
+    let game = &release_data["game"];
+    let launcher = &release_data["launcher"];
+    let version_number = match game["version_number"].as_u64() {
+      Some(version_number) => version_number,
+      None => return Err("release.json: \"game.version_number\" is missing or not a number".to_string().into())
+    };
+    let instructions_hash = string(&game["instructions_hash"], "game.instructions_hash")?;
+    if instructions_hash.len() != 64 || !instructions_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(format!("release.json: \"{}\" is not a SHA256 hash", &instructions_hash).into());
+    }
+    let optional_string = |value: &json::JsonValue, name: &str| -> Result<Option<String>, Error> {
+      match value.is_null() {
+        true => Ok(None),
+        false => string(value, name).map(Some)
+      }
+    };
+    let mut mirrors = Vec::with_capacity(game["mirrors"].len());
+    //A broken mirror only costs us that mirror, like in Mirrors::add_mirror_sources
+    for mirror in game["mirrors"].members() {
+      let source = string(&mirror["url"], "game.mirrors.url").and_then(|url| {
+        parse_mirror_url(&url)?;
+        Ok(MirrorSource {
+          url,
+          patch_path: optional_string(&mirror["patch_path"], "game.mirrors.patch_path")?,
+          full_path: optional_string(&mirror["full_path"], "game.mirrors.full_path")?,
+          delta_path: optional_string(&mirror["delta_path"], "game.mirrors.delta_path")?,
+        })
+      });
+      match source {
+        Ok(source) => mirrors.push(source),
+        Err(e) => println!("Skipping mirror {}: {}", mirror.dump(), e)
+      }
+    }
+    if mirrors.is_empty() {
+      return Err("release.json: no usable mirrors found".to_string().into());
+    }
+    let optional_number = |value: &json::JsonValue, name: &str| -> Result<Option<u64>, Error> {
+      match (value.is_null(), value.as_u64()) {
+        (true, _) => Ok(None),
+        (false, Some(number)) => Ok(Some(number)),
+        (false, None) => Err(format!("release.json: \"{}\" is not a number", name).into())
+      }
+    };
+    Ok(ReleaseInfo {
+      version_number,
+      instructions_hash,
+      patch_path: string(&game["patch_path"], "game.patch_path")?,
+      full_path: optional_string(&game["full_path"], "game.full_path")?.unwrap_or_else(|| "full".to_string()),
+      delta_path: optional_string(&game["delta_path"], "game.delta_path")?.unwrap_or_else(|| "delta".to_string()),
+      mirrors,
+      torrent: game["torrent"].as_string_option(),
+      expected_file_count: optional_number(&game["file_count"], "game.file_count")?.map(|count| count as usize),
+      instructions_size: optional_number(&game["instructions_size"], "game.instructions_size")?,
+      launcher_info: LauncherInfo {
+        version_name: string(&launcher["version_name"], "launcher.version_name")?,
+        version_number: match launcher["version_number"].as_usize() {
+          Some(version_number) => version_number,
+          None => return Err("release.json: \"launcher.version_number\" is missing or not a number".to_string().into())
+        },
+        patch_url: string(&launcher["patch_url"], "launcher.patch_url")?,
+        patch_hash: string(&launcher["patch_hash"], "launcher.patch_hash")?,
+        prompted: false,
+      },
+    })
+  }
+}
+
+/// Benchmarks start at a random moment within this many milliseconds.
+const BENCHMARK_JITTER_MS : u64 = 250;
+
+const THROUGHPUT_WINDOW : Duration = Duration::from_secs(5);
+
+/// Bytes received from a single mirror during the last few seconds.
+pub struct Throughput {
+  samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+  pub fn new() -> Throughput {
+    Throughput {
+      samples: VecDeque::new(),
+    }
+  }
+
+  pub fn record(&mut self, bytes: u64) {
+    let now = Instant::now();
+    self.samples.push_back((now, bytes));
+    self.prune(now);
+  }
+
+  pub fn bytes_per_second(&mut self) -> f64 {
+    self.prune(Instant::now());
+    let bytes : u64 = self.samples.iter().map(|(_, bytes)| bytes).sum();
+    bytes as f64 / THROUGHPUT_WINDOW.as_secs_f64()
+  }
+
+  fn prune(&mut self, now: Instant) {
+    while let Some(&(time, _)) = self.samples.front() {
+      if now.duration_since(time) <= THROUGHPUT_WINDOW {
+        break;
+      }
+      self.samples.pop_front();
+    }
+  }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -42,11 +301,29 @@ pub struct LauncherInfo {
   pub prompted: bool,
 }
 
+/// Picks the index of the mirror to use out of the currently enabled mirrors.
+pub type MirrorSelector = Box<dyn Fn(&[Mirror]) -> usize + Send + Sync>;
+
 pub struct Mirrors {
   pub mirrors: Vec<Mirror>,
   pub instructions_hash: Option<String>,
   pub version_number: Option<String>,
   pub launcher_info: Option<LauncherInfo>,
+  pub torrent: Option<String>, //Magnet link or torrent url of the full game, if release.json advertises one
+  pub expected_file_count: Option<usize>, //Checked against instructions.json, to catch truncated or stale manifests
+  pub instructions_size: Option<u64>,
+  pub selector: Option<MirrorSelector>,
+  pub check_certificates: bool,
+  pub respect_env_proxy: bool, //Whether release.json, the benchmarks and the downloads go through the proxy in HTTP_PROXY
+  pub benchmark_concurrency: usize,
+  pub benchmark_deadline: Duration, //Mirrors that haven't finished benchmarking by then are disabled
+  pub prune_ratio: Option<f64>, //Mirrors more than this many times slower than the fastest one are disabled after benchmarking
+  pub min_mirrors: usize,
+  pub fallback_mirrors: Vec<String>, //Full urls, including the patch path
+  pub release_timeout: Duration,
+  pub release_attempts: usize, //How often release.json is requested before falling back
+  pub release_backoff: Backoff,
+  pub benchmark_results: Vec<BenchmarkResult>, //Of the last benchmark
 }
 
 impl Mirrors {
@@ -56,6 +333,21 @@ impl Mirrors {
       instructions_hash: None,
       version_number: None,
       launcher_info: None,
+      torrent: None,
+      expected_file_count: None,
+      instructions_size: None,
+      selector: None,
+      check_certificates: false,
+      respect_env_proxy: true,
+      benchmark_concurrency: 8,
+      benchmark_deadline: Duration::from_secs(30),
+      prune_ratio: Some(4.0),
+      min_mirrors: 1,
+      fallback_mirrors: Vec::new(),
+      release_timeout: Duration::from_secs(10),
+      release_attempts: 3,
+      release_backoff: Backoff::Exponential { initial: Duration::from_millis(500), max: Duration::from_secs(4) },
+      benchmark_results: Vec::new(),
     }
   }
 
@@ -65,7 +357,8 @@ impl Mirrors {
 
   pub fn remove(&self, entry: Mirror) {
     for i in 0..self.mirrors.len() {
-      if self.mirrors[i].ip == entry.ip {
+      //Virtual hosts behind the same CDN share their addresses, so the url is what tells mirrors apart
+      if self.mirrors[i].address == entry.address {
         self.disable(i);
       }
     }
@@ -73,130 +366,597 @@ impl Mirrors {
 
   pub fn disable(&self, entry: usize) {
     let mirrors = self.mirrors[entry].enabled.clone();
-    *mirrors.lock().expect(concat!(module_path!(),":",file!(),":",line!())) = false;
+    *mirrors.lock_unpoisoned() = false;
   }
 
   /**
   Downloads release.json from the renegade-x server and adds it to the struct
   */
   pub fn get_mirrors(&mut self, location: &str) -> Result<(), Error> {
-    let mut release_json = match download_file(location.to_string(), Duration::from_secs(10)) {
-      Ok(result) => result,
-      Err(e) => return Err(format!("Is your internet down? {}", e).into())
-    };
-    let release_json_response = match release_json.text() {
-      Ok(result) => result,
-      Err(e) => return Err(format!("mirrors.rs: Corrupted response: {}", e).into())
-    };
-    let release_data = match json::parse(&release_json_response) {
-      Ok(result) => result,
-      Err(e) => return Err(format!("mirrors.rs: Invalid JSON: {}", e).into())
+    match self.fetch_release(location) {
+      Ok(release_info) => self.load_release(release_info),
+      Err(e) => {
+        if self.fallback_mirrors.is_empty() {
+          return Err(e);
+        }
+        //Without release.json neither the version nor the instructions hash is known, instructions.json is trusted as is.
+        println!("Couldn't retrieve release.json, using the fallback mirrors: {}", e);
+        let fallback_mirrors = self.fallback_mirrors.clone();
+        self.add_mirrors(fallback_mirrors, "");
+        self.instructions_hash = None;
+        self.version_number = None;
+        self.expected_file_count = None;
+        self.instructions_size = None;
+      }
     };
-    self.launcher_info = Some(LauncherInfo {
-      version_name: release_data["launcher"]["version_name"].as_string(),
-      version_number: release_data["launcher"]["version_number"].as_usize().expect(concat!(module_path!(),":",file!(),":",line!())),
-      patch_url: release_data["launcher"]["patch_url"].as_string(),
-      patch_hash: release_data["launcher"]["patch_hash"].as_string(),
-      prompted: false,
-    });
-    let mut mirror_vec = Vec::with_capacity(release_data["game"]["mirrors"].len());
-    release_data["game"]["mirrors"].members().for_each(|mirror| mirror_vec.push(mirror["url"].as_string()) );
-    for mirror in mirror_vec {
-      let url = mirror.parse::<url::Url>().expect(concat!(module_path!(),":",file!(),":",line!()));
-      self.mirrors.push(Mirror{
-        address: Arc::new(format!("{}{}", &mirror, release_data["game"]["patch_path"].as_string())),
-        ip: url.socket_addrs(|| None).expect(concat!(module_path!(),":",file!(),":",line!())).into(),
-        speed: 1.0,
-        ping: 1000.0,
-        enabled: Arc::new(Mutex::new(false)),
-      });
-    }
     self.test_mirrors()?;
     println!("{:#?}", &self.mirrors);
-    self.instructions_hash = Some(release_data["game"]["instructions_hash"].as_string());
-    self.version_number = Some(release_data["game"]["version_number"].as_u64().expect(concat!(module_path!(),":",file!(),":",line!())).to_string());
+    let enabled_mirrors = self.enabled_count();
+    if enabled_mirrors < self.min_mirrors {
+      let total_mirrors = self.mirrors.len();
+      //Start over on the next attempt
+      self.mirrors.clear();
+      return Err(format!("Only {} out of {} mirrors are usable, at least {} are required", enabled_mirrors, total_mirrors, self.min_mirrors).into());
+    }
     Ok(())
   }
 
-  
-  pub fn get_mirror(&self) -> Mirror {
-    for i in 0..20 {
-      for mirror in self.mirrors.iter() {
-        if *mirror.enabled.lock().expect(concat!(module_path!(),":",file!(),":",line!())) && Arc::strong_count(&mirror.address) == i {
-          println!("i: {}, mirror: {}", i, &mirror.address);
-          return mirror.clone();
+  /**
+  Downloads release.json like `fetch_release`, retrying a few times so a hiccup of the version server doesn't fail the whole startup.
+  */
+  fn fetch_release(&self, location: &str) -> Result<ReleaseInfo, Error> {
+    let mut attempt = 1;
+    loop {
+      match fetch_release_within(location, self.release_timeout, self.respect_env_proxy) {
+        Ok(release_info) => return Ok(release_info),
+        Err(e) => {
+          if attempt >= self.release_attempts {
+            return Err(e);
+          }
+          println!("Couldn't retrieve release.json, attempt {} of {}: {}", attempt, self.release_attempts, e);
+          std::thread::sleep(self.release_backoff.delay(attempt));
+          attempt += 1;
         }
       }
     }
-    panic!("No mirrors found?");
+  }
+
+  pub fn enabled_count(&self) -> usize {
+    self.mirrors.iter().filter(|mirror| *mirror.enabled.lock_unpoisoned()).count()
   }
 
   /**
-  Checks the speed on the mirrors again
+  Takes over the mirrors, version and instructions hash of a release, the mirrors stay disabled until they're benchmarked.
   */
-  pub fn test_mirrors(&mut self) -> Result<(), Error> {
-    let mut handles = Vec::new();
-    for i in 0..self.mirrors.len() {
-      let mirror = self.mirrors[i].clone();
-      let fastest_mirror_speed = self.mirrors[0].speed;
-      handles.push(std::thread::spawn(move || -> Mirror {
-        let start = Instant::now();
-        let mut url = format!("{}", mirror.address.to_owned());
-        url.truncate(url.rfind('/').expect(concat!(module_path!(),":",file!(),":",line!())) + 1);
-        url.push_str("10kb_file");
-        let download_response = download_file(url, Duration::from_millis(10_000/fastest_mirror_speed as u64 * 4));
-        match download_response {
-          Ok(result) => {
-            let duration = start.elapsed();
-            let content_length = result.headers().get("content-length");
-            if content_length.is_none() || content_length.expect(concat!(module_path!(),":",file!(),":",line!())) != "10000" {
-              Mirror { 
-                address: mirror.address,
-                ip: mirror.ip,
-                speed: 0.0,
-                ping: 1000.0,
-                enabled: Arc::new(Mutex::new(false)),
-              }
-            } else {
-              Mirror { 
-                address: mirror.address,
-                ip: mirror.ip,
-                speed: 10_000.0/(duration.as_millis() as f64),
-                ping: (duration.as_micros() as f64)/1000.0,
-                enabled: Arc::new(Mutex::new(true)),
-              }
-            }
-          },
-          Err(_e) => {
-            Mirror { 
-              address: mirror.address,
-              ip: mirror.ip,
-              speed: 0.0,
-              ping: 1000.0,
-              enabled: Arc::new(Mutex::new(false)),
-            }
+  pub fn load_release(&mut self, release_info: ReleaseInfo) {
+    self.launcher_info = Some(release_info.launcher_info);
+    self.add_mirror_sources(&release_info.mirrors, &release_info.patch_path, &release_info.full_path, &release_info.delta_path);
+    self.instructions_hash = Some(release_info.instructions_hash);
+    self.torrent = release_info.torrent;
+    self.expected_file_count = release_info.expected_file_count;
+    self.instructions_size = release_info.instructions_size;
+    self.version_number = Some(release_info.version_number.to_string());
+  }
+
+  /**
+  Adds every mirror that can be parsed, a broken entry in release.json only costs us that mirror.
+  */
+  fn add_mirrors(&mut self, mirror_vec: Vec<String>, patch_path: &str) {
+    let sources : Vec<MirrorSource> = mirror_vec.into_iter().map(|url| MirrorSource { url, patch_path: None, full_path: None, delta_path: None }).collect();
+    self.add_mirror_sources(&sources, patch_path, "full", "delta");
+  }
+
+  /**
+  Like add_mirrors, the paths a mirror doesn't set for itself are the given ones.
+  */
+  fn add_mirror_sources(&mut self, sources: &[MirrorSource], patch_path: &str, full_path: &str, delta_path: &str) {
+    for source in sources {
+      match Mirror::parse(&source.url, source.patch_path.as_ref().map_or(patch_path, |path| path.as_str())) {
+        Ok(mut parsed) => {
+          parsed.full_path = Arc::new(source.full_path.as_ref().map_or(full_path, |path| path.as_str()).trim_matches('/').to_string());
+          parsed.delta_path = Arc::new(source.delta_path.as_ref().map_or(delta_path, |path| path.as_str()).trim_matches('/').to_string());
+          //A mirror listed twice would be benchmarked twice and get twice its share of the downloads
+          if self.mirrors.iter().any(|mirror| mirror.address == parsed.address) {
+            println!("Skipping mirror \"{}\": it's listed already", &parsed.address);
+            continue;
           }
-        }
+          self.mirrors.push(parsed);
+        },
+        Err(e) => println!("Skipping mirror \"{}\": {}", &source.url, e)
+      }
+    }
+  }
+
+  pub fn get_mirror(&self) -> Mirror {
+    match self.try_get_mirror() {
+      Some(mirror) => mirror,
+      None => panic!("No mirrors found?")
+    }
+  }
+
+  /**
+  Picks the mirror to download from, or None if every mirror has been disabled.
+  Hold on to `Mirror::acquire()` while downloading from it, so other downloads go elsewhere.
+  */
+  pub fn try_get_mirror(&self) -> Option<Mirror> {
+    self.try_get_mirror_avoiding(&[])
+  }
+
+  /**
+  Picks the mirror to download from like `try_get_mirror`, leaving out the mirrors in `avoid` unless they're the only ones left.
+  Used to retry a download on another mirror than the ones it failed on.
+  */
+  pub fn try_get_mirror_avoiding(&self, avoid: &[String]) -> Option<Mirror> {
+    let candidates : Vec<&Mirror> = self.mirrors.iter().filter(|mirror| *mirror.enabled.lock_unpoisoned() && !avoid.iter().any(|address| address == mirror.address.as_str())).collect();
+    if candidates.is_empty() {
+      return if avoid.is_empty() { None } else { self.try_get_mirror_avoiding(&[]) };
+    }
+    if let Some(selector) = &self.selector {
+      let candidates : Vec<Mirror> = candidates.into_iter().cloned().collect();
+      let index = std::cmp::min(selector(&candidates), candidates.len() - 1);
+      return Some(candidates[index].clone());
+    }
+    //The least busy mirror, the fastest one of those as the mirrors are sorted by speed
+    candidates.into_iter().min_by_key(|mirror| mirror.in_use.load(Ordering::SeqCst)).cloned()
+  }
+
+  /**
+  Checks the speed on the mirrors again, returning what was measured for each of them and whether they're enabled because of it.
+  The results are kept in `benchmark_results` until the next benchmark.
+  */
+  pub fn test_mirrors(&mut self) -> Result<Vec<BenchmarkResult>, Error> {
+    let fastest_mirror_speed = self.mirrors.iter().find(|mirror| !mirror.is_local()).map_or(1.0, |mirror| mirror.speed);
+    let check_certificates = self.check_certificates;
+    let respect_env_proxy = self.respect_env_proxy;
+    let deadline = Instant::now() + self.benchmark_deadline;
+    let mirrors = self.mirrors.clone();
+    let benchmark_concurrency = self.benchmark_concurrency;
+    //Benchmarked on a thread of its own, so a mirror that hangs can be left behind once the deadline passes
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+      let pool = rayon::ThreadPoolBuilder::new().num_threads(benchmark_concurrency).thread_name(|index| format!("patcher-benchmark-{}", index)).build().expect(concat!(module_path!(),":",file!(),":",line!()));
+      pool.install(|| mirrors.into_par_iter().for_each_with(sender, |sender, mirror| {
+        //Spread out the probes a little, so mirrors that share a backend don't skew each other's results
+        std::thread::sleep(Duration::from_millis(rand::thread_rng().gen_range(0, BENCHMARK_JITTER_MS)));
+        sender.send(benchmark(mirror, fastest_mirror_speed, check_certificates, respect_env_proxy)).ok();
       }));
+    });
+    let mut benchmarked_mirrors : Vec<Mirror> = Vec::new();
+    let mut failures : Vec<(Arc<String>, String)> = Vec::new();
+    while benchmarked_mirrors.len() < self.mirrors.len() {
+      let now = Instant::now();
+      if now >= deadline {
+        break;
+      }
+      match receiver.recv_timeout(deadline - now) {
+        Ok((mirror, failure)) => {
+          if let Some(failure) = failure {
+            failures.push((mirror.address.clone(), failure));
+          }
+          benchmarked_mirrors.push(mirror);
+        },
+        Err(_e) => break
+      }
     }
-    for handle in handles {
-      let mirror = handle.join().expect("Failed to execute thread in test_mirrors!");
-      for i in 0..self.mirrors.len() {
-        if self.mirrors[i].address == mirror.address {
-          self.mirrors[i] = mirror;
-          break;
+    for i in 0..self.mirrors.len() {
+      match benchmarked_mirrors.iter().position(|mirror| mirror.address == self.mirrors[i].address) {
+        Some(index) => self.mirrors[i] = benchmarked_mirrors.swap_remove(index),
+        None => {
+          println!("Disabling mirror {}, benchmarking it took too long", &self.mirrors[i].address);
+          self.mirrors[i].enabled = Arc::new(Mutex::new(false));
+          failures.push((self.mirrors[i].address.clone(), "benchmarking it took too long".to_string()));
         }
       }
     }
     if self.mirrors.len() > 1 {
-      self.mirrors.sort_by(|a,b| b.speed.partial_cmp(&a.speed).expect(concat!(module_path!(),":",file!(),":",line!())));
-      let best_speed = self.mirrors[0].speed;
-      for mut elem in self.mirrors.iter_mut() {
-        if elem.speed < best_speed / 4.0 {
-          elem.enabled = Arc::new(Mutex::new(false));
-        }
+      sort_by_speed(&mut self.mirrors);
+      if let Some(prune_ratio) = self.prune_ratio {
+        prune_slow_mirrors(&mut self.mirrors, prune_ratio);
       }
     }
-    Ok(())
+    let prune_ratio = self.prune_ratio;
+    self.benchmark_results = self.mirrors.iter().map(|mirror| {
+      let failure = failures.iter().find(|(address, _)| address == &mirror.address).map(|(_, failure)| failure.clone());
+      let enabled = *mirror.enabled.lock_unpoisoned();
+      let reason = match (&failure, enabled) {
+        (_, true) => None,
+        (Some(failure), false) => Some(failure.clone()),
+        (None, false) => prune_ratio.map(|prune_ratio| format!("it's more than {} times slower than the fastest mirror", prune_ratio))
+      };
+      BenchmarkResult {
+        address: mirror.address.to_string(),
+        speed: mirror.speed,
+        ping: mirror.ping,
+        succeeded: failure.is_none(),
+        enabled,
+        reason,
+      }
+    }).collect();
+    Ok(self.benchmark_results.clone())
+  }
+}
+
+/**
+Disables the mirrors that are more than `prune_ratio` times slower than the fastest one, the mirrors have to be sorted by speed.
+Local mirrors come first and aren't compared with the servers.
+*/
+fn prune_slow_mirrors(mirrors: &mut [Mirror], prune_ratio: f64) {
+  let best_speed = mirrors.iter().find(|mirror| !mirror.is_local()).map_or(0.0, |mirror| mirror.speed);
+  for mut elem in mirrors.iter_mut().filter(|mirror| !mirror.is_local()) {
+    if elem.speed < best_speed / prune_ratio {
+      elem.enabled = Arc::new(Mutex::new(false));
+    }
+  }
+}
+
+/**
+Sorts the mirrors from fastest to slowest, a NaN speed counts as the slowest.
+Mirrors with the same speed keep their order.
+*/
+fn sort_by_speed(mirrors: &mut [Mirror]) {
+  mirrors.sort_by(|a, b| {
+    match (a.speed.is_nan(), b.speed.is_nan()) {
+      (true, true) => std::cmp::Ordering::Equal,
+      (true, false) => std::cmp::Ordering::Greater,
+      (false, true) => std::cmp::Ordering::Less,
+      (false, false) => b.speed.partial_cmp(&a.speed).expect(concat!(module_path!(),":",file!(),":",line!()))
+    }
+  });
+}
+
+/// The connection preface every HTTP/2 connection starts with, followed by an empty SETTINGS frame.
+const HTTP2_PREFACE : &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00";
+
+/**
+Whether the server speaks HTTP/2 without negotiating it first, by sending it the HTTP/2 connection preface.
+A server that does answers with a SETTINGS frame, an HTTP/1.1 server with an error response or by closing the connection.
+*/
+fn speaks_http2(ip: &SocketAddrs) -> bool {
+  let answer = ip.to_socket_addrs().and_then(|mut addresses| {
+    let address = addresses.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "The mirror has no address"))?;
+    let mut stream = std::net::TcpStream::connect_timeout(&address, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(HTTP2_PREFACE)?;
+    let mut frame_header = [0u8; 9];
+    stream.read_exact(&mut frame_header)?;
+    Ok(frame_header)
+  });
+  //The type of the frame is its fourth byte, 4 is SETTINGS
+  answer.map_or(false, |frame_header| frame_header[3] == 0x04)
+}
+
+/**
+Downloads a 10kb file from the mirror to measure its speed and ping, a mirror that fails gets disabled and the reason is returned with it.
+*/
+fn benchmark(mirror: Mirror, fastest_mirror_speed: f64, check_certificates: bool, respect_env_proxy: bool) -> (Mirror, Option<String>) {
+  if mirror.is_local() {
+    let exists = local_path(&mirror.address).map_or(false, |path| path.is_dir());
+    if !exists {
+      println!("Disabling mirror {}, the directory doesn't exist", &mirror.address);
+    }
+    let failure = if exists { None } else { Some("the directory doesn't exist".to_string()) };
+    return (Mirror {
+      address: mirror.address,
+      ip: mirror.ip,
+      speed: if exists { f64::MAX } else { 0.0 },
+      ping: 0.0,
+      enabled: Arc::new(Mutex::new(exists)),
+      certificate: None,
+      in_use: mirror.in_use,
+      full_path: mirror.full_path,
+      delta_path: mirror.delta_path,
+      http2: false,
+    }, failure);
+  }
+  let certificate = match mirror.address.parse::<url::Url>() {
+    Ok(ref url) if check_certificates && url.scheme() == "https" => {
+      let status = check_certificate(url.host_str().unwrap_or(""), &mirror.ip, Duration::from_secs(10));
+      if let CertificateStatus::ExpiresSoon { expires_in } = &status {
+        println!("The certificate of mirror {} expires in {} hours", &mirror.address, expires_in.as_secs() / 3600);
+      }
+      Some(status)
+    },
+    _ => None
+  };
+  if certificate.as_ref().map_or(false, |status| status.is_invalid()) {
+    println!("Disabling mirror {} due to its certificate: {:?}", &mirror.address, &certificate);
+    let failure = format!("its certificate is invalid: {:?}", &certificate);
+    return (Mirror {
+      address: mirror.address,
+      ip: mirror.ip,
+      speed: 0.0,
+      ping: 1000.0,
+      enabled: Arc::new(Mutex::new(false)),
+      certificate,
+      in_use: mirror.in_use,
+      full_path: mirror.full_path,
+      delta_path: mirror.delta_path,
+      http2: false,
+    }, Some(failure));
+  }
+  let start = Instant::now();
+  let mut url = format!("{}", mirror.address.to_owned());
+  url.truncate(url.rfind('/').expect(concat!(module_path!(),":",file!(),":",line!())) + 1);
+  url.push_str("10kb_file");
+  //Downloads through a proxy always use HTTP/1.1
+  let proxied = respect_env_proxy && crate::proxy::env_proxy(&url).is_some();
+  let download_response = download_file(url, Duration::from_millis((40_000.0/fastest_mirror_speed.max(1.0)) as u64), respect_env_proxy);
+  match download_response {
+    Ok(result) => {
+      let duration = start.elapsed();
+      let content_length = result.headers().get("content-length");
+      if content_length.is_none() || content_length.expect(concat!(module_path!(),":",file!(),":",line!())) != "10000" {
+        (Mirror {
+          address: mirror.address,
+          ip: mirror.ip,
+          speed: 0.0,
+          ping: 1000.0,
+          enabled: Arc::new(Mutex::new(false)),
+          certificate,
+          in_use: mirror.in_use,
+          full_path: mirror.full_path,
+          delta_path: mirror.delta_path,
+          http2: false,
+        }, Some("the benchmark file doesn't have the expected size".to_string()))
+      } else {
+        (Mirror {
+          address: mirror.address,
+          ip: mirror.ip,
+          speed: 10_000.0/(std::cmp::max(duration.as_millis(), 1) as f64),
+          ping: (duration.as_micros() as f64)/1000.0,
+          enabled: Arc::new(Mutex::new(true)),
+          certificate,
+          in_use: mirror.in_use,
+          full_path: mirror.full_path,
+          delta_path: mirror.delta_path,
+          http2: !proxied && speaks_http2(&mirror.ip),
+        }, None)
+      }
+    },
+    Err(e) => {
+      (Mirror {
+        address: mirror.address,
+        ip: mirror.ip,
+        speed: 0.0,
+        ping: 1000.0,
+        enabled: Arc::new(Mutex::new(false)),
+        certificate,
+        in_use: mirror.in_use,
+        full_path: mirror.full_path,
+        delta_path: mirror.delta_path,
+        http2: false,
+      }, Some(e.to_string()))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn release_json(version_number: &str, instructions_hash: &str, mirror: &str) -> String {
+    format!(r#"{{
+      "launcher": {{ "version_name": "0.8.0", "version_number": 80, "patch_url": "https://example.com/launcher.zip", "patch_hash": "ABCD" }},
+      "game": {{ "version_number": {}, "instructions_hash": "{}", "patch_path": "patches/5.0/", "mirrors": [ {{ "url": "{}" }} ] }}
+    }}"#, version_number, instructions_hash, mirror)
+  }
+
+  #[test]
+  fn release_json_is_validated() {
+    let hash = "A".repeat(64);
+    let release_info = ReleaseInfo::parse(&release_json("5877", &hash, "https://cdn.example.com/")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(release_info.version_number, 5877);
+    assert_eq!(release_info.mirrors.iter().map(|mirror| mirror.url.as_str()).collect::<Vec<&str>>(), vec!["https://cdn.example.com/"]);
+    assert_eq!((release_info.full_path.as_str(), release_info.delta_path.as_str()), ("full", "delta"));
+    assert_eq!(release_info.launcher_info.version_number, 80);
+    assert_eq!(release_info.expected_file_count, None);
+    let with_count = release_json("5877", &hash, "https://cdn.example.com/").replace("\"patch_path\"", "\"file_count\": 12000, \"patch_path\"");
+    assert_eq!(ReleaseInfo::parse(&with_count).expect(concat!(module_path!(),":",file!(),":",line!())).expected_file_count, Some(12000));
+    assert!(ReleaseInfo::parse(&with_count.replace("12000", "\"12000\"")).is_err());
+    assert!(ReleaseInfo::parse(&release_json("\"5877\"", &hash, "https://cdn.example.com/")).is_err());
+    assert!(ReleaseInfo::parse(&release_json("5877", "ABCD", "https://cdn.example.com/")).is_err());
+    assert!(ReleaseInfo::parse(&release_json("5877", &hash, "ftp://cdn.example.com/")).is_err());
+    let with_bad_mirror = release_json("5877", &hash, "https://cdn.example.com/").replace(r#"{ "url": "https://cdn.example.com/" }"#, r#"{ "url": "ftp://old.example.com/" }, { "url": "https://cdn.example.com/" }"#);
+    let release_info = ReleaseInfo::parse(&with_bad_mirror).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(release_info.mirrors.iter().map(|mirror| mirror.url.as_str()).collect::<Vec<&str>>(), vec!["https://cdn.example.com/"]);
+    assert!(ReleaseInfo::parse("{").is_err());
+  }
+
+  #[test]
+  fn mirrors_can_override_their_paths() {
+    let release_json = release_json("5877", &"A".repeat(64), "http://127.0.0.1:1/").replace(
+      r#"{ "url": "http://127.0.0.1:1/" }"#,
+      r#"{ "url": "http://127.0.0.1:1/" }, { "url": "http://127.0.0.1:2/", "patch_path": "renx/5.0/", "full_path": "/complete/" }"#
+    );
+    let mut mirrors = Mirrors::new();
+    mirrors.load_release(ReleaseInfo::parse(&release_json).expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert_eq!(mirrors.mirrors.len(), 2);
+    assert_eq!(mirrors.mirrors[0].address.as_str(), "http://127.0.0.1:1/patches/5.0/");
+    assert_eq!((mirrors.mirrors[0].full_path.as_str(), mirrors.mirrors[0].delta_path.as_str()), ("full", "delta"));
+    assert_eq!(mirrors.mirrors[1].address.as_str(), "http://127.0.0.1:2/renx/5.0/");
+    assert_eq!((mirrors.mirrors[1].full_path.as_str(), mirrors.mirrors[1].delta_path.as_str()), ("complete", "delta"));
+    assert!(ReleaseInfo::parse(&release_json.replace(r#""/complete/""#, "5")).is_err());
+  }
+
+  #[test]
+  fn release_json_is_retried() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let location = format!("http://{}/release.json", listener.local_addr().expect(concat!(module_path!(),":",file!(),":",line!())));
+    let body = release_json("5877", &"A".repeat(64), "http://127.0.0.1:1/");
+    let connections = Arc::new(AtomicUsize::new(0));
+    {
+      let connections = connections.clone();
+      std::thread::spawn(move || {
+        use std::io::{Read, Write};
+        for stream in listener.incoming() {
+          let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_e) => return
+          };
+          let mut request = [0u8; 4096];
+          let _ = stream.read(&mut request);
+          //The first two requests are dropped without an answer
+          if connections.fetch_add(1, Ordering::SeqCst) >= 2 {
+            let _ = stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), &body).as_bytes());
+          }
+        }
+      });
+    }
+    let mut mirrors = Mirrors::new();
+    mirrors.release_backoff = Backoff::Fixed(Duration::from_millis(10));
+    mirrors.release_attempts = 2;
+    assert!(mirrors.fetch_release(&location).is_err());
+    mirrors.release_attempts = 3;
+    connections.store(0, Ordering::SeqCst);
+    assert_eq!(mirrors.fetch_release(&location).expect(concat!(module_path!(),":",file!(),":",line!())).version_number, 5877);
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+  }
+
+  #[test]
+  fn sorting_by_speed_handles_nan() {
+    let mirror = |address: &str, speed: f64| {
+      let mut mirror = Mirror::parse(address, "").expect(concat!(module_path!(),":",file!(),":",line!()));
+      mirror.speed = speed;
+      mirror
+    };
+    let mut mirrors = vec![
+      mirror("http://127.0.0.1:1/", std::f64::NAN),
+      mirror("http://127.0.0.1:2/", 5.0),
+      mirror("http://127.0.0.1:3/", 0.0),
+      mirror("http://127.0.0.1:4/", 10_000.0),
+      mirror("http://127.0.0.1:5/", 5.0),
+    ];
+    sort_by_speed(&mut mirrors);
+    let order : Vec<&str> = mirrors.iter().map(|mirror| mirror.address.as_str()).collect();
+    assert_eq!(order, vec!["http://127.0.0.1:4/", "http://127.0.0.1:2/", "http://127.0.0.1:5/", "http://127.0.0.1:3/", "http://127.0.0.1:1/"]);
+  }
+
+  #[test]
+  fn hanging_benchmark_is_abandoned() {
+    //Connections end up in the backlog and are never answered
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let address = format!("http://{}/", listener.local_addr().expect(concat!(module_path!(),":",file!(),":",line!())));
+    let mut mirrors = Mirrors::new();
+    let mirror = Mirror::parse(&address, "").expect(concat!(module_path!(),":",file!(),":",line!()));
+    *mirror.enabled.lock_unpoisoned() = true;
+    mirrors.mirrors.push(mirror);
+    mirrors.benchmark_deadline = Duration::from_millis(500);
+    let started = Instant::now();
+    mirrors.test_mirrors().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(!*mirrors.mirrors[0].enabled.lock_unpoisoned());
+  }
+
+  #[test]
+  fn benchmark_results_tell_why_mirrors_are_disabled() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let existing = format!("file://{}/", dir.path().to_str().expect(concat!(module_path!(),":",file!(),":",line!())).replace("\\", "/"));
+    let missing = format!("{}missing/", &existing);
+    let mut mirrors = Mirrors::new();
+    mirrors.add_mirrors(vec![missing.clone(), existing.clone()], "");
+    let results = mirrors.test_mirrors().expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(results, mirrors.benchmark_results);
+    assert_eq!(results.len(), 2);
+    assert_eq!((results[0].address.as_str(), results[0].succeeded, results[0].enabled, results[0].reason.as_ref()), (existing.as_str(), true, true, None));
+    assert_eq!((results[1].address.as_str(), results[1].succeeded, results[1].enabled), (missing.as_str(), false, false));
+    assert_eq!(results[1].reason.as_ref().map(|reason| reason.as_str()), Some("the directory doesn't exist"));
+  }
+
+  #[test]
+  fn slow_mirrors_are_pruned_by_ratio() {
+    let mirror = |address: &str, speed: f64| {
+      let mut mirror = Mirror::parse(address, "").expect(concat!(module_path!(),":",file!(),":",line!()));
+      mirror.speed = speed;
+      *mirror.enabled.lock_unpoisoned() = true;
+      mirror
+    };
+    let mut mirrors = vec![mirror("http://127.0.0.1:1/", 100.0), mirror("http://127.0.0.1:2/", 30.0), mirror("http://127.0.0.1:3/", 10.0)];
+    prune_slow_mirrors(&mut mirrors, 4.0);
+    let enabled : Vec<bool> = mirrors.iter().map(|mirror| *mirror.enabled.lock_unpoisoned()).collect();
+    assert_eq!(enabled, vec![true, true, false]);
+    prune_slow_mirrors(&mut mirrors[..2], 2.0);
+    assert!(!*mirrors[1].enabled.lock_unpoisoned());
+  }
+
+  #[test]
+  fn least_busy_mirror_is_picked() {
+    let mut mirrors = Mirrors::new();
+    for address in ["http://127.0.0.1:1/", "http://127.0.0.1:2/"].iter() {
+      let mirror = Mirror::parse(address, "").expect(concat!(module_path!(),":",file!(),":",line!()));
+      *mirror.enabled.lock_unpoisoned() = true;
+      mirrors.mirrors.push(mirror);
+    }
+    //Clones that aren't downloading anything don't count
+    let clones : Vec<Mirror> = (0..5).map(|_| mirrors.mirrors[0].clone()).collect();
+    assert_eq!(mirrors.get_mirror().address.as_str(), "http://127.0.0.1:1/");
+    let first = mirrors.get_mirror().acquire();
+    assert_eq!(mirrors.get_mirror().address.as_str(), "http://127.0.0.1:2/");
+    let second = (mirrors.get_mirror().acquire(), mirrors.mirrors[1].acquire());
+    assert_eq!(mirrors.get_mirror().address.as_str(), "http://127.0.0.1:1/");
+    drop(first);
+    drop(second);
+    assert_eq!(clones[0].in_use.load(Ordering::SeqCst), 0);
+    assert_eq!(mirrors.mirrors[1].in_use.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn removing_a_mirror_keeps_other_hosts_on_its_address() {
+    let mut mirrors = Mirrors::new();
+    for address in ["http://127.0.0.1:1/", "http://localhost:1/"].iter() {
+      let mirror = Mirror::parse(address, "").expect(concat!(module_path!(),":",file!(),":",line!()));
+      *mirror.enabled.lock_unpoisoned() = true;
+      mirrors.mirrors.push(mirror);
+    }
+    mirrors.remove(mirrors.mirrors[0].clone());
+    assert!(!*mirrors.mirrors[0].enabled.lock_unpoisoned());
+    assert!(*mirrors.mirrors[1].enabled.lock_unpoisoned());
+  }
+
+  #[test]
+  fn http2_is_detected_by_the_preface() {
+    let serve = |answer: &'static [u8]| {
+      let listener = std::net::TcpListener::bind("127.0.0.1:0").expect(concat!(module_path!(),":",file!(),":",line!()));
+      let address = format!("http://{}/", listener.local_addr().expect(concat!(module_path!(),":",file!(),":",line!())));
+      std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect(concat!(module_path!(),":",file!(),":",line!()));
+        let mut preface = [0u8; 33];
+        stream.read_exact(&mut preface).ok();
+        stream.write_all(answer).ok();
+      });
+      Mirror::parse(&address, "").expect(concat!(module_path!(),":",file!(),":",line!()))
+    };
+    assert!(speaks_http2(&serve(b"\x00\x00\x00\x04\x00\x00\x00\x00\x00").ip));
+    assert!(!speaks_http2(&serve(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").ip));
+  }
+
+  #[test]
+  fn malformed_mirrors_are_skipped() {
+    let mut mirrors = Mirrors::new();
+    let mirror_vec = vec![
+      "http://127.0.0.1:8080/".to_string(),
+      "http://[::1/".to_string(),
+      "https://127.0.0.1/".to_string(),
+      "ftp://127.0.0.1/".to_string(),
+      "127.0.0.1:8081/".to_string(),
+    ];
+    mirrors.add_mirrors(mirror_vec, "patches/5.0/");
+    assert_eq!(mirrors.mirrors.len(), 3);
+    assert_eq!(*mirrors.mirrors[0].address, "http://127.0.0.1:8080/patches/5.0/");
+    assert_eq!(mirrors.mirrors[0].ip.inner[0].port(), 8080);
+    assert_eq!(mirrors.mirrors[1].ip.inner[0].port(), 443);
+    assert_eq!(*mirrors.mirrors[2].address, "http://127.0.0.1:8081/patches/5.0/");
+    assert_eq!(mirrors.mirrors[2].ip.inner[0].port(), 8081);
+  }
+
+  #[test]
+  fn duplicate_mirrors_are_skipped() {
+    let release_json = release_json("5877", &"A".repeat(64), "http://127.0.0.1:1/").replace(
+      r#"{ "url": "http://127.0.0.1:1/" }"#,
+      r#"{ "url": "http://127.0.0.1:1/" }, { "url": "http://127.0.0.1:2/" }, { "url": "http://127.0.0.1:1/" }, { "url": "http://127.0.0.1:1/", "patch_path": "renx/5.0/" }"#
+    );
+    let mut mirrors = Mirrors::new();
+    mirrors.load_release(ReleaseInfo::parse(&release_json).expect(concat!(module_path!(),":",file!(),":",line!())));
+    let addresses : Vec<&str> = mirrors.mirrors.iter().map(|mirror| mirror.address.as_str()).collect();
+    assert_eq!(addresses, vec!["http://127.0.0.1:1/patches/5.0/", "http://127.0.0.1:2/patches/5.0/", "http://127.0.0.1:1/renx/5.0/"]);
   }
 }
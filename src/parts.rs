@@ -0,0 +1,91 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::traits::Error;
+
+/**
+ Keeps track of which parts of a download are complete, one bit per part in a file next to the download.
+ Used instead of the counter at the end of the file when parts are downloaded out of order.
+*/
+pub struct PartMap {
+  file: std::fs::File,
+  bits: Vec<u8>,
+  parts_amount: usize,
+}
+
+impl PartMap {
+  /**
+   Opens the part map at `path`, or creates one in which the first `complete_parts` parts are marked complete.
+   A part map of the wrong length belongs to another download and starts over as well.
+  */
+  pub fn open(path: &str, parts_amount: usize, complete_parts: usize) -> Result<PartMap, Error> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    let len = (parts_amount + 7) / 8;
+    let mut bits = Vec::new();
+    file.read_to_end(&mut bits)?;
+    if bits.len() != len {
+      bits = vec![0u8; len];
+      for part in 0..complete_parts.min(parts_amount) {
+        bits[part / 8] |= 1 << (part % 8);
+      }
+      file.set_len(0)?;
+      file.seek(SeekFrom::Start(0))?;
+      file.write_all(&bits)?;
+    }
+    Ok(PartMap {
+      file,
+      bits,
+      parts_amount,
+    })
+  }
+
+  /**
+   The parts the part map at `path` has marked complete, without creating or changing it. None if there is no part map for `parts_amount` parts.
+  */
+  pub fn read_complete(path: &str, parts_amount: usize) -> Option<Vec<usize>> {
+    let bits = std::fs::read(path).ok()?;
+    if bits.len() != (parts_amount + 7) / 8 {
+      return None;
+    }
+    Some((0..parts_amount).filter(|&part| bits[part / 8] & (1 << (part % 8)) != 0).collect())
+  }
+
+  pub fn is_complete(&self, part: usize) -> bool {
+    self.bits[part / 8] & (1 << (part % 8)) != 0
+  }
+
+  /**
+   Marks a part as complete, straight away on disk so an interrupted download resumes after it.
+  */
+  pub fn complete(&mut self, part: usize) -> Result<(), Error> {
+    self.bits[part / 8] |= 1 << (part % 8);
+    self.file.seek(SeekFrom::Start((part / 8) as u64))?;
+    self.file.write_all(&self.bits[part / 8..part / 8 + 1])?;
+    Ok(())
+  }
+
+  pub fn missing(&self) -> Vec<usize> {
+    (0..self.parts_amount).filter(|&part| !self.is_complete(part)).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn completed_parts_are_remembered() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let path = dir.path().join("download.parts").to_str().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let mut parts = PartMap::open(&path, 10, 2).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(parts.missing(), vec![2, 3, 4, 5, 6, 7, 8, 9]);
+    parts.complete(9).expect(concat!(module_path!(),":",file!(),":",line!()));
+    drop(parts);
+    let parts = PartMap::open(&path, 10, 0).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(parts.missing(), vec![2, 3, 4, 5, 6, 7, 8]);
+    //Another download with a different amount of parts
+    let parts = PartMap::open(&path, 20, 0).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(parts.missing().len(), 20);
+    assert_eq!(PartMap::read_complete(&path, 20), Some(Vec::new()));
+    assert_eq!(PartMap::read_complete(&path, 10), None);
+  }
+}
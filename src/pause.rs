@@ -0,0 +1,77 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use crate::traits::LockUnpoisoned;
+
+/**
+ Shared by all downloads, workers that find it set block until it is cleared again.
+*/
+pub struct PauseFlag {
+  paused: Mutex<bool>,
+  resumed: Condvar,
+}
+
+impl PauseFlag {
+  pub fn new() -> PauseFlag {
+    PauseFlag {
+      paused: Mutex::new(false),
+      resumed: Condvar::new(),
+    }
+  }
+
+  pub fn pause(&self) {
+    *self.paused.lock_unpoisoned() = true;
+  }
+
+  pub fn resume(&self) {
+    *self.paused.lock_unpoisoned() = false;
+    self.resumed.notify_all();
+  }
+
+  pub fn is_paused(&self) -> bool {
+    *self.paused.lock_unpoisoned()
+  }
+
+  /**
+   Blocks while paused, for at most `timeout` if one is given.
+   Returns whether the flag is still set, which only happens when the timeout ran out.
+  */
+  pub fn wait(&self, timeout: Option<Duration>) -> bool {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut paused = self.paused.lock_unpoisoned();
+    while *paused {
+      paused = match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            return true;
+          }
+          self.resumed.wait_timeout(paused, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+        },
+        None => self.resumed.wait(paused).unwrap_or_else(|e| e.into_inner())
+      };
+    }
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+
+  #[test]
+  fn waiting_blocks_until_resumed() {
+    let flag = Arc::new(PauseFlag::new());
+    assert!(!flag.wait(None));
+    flag.pause();
+    assert!(flag.wait(Some(Duration::from_millis(10))));
+    let waiter = {
+      let flag = flag.clone();
+      std::thread::spawn(move || flag.wait(None))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    flag.resume();
+    assert!(!waiter.join().expect(concat!(module_path!(),":",file!(),":",line!())));
+    assert!(!flag.is_paused());
+  }
+}
@@ -0,0 +1,145 @@
+/**
+ The address of the proxy to send a request for `url` to, taken from the `HTTP_PROXY` and `NO_PROXY` environment variables like curl does.
+ Only plain http urls go through a proxy, as the requests are sent to it as they are rather than tunnelled. This machine is always reached directly.
+*/
+pub fn env_proxy(url: &str) -> Option<String> {
+  proxy_from(url, |name| {
+    std::env::var(name.to_lowercase()).or_else(|_e| std::env::var(name)).ok().filter(|value| !value.trim().is_empty())
+  })
+}
+
+/// How long connecting to a proxy may take, a proxy that doesn't answer shouldn't hang a download until its own timeout.
+const PROXY_CONNECT_TIMEOUT : std::time::Duration = std::time::Duration::from_secs(10);
+
+/**
+ Connects to the proxy at `address`, "host:port", trying each of its addresses and giving up on each after `PROXY_CONNECT_TIMEOUT`.
+*/
+pub fn connect(address: &str) -> std::io::Result<std::net::TcpStream> {
+  let mut last_error = None;
+  for address in std::net::ToSocketAddrs::to_socket_addrs(address)? {
+    match std::net::TcpStream::connect_timeout(&address, PROXY_CONNECT_TIMEOUT) {
+      Ok(stream) => return Ok(stream),
+      Err(e) => last_error = Some(e)
+    }
+  }
+  Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "The proxy has no address")))
+}
+
+/**
+ A hyper connector that connects every request to a proxy instead of the host in its url, which makes hyper send the whole url as the target.
+*/
+pub struct ProxyConnector {
+  address: String,
+}
+
+impl ProxyConnector {
+  pub fn new(address: String) -> ProxyConnector {
+    ProxyConnector {
+      address,
+    }
+  }
+}
+
+impl hyper::client::connect::Connect for ProxyConnector {
+  type Transport = tokio::net::TcpStream;
+  type Error = std::io::Error;
+  type Future = futures::future::FutureResult<(tokio::net::TcpStream, hyper::client::connect::Connected), std::io::Error>;
+
+  fn connect(&self, _destination: hyper::client::connect::Destination) -> Self::Future {
+    futures::future::result(connect(&self.address).and_then(|stream| {
+      tokio::net::TcpStream::from_std(stream, &tokio_reactor::Handle::default())
+    }).map(|stream| (stream, hyper::client::connect::Connected::new().proxy(true))))
+  }
+}
+
+fn proxy_from<F: Fn(&str) -> Option<String>>(url: &str, var: F) -> Option<String> {
+  let url = url.parse::<url::Url>().ok()?;
+  if url.scheme() != "http" {
+    return None;
+  }
+  let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+  if host == "localhost" || host.parse::<std::net::IpAddr>().map_or(false, |ip| ip.is_loopback()) {
+    return None;
+  }
+  if let Some(no_proxy) = var("NO_PROXY") {
+    if no_proxy.split(',').map(|entry| entry.trim()).any(|entry| bypasses(entry, &host, url.port_or_known_default())) {
+      return None;
+    }
+  }
+  let proxy = var("HTTP_PROXY")?;
+  let proxy = proxy.trim();
+  let proxy = if proxy.contains("://") { proxy.to_string() } else { format!("http://{}", proxy) };
+  let proxy = proxy.parse::<url::Url>().ok()?;
+  Some(format!("{}:{}", proxy.host_str()?, proxy.port_or_known_default()?))
+}
+
+/**
+ Whether an entry of NO_PROXY covers the host: `*` covers every host, a domain covers its subdomains as well and a port limits it to that port.
+*/
+fn bypasses(entry: &str, host: &str, port: Option<u16>) -> bool {
+  if entry == "*" {
+    return true;
+  }
+  let (entry, entry_port) = match entry.rfind(':') {
+    //The colons of an IPv6 address aren't a port, unless it's in brackets
+    Some(index) if entry[..index].ends_with(']') || !entry[..index].contains(':') => (&entry[..index], entry[index + 1..].parse::<u16>().ok()),
+    _ => (entry, None)
+  };
+  if entry_port.map_or(false, |entry_port| Some(entry_port) != port) {
+    return false;
+  }
+  let entry = entry.trim_start_matches("*.").trim_start_matches('.').trim_start_matches('[').trim_end_matches(']').to_lowercase();
+  !entry.is_empty() && (host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn proxy_leaves_out_no_proxy_hosts() {
+    let env = |no_proxy: &'static str| move |name: &str| match name {
+      "HTTP_PROXY" => Some("proxy.corp:3128".to_string()),
+      "NO_PROXY" => Some(no_proxy.to_string()),
+      _ => None
+    };
+    assert_eq!(proxy_from("http://cdn.example.com/patches/", env("")), Some("proxy.corp:3128".to_string()));
+    assert_eq!(proxy_from("https://cdn.example.com/patches/", env("")), None);
+    assert_eq!(proxy_from("http://cdn.example.com/patches/", env("localhost, .example.com")), None);
+    assert_eq!(proxy_from("http://example.com/patches/", env("example.com")), None);
+    assert_eq!(proxy_from("http://notexample.com/patches/", env("example.com")), Some("proxy.corp:3128".to_string()));
+    assert_eq!(proxy_from("http://10.0.0.1:8080/patches/", env("10.0.0.1:80")), Some("proxy.corp:3128".to_string()));
+    assert_eq!(proxy_from("http://10.0.0.1:8080/patches/", env("10.0.0.1:8080")), None);
+    assert_eq!(proxy_from("http://[fd00::1]:8080/patches/", env("fd00::1")), None);
+    assert_eq!(proxy_from("http://[fd00::1]:8080/patches/", env("[fd00::1]:80")), Some("proxy.corp:3128".to_string()));
+    assert_eq!(proxy_from("http://cdn.example.com/patches/", env("*")), None);
+    assert_eq!(proxy_from("http://127.0.0.1:8080/patches/", env("")), None);
+    assert_eq!(proxy_from("http://cdn.example.com/patches/", |_name: &str| None), None);
+  }
+
+  #[test]
+  fn requests_are_sent_to_the_proxy_with_the_whole_url() {
+    use std::io::{Read, Write};
+    use futures::{Future, Stream};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect(concat!(module_path!(),":",file!(),":",line!()));
+    let address = listener.local_addr().expect(concat!(module_path!(),":",file!(),":",line!())).to_string();
+    let proxy = std::thread::spawn(move || {
+      let (mut stream, _) = listener.accept().expect(concat!(module_path!(),":",file!(),":",line!()));
+      let mut request = Vec::new();
+      let mut buffer = [0u8; 1024];
+      while !request.ends_with(b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).expect(concat!(module_path!(),":",file!(),":",line!()));
+        assert_ne!(read, 0);
+        request.extend_from_slice(&buffer[..read]);
+      }
+      stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").expect(concat!(module_path!(),":",file!(),":",line!()));
+      String::from_utf8(request).expect(concat!(module_path!(),":",file!(),":",line!()))
+    });
+    let client = hyper::Client::builder().build::<_, hyper::Body>(ProxyConnector::new(address));
+    let request = hyper::Request::get("http://mirror.invalid/patches/10kb_file").body(hyper::Body::empty()).expect(concat!(module_path!(),":",file!(),":",line!()));
+    let mut rt = tokio::runtime::current_thread::Runtime::new().expect(concat!(module_path!(),":",file!(),":",line!()));
+    let body = rt.block_on(client.request(request).and_then(|res| res.into_body().concat2())).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(body.as_ref(), b"ok");
+    assert!(proxy.join().expect(concat!(module_path!(),":",file!(),":",line!())).starts_with("GET http://mirror.invalid/patches/10kb_file HTTP/1.1\r\n"));
+  }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::certificate::days_from_civil;
+
+/// Used when Retry-After can't be trusted, e.g. because the system clock is wrong.
+pub const FALLBACK_BACKOFF : Duration = Duration::from_secs(5);
+/// Longer waits than this are cut short, the download thread sleeps through them and the download is retried on another attempt anyway.
+pub const MAX_RETRY_AFTER : Duration = Duration::from_secs(30);
+/// A difference between the system clock and the server's Date header above this is considered a wrong clock.
+pub const MAX_CLOCK_SKEW : Duration = Duration::from_secs(300);
+
+/// How long to wait before the next attempt at a download, after a failed one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+  None,
+  Fixed(Duration),
+  /// Doubles after every failed attempt, starting at `initial` and never waiting longer than `max`.
+  Exponential {
+    initial: Duration,
+    max: Duration,
+  },
+}
+
+impl Backoff {
+  /**
+   The wait after `failed_attempts` failed attempts, of which there is at least one.
+  */
+  pub fn delay(&self, failed_attempts: usize) -> Duration {
+    match *self {
+      Backoff::None => Duration::from_secs(0),
+      Backoff::Fixed(delay) => delay,
+      Backoff::Exponential { initial, max } => {
+        let factor = 1u32.checked_shl(failed_attempts.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+        std::cmp::min(initial.checked_mul(factor).unwrap_or(max), max)
+      }
+    }
+  }
+}
+
+/// How often a download is attempted, on every mirror and in total, before it's given up on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+  pub per_mirror: usize,
+  pub total: usize,
+  pub backoff: Backoff,
+  /// Removes a mirror the first time it sends a file that doesn't match its hash, instead of the second time
+  /// or once another mirror sent the file intact. Connection errors are retried either way.
+  pub remove_on_hash_mismatch: bool,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      per_mirror: 5,
+      total: 5,
+      backoff: Backoff::None,
+      remove_on_hash_mismatch: false,
+    }
+  }
+}
+
+impl RetryPolicy {
+  /**
+   The mirrors that have no attempts left, given the mirror of every failed attempt so far.
+  */
+  pub fn exhausted(&self, failed_mirrors: &[String]) -> Vec<String> {
+    let mut exhausted : Vec<String> = Vec::new();
+    for address in failed_mirrors {
+      if !exhausted.contains(address) && failed_mirrors.iter().filter(|failed| *failed == address).count() >= self.per_mirror {
+        exhausted.push(address.clone());
+      }
+    }
+    exhausted
+  }
+}
+
+/**
+ Works out how long to wait before retrying, from a Retry-After header and the Date header of the same response.
+ Retry-After is either a number of seconds or an HTTP-date, the latter is only as good as the system clock.
+*/
+pub fn retry_delay(retry_after: &str, server_date: Option<&str>) -> Duration {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0)).as_secs();
+  retry_delay_at(retry_after, server_date, now)
+}
+
+fn retry_delay_at(retry_after: &str, server_date: Option<&str>, now: u64) -> Duration {
+  let retry_after = retry_after.trim();
+  if let Ok(seconds) = retry_after.parse::<u64>() {
+    return std::cmp::min(Duration::from_secs(seconds), MAX_RETRY_AFTER);
+  }
+  let retry_at = match parse_http_date(retry_after) {
+    Some(retry_at) => retry_at,
+    None => return FALLBACK_BACKOFF
+  };
+  if let Some(server_now) = server_date.and_then(parse_http_date) {
+    let skew = if server_now > now { server_now - now } else { now - server_now };
+    if skew > MAX_CLOCK_SKEW.as_secs() {
+      println!("Warning: the system clock is off by {} seconds compared to the server, ignoring Retry-After", skew);
+      return FALLBACK_BACKOFF;
+    }
+  }
+  std::cmp::min(Duration::from_secs(retry_at.saturating_sub(now)), MAX_RETRY_AFTER)
+}
+
+/*
+ * Parses an HTTP-date in the preferred IMF-fixdate form, e.g. "Sun, 06 Nov 1994 08:49:37 GMT", as seconds since the unix epoch
+ */
+fn parse_http_date(date: &str) -> Option<u64> {
+  let fields : Vec<&str> = date.split_whitespace().collect();
+  if fields.len() != 6 || fields[5] != "GMT" {
+    return None;
+  }
+  let day : u64 = fields[1].parse().ok()?;
+  let month = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"].iter().position(|month| *month == fields[2])? as u64 + 1;
+  let year : u64 = fields[3].parse().ok()?;
+  let time : Vec<u64> = fields[4].split(':').map(|number| number.parse().ok()).collect::<Option<Vec<u64>>>()?;
+  if year < 1970 || !(1..=31).contains(&day) || time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+    return None;
+  }
+  Some(days_from_civil(year, month, day)? * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn skewed_clock_falls_back_to_fixed_backoff() {
+    let server_now = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(server_now, 784_111_777);
+    assert_eq!(retry_delay_at("20", None, 0), Duration::from_secs(20));
+    assert_eq!(retry_delay_at("86400", None, 0), MAX_RETRY_AFTER);
+    assert_eq!(retry_delay_at("Sun, 06 Nov 1994 08:49:42 GMT", Some("Sun, 06 Nov 1994 08:49:37 GMT"), server_now), Duration::from_secs(5));
+    //The system clock is two hours behind
+    assert_eq!(retry_delay_at("Sun, 06 Nov 1994 08:49:47 GMT", Some("Sun, 06 Nov 1994 08:49:37 GMT"), server_now - 7200), FALLBACK_BACKOFF);
+    assert_eq!(retry_delay_at("soon", None, server_now), FALLBACK_BACKOFF);
+  }
+
+  #[test]
+  fn backoff_grows_up_to_its_maximum() {
+    let backoff = Backoff::Exponential { initial: Duration::from_secs(1), max: Duration::from_secs(10) };
+    assert_eq!(backoff.delay(1), Duration::from_secs(1));
+    assert_eq!(backoff.delay(3), Duration::from_secs(4));
+    assert_eq!(backoff.delay(5), Duration::from_secs(10));
+    assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    assert_eq!(Backoff::None.delay(3), Duration::from_secs(0));
+    let policy = RetryPolicy { per_mirror: 2, total: 8, ..RetryPolicy::default() };
+    let failed_mirrors = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+    assert_eq!(policy.exhausted(&failed_mirrors), vec!["a".to_string()]);
+  }
+}
@@ -0,0 +1,126 @@
+use crate::traits::Error;
+
+/// The extensions of the files that carry an Authenticode signature.
+const SIGNED_EXTENSIONS : [&str; 2] = ["exe", "dll"];
+
+fn is_executable(path: &str) -> bool {
+  let extension = std::path::Path::new(path).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+  extension.map_or(false, |extension| SIGNED_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/**
+ Whether `path` is an executable with a valid Authenticode signature, asked before it's patched.
+ Always false without the `signatures` feature or outside of Windows, so nothing gets checked afterwards.
+*/
+pub fn is_signed(path: &str) -> bool {
+  is_executable(path) && verify_trust(path) == Ok(())
+}
+
+/**
+ Fails if a patched executable that was signed before no longer has a valid signature.
+*/
+pub fn check_signed(path: &str) -> Result<(), Error> {
+  match verify_trust(path) {
+    Ok(()) => Ok(()),
+    Err(reason) => Err(Error::invalid_signature(path.to_string(), reason))
+  }
+}
+
+#[cfg(all(windows, feature = "signatures"))]
+fn verify_trust(path: &str) -> Result<(), String> {
+  use std::os::windows::ffi::OsStrExt;
+  #[repr(C)]
+  struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+  }
+  #[repr(C)]
+  struct WintrustFileInfo {
+    struct_size: u32,
+    file_path: *const u16,
+    file: *mut std::ffi::c_void,
+    known_subject: *const Guid,
+  }
+  #[repr(C)]
+  struct WintrustData {
+    struct_size: u32,
+    policy_callback_data: *mut std::ffi::c_void,
+    sip_client_data: *mut std::ffi::c_void,
+    ui_choice: u32,
+    revocation_checks: u32,
+    union_choice: u32,
+    file: *mut WintrustFileInfo,
+    state_action: u32,
+    state_data: *mut std::ffi::c_void,
+    url_reference: *mut u16,
+    prov_flags: u32,
+    ui_context: u32,
+    signature_settings: *mut std::ffi::c_void,
+  }
+  #[link(name = "wintrust")]
+  extern "system" {
+    fn WinVerifyTrust(window: *mut std::ffi::c_void, action: *const Guid, data: *mut WintrustData) -> i32;
+  }
+  //WINTRUST_ACTION_GENERIC_VERIFY_V2
+  const ACTION : Guid = Guid { data1: 0x00aa_c56b, data2: 0xcd44, data3: 0x11d0, data4: [0x8c, 0xc2, 0x00, 0xc0, 0x4f, 0xc2, 0x95, 0xee] };
+  const WTD_UI_NONE : u32 = 2;
+  const WTD_REVOKE_NONE : u32 = 0;
+  const WTD_CHOICE_FILE : u32 = 1;
+  const WTD_STATEACTION_VERIFY : u32 = 1;
+  const WTD_STATEACTION_CLOSE : u32 = 2;
+  //The revocation lists may not be reachable, a changed binary is what this is after
+  const WTD_CACHE_ONLY_URL_RETRIEVAL : u32 = 0x1000;
+  const TRUST_E_NOSIGNATURE : i32 = 0x800b_0100_u32 as i32;
+
+  let wide_path : Vec<u16> = std::ffi::OsStr::new(path).encode_wide().chain(std::iter::once(0)).collect();
+  let mut file_info = WintrustFileInfo {
+    struct_size: std::mem::size_of::<WintrustFileInfo>() as u32,
+    file_path: wide_path.as_ptr(),
+    file: std::ptr::null_mut(),
+    known_subject: std::ptr::null(),
+  };
+  let mut data = WintrustData {
+    struct_size: std::mem::size_of::<WintrustData>() as u32,
+    policy_callback_data: std::ptr::null_mut(),
+    sip_client_data: std::ptr::null_mut(),
+    ui_choice: WTD_UI_NONE,
+    revocation_checks: WTD_REVOKE_NONE,
+    union_choice: WTD_CHOICE_FILE,
+    file: &mut file_info,
+    state_action: WTD_STATEACTION_VERIFY,
+    state_data: std::ptr::null_mut(),
+    url_reference: std::ptr::null_mut(),
+    prov_flags: WTD_CACHE_ONLY_URL_RETRIEVAL,
+    ui_context: 0,
+    signature_settings: std::ptr::null_mut(),
+  };
+  let status = unsafe { WinVerifyTrust(std::ptr::null_mut(), &ACTION, &mut data) };
+  //Releases what the verification allocated
+  data.state_action = WTD_STATEACTION_CLOSE;
+  unsafe { WinVerifyTrust(std::ptr::null_mut(), &ACTION, &mut data) };
+  match status {
+    0 => Ok(()),
+    TRUST_E_NOSIGNATURE => Err("it isn't signed".to_string()),
+    status => Err(format!("its signature is invalid (0x{:08X})", status as u32))
+  }
+}
+
+#[cfg(not(all(windows, feature = "signatures")))]
+fn verify_trust(_path: &str) -> Result<(), String> {
+  Err("signatures aren't checked on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_executables_are_checked() {
+    assert!(is_executable("/RenegadeX/Binaries/Win64/UDK.exe"));
+    assert!(is_executable("/RenegadeX/Binaries/Win64/steam_api64.DLL"));
+    assert!(!is_executable("/RenegadeX/UDKGame/CookedPC/RenX_Game.u"));
+    assert!(!is_signed("/RenegadeX/UDKGame/CookedPC/RenX_Game.u"));
+  }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+/// A part of the day, as the time since midnight in the local time zone. A range may wrap around midnight.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeRange {
+  pub start: Duration,
+  pub end: Duration,
+}
+
+impl TimeRange {
+  pub fn new(start: Duration, end: Duration) -> TimeRange {
+    TimeRange {
+      start,
+      end,
+    }
+  }
+
+  pub fn contains(&self, time_of_day: Duration) -> bool {
+    if self.start <= self.end {
+      time_of_day >= self.start && time_of_day < self.end
+    } else {
+      time_of_day >= self.start || time_of_day < self.end
+    }
+  }
+}
+
+/**
+ Token bucket shared by all downloads.
+ The speed cap (in bytes per second) is looked up in the schedule on every tick, so a long download adapts as time passes.
+ Times that aren't covered by the schedule, or have a cap of 0, aren't throttled.
+*/
+pub struct Throttle {
+  schedule: Vec<(TimeRange, u64)>,
+  available: f64,
+  last_tick: Instant,
+}
+
+impl Throttle {
+  pub fn new() -> Throttle {
+    Throttle {
+      schedule: Vec::new(),
+      available: 0.0,
+      last_tick: Instant::now(),
+    }
+  }
+
+  pub fn set_schedule(&mut self, schedule: Vec<(TimeRange, u64)>) {
+    self.schedule = schedule;
+    self.available = 0.0;
+  }
+
+  fn current_limit(&self) -> Option<u64> {
+    if self.schedule.is_empty() {
+      return None;
+    }
+    let time_of_day = local_time_of_day();
+    self.schedule.iter().find(|(range, limit)| *limit != 0 && range.contains(time_of_day)).map(|(_, limit)| *limit)
+  }
+
+  /**
+   Takes `bytes` out of the bucket and returns how long the caller has to wait before continuing.
+  */
+  pub fn consume(&mut self, bytes: u64) -> Duration {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.last_tick);
+    self.last_tick = now;
+    let limit = match self.current_limit() {
+      Some(limit) => limit as f64,
+      None => {
+        self.available = 0.0;
+        return Duration::from_secs(0);
+      }
+    };
+    //Never save up more than a second worth of data
+    self.available = (self.available + elapsed.as_secs_f64() * limit).min(limit);
+    self.available -= bytes as f64;
+    if self.available >= 0.0 {
+      Duration::from_secs(0)
+    } else {
+      Duration::from_secs_f64(-self.available / limit)
+    }
+  }
+}
+
+/**
+ The time since midnight in the local time zone, daylight saving time included.
+*/
+#[cfg(unix)]
+fn local_time_of_day() -> Duration {
+  let mut tm : libc::tm = unsafe { std::mem::zeroed() };
+  let now = unsafe { libc::time(std::ptr::null_mut()) };
+  if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+    return utc_time_of_day();
+  }
+  Duration::from_secs(tm.tm_hour as u64 * 3600 + tm.tm_min as u64 * 60 + tm.tm_sec as u64)
+}
+
+#[cfg(windows)]
+fn local_time_of_day() -> Duration {
+  #[repr(C)]
+  struct SystemTime {
+    year: u16,
+    month: u16,
+    day_of_week: u16,
+    day: u16,
+    hour: u16,
+    minute: u16,
+    second: u16,
+    milliseconds: u16,
+  }
+  extern "system" {
+    fn GetLocalTime(system_time: *mut SystemTime);
+  }
+  let mut time = SystemTime { year: 0, month: 0, day_of_week: 0, day: 0, hour: 0, minute: 0, second: 0, milliseconds: 0 };
+  unsafe { GetLocalTime(&mut time) };
+  Duration::from_secs(time.hour as u64 * 3600 + time.minute as u64 * 60 + time.second as u64)
+}
+
+#[cfg(not(any(windows, unix)))]
+fn local_time_of_day() -> Duration {
+  utc_time_of_day()
+}
+
+#[cfg(not(windows))]
+fn utc_time_of_day() -> Duration {
+  let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+  Duration::from_secs(now.as_secs() % 86_400)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn time_range_wraps_around_midnight() {
+    let hour = |hours: u64| Duration::from_secs(hours * 3600);
+    let night = TimeRange::new(hour(22), hour(6));
+    assert!(night.contains(hour(23)));
+    assert!(night.contains(hour(2)));
+    assert!(!night.contains(hour(12)));
+    let day = TimeRange::new(hour(8), hour(18));
+    assert!(day.contains(hour(8)));
+    assert!(!day.contains(hour(18)));
+  }
+
+  #[test]
+  fn time_of_day_is_within_a_day() {
+    assert!(local_time_of_day() < Duration::from_secs(86_400));
+  }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+use crate::traits::Error;
+
+/**
+ Downloads the content of the torrent that release.json advertises, see `Downloader::set_torrent_transport()`.
+ The content has to be laid out like the patch path of a mirror, with the full downloads in `full/` named by their hash.
+*/
+pub trait TorrentTransport: Send + Sync {
+  /// Downloads everything in the torrent behind `link`, a magnet link or the url of a .torrent file, into `dir`.
+  /// Returns the directory the content ended up in, which is `dir` or a directory inside of it.
+  fn fetch(&self, link: &str, dir: &Path) -> Result<PathBuf, Error>;
+}
+
+/// A `TorrentTransport` that runs aria2c, which has to be installed or shipped with the launcher.
+pub struct Aria2Transport {
+  program: PathBuf,
+}
+
+impl Aria2Transport {
+  /// Runs the aria2c found in the PATH.
+  pub fn new() -> Aria2Transport {
+    Aria2Transport::with_program(PathBuf::from("aria2c"))
+  }
+
+  pub fn with_program(program: PathBuf) -> Aria2Transport {
+    Aria2Transport {
+      program,
+    }
+  }
+}
+
+impl Default for Aria2Transport {
+  fn default() -> Aria2Transport {
+    Aria2Transport::new()
+  }
+}
+
+impl TorrentTransport for Aria2Transport {
+  fn fetch(&self, link: &str, dir: &Path) -> Result<PathBuf, Error> {
+    std::fs::create_dir_all(dir)?;
+    //Stop as soon as the download is complete rather than seeding, and keep the .torrent file out of the content
+    let status = std::process::Command::new(&self.program)
+      .arg("--seed-time=0")
+      .arg("--follow-torrent=mem")
+      .arg("--summary-interval=0")
+      .arg("--console-log-level=warn")
+      .arg(format!("--dir={}", dir.display()))
+      .arg(link)
+      .status();
+    match status {
+      Ok(status) if status.success() => Ok(content_root(dir)),
+      Ok(status) => Err(format!("{} failed with {}", self.program.display(), status).into()),
+      Err(e) => Err(format!("Couldn't run {}: {}", self.program.display(), e).into())
+    }
+  }
+}
+
+/**
+ A torrent with several files keeps them in a directory named after the torrent, that directory is the content if it's the only thing in `dir`.
+*/
+fn content_root(dir: &Path) -> PathBuf {
+  let entries : Vec<PathBuf> = match std::fs::read_dir(dir) {
+    Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+    Err(_e) => return dir.to_path_buf()
+  };
+  match entries.as_slice() {
+    [only] if only.is_dir() && !dir.join("full").is_dir() => only.clone(),
+    _ => dir.to_path_buf()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn content_is_found_inside_the_torrent_directory() {
+    let dir = tempfile::tempdir().expect(concat!(module_path!(),":",file!(),":",line!()));
+    std::fs::create_dir_all(dir.path().join("RenegadeX-5877").join("full")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(content_root(dir.path()), dir.path().join("RenegadeX-5877"));
+    std::fs::create_dir(dir.path().join("full")).expect(concat!(module_path!(),":",file!(),":",line!()));
+    assert_eq!(content_root(dir.path()), dir.path());
+  }
+}
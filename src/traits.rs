@@ -44,17 +44,127 @@ impl<T> BorrowUnwrap<T> for Option<T> {
   }
 }
 
+pub trait LockUnpoisoned<T> {
+  fn lock_unpoisoned(&self) -> std::sync::MutexGuard<T>;
+}
+
+impl<T> LockUnpoisoned<T> for std::sync::Mutex<T> {
+  /**
+   Locks the mutex even if a thread panicked while holding it, so one failed download doesn't take every later use of the Downloader down with it.
+  */
+  fn lock_unpoisoned(&self) -> std::sync::MutexGuard<T> {
+    self.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+/// A single failed attempt at downloading a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadAttempt {
+  pub mirror: String,
+  pub url: String,
+  pub error: String,
+}
+
+/// What went wrong, for errors that callers may want to handle or report in detail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+  Other,
+  /// The download was paused for long enough that its connection got closed.
+  Paused,
+  /// Another patcher is working on the same installation.
+  AlreadyRunning,
+  /// The installation directory can't be written to, e.g. because it's under Program Files and the patcher isn't running as administrator.
+  ReadOnlyInstall {
+    path: String,
+  },
+  /// The mirror is busy and asked to be retried later, through a Retry-After header.
+  RateLimited {
+    retry_after: std::time::Duration,
+  },
+  /// An entry of instructions.json has a Path outside of the installation, through ".." or by being absolute.
+  PathTraversal {
+    path: String,
+  },
+  /// A mirror sent a file, or a part of one, that doesn't match its hash in instructions.json.
+  HashMismatch {
+    mirror: String,
+  },
+  /// Less than `Downloader::set_min_free_space()` was left on the drive of the installation while downloading.
+  InsufficientSpace {
+    path: String,
+    available: u64,
+    required: u64,
+  },
+  /// A patched executable that was signed before no longer has a valid Authenticode signature, see `Downloader::set_verify_signatures()`.
+  InvalidSignature {
+    path: String,
+  },
+  /// Every attempt at downloading a file failed.
+  DownloadFailed {
+    key: String,
+    size: usize,
+    attempts: Vec<DownloadAttempt>,
+  },
+}
+
 #[derive(Debug)]
 pub struct Error {
   details: String,
-  pub remove_mirror: bool
+  pub remove_mirror: bool,
+  pub kind: ErrorKind
 }
 
 impl Error {
     pub const fn new(msg: String) -> Error {
         Error { 
             details: msg,
-            remove_mirror: false
+            remove_mirror: false,
+            kind: ErrorKind::Other
+        }
+    }
+
+    pub fn hash_mismatch(msg: String, mirror: String) -> Error {
+        Error {
+            details: msg,
+            remove_mirror: false,
+            kind: ErrorKind::HashMismatch {
+              mirror
+            }
+        }
+    }
+
+    pub fn insufficient_space(path: String, available: u64, required: u64) -> Error {
+        Error {
+            details: format!("Only {} bytes are free on the drive of \"{}\", at least {} bytes have to stay free", available, &path, required),
+            remove_mirror: false,
+            kind: ErrorKind::InsufficientSpace {
+              path,
+              available,
+              required,
+            }
+        }
+    }
+
+    pub fn invalid_signature(path: String, reason: String) -> Error {
+        Error {
+            details: format!("\"{}\" was signed before it was patched, but now {}", &path, reason),
+            remove_mirror: false,
+            kind: ErrorKind::InvalidSignature {
+              path
+            }
+        }
+    }
+
+    pub fn download_failed(key: String, size: usize, attempts: Vec<DownloadAttempt>) -> Error {
+        let tried = attempts.iter().map(|attempt| format!("{} ({})", &attempt.url, &attempt.error)).collect::<Vec<String>>().join(", ");
+        Error {
+            details: format!("Couldn't download file {} ({} bytes) after {} attempts: {}", &key, size, attempts.len(), tried),
+            remove_mirror: false,
+            kind: ErrorKind::DownloadFailed {
+              key,
+              size,
+              attempts,
+            }
         }
     }
 }
@@ -79,7 +189,8 @@ impl From<std::io::Error> for Error {
     use std::error::Error;
     Self {
       details: error.description().to_string(),
-      remove_mirror: false
+      remove_mirror: false,
+      kind: ErrorKind::Other
     }
   }
 }
@@ -90,7 +201,8 @@ impl From<std::string::FromUtf8Error> for Error {
     use std::error::Error;
     Self {
       details: error.description().to_string(),
-      remove_mirror: false
+      remove_mirror: false,
+      kind: ErrorKind::Other
     }
   }
 }
@@ -101,7 +213,8 @@ impl From<tokio::timer::timeout::Error<hyper::Error>> for Error {
     use std::error::Error;
     Self {
       details: error.description().to_string(),
-      remove_mirror: false
+      remove_mirror: false,
+      kind: ErrorKind::Other
     }
   }
 }
@@ -113,7 +226,8 @@ impl From<http::Error> for Error {
     println!("http::Error: {:#?}", error);
     Self {
       details: error.description().to_string(),
-      remove_mirror: false
+      remove_mirror: false,
+      kind: ErrorKind::Other
     }
   }
 }
@@ -125,7 +239,8 @@ impl From<http::uri::InvalidUri> for Error {
     println!("http::uri::InvalidUri: {:#?}", error);
     Self {
       details: error.description().to_string(),
-      remove_mirror: false
+      remove_mirror: false,
+      kind: ErrorKind::Other
     }
   }
 }
@@ -137,7 +252,8 @@ impl From<hyper::Error> for Error {
     println!("hyper::Error: {:#?}", error);
     Self {
       details: error.description().to_string(),
-      remove_mirror: error.is_user()
+      remove_mirror: error.is_user(),
+      kind: ErrorKind::Other
     }
   }
 }
@@ -147,7 +263,8 @@ impl From<std::string::String> for Error {
   fn from(string: String) -> Self {
     Error {
       details: string,
-      remove_mirror: false
+      remove_mirror: false,
+      kind: ErrorKind::Other
     }
   }
 }
@@ -157,7 +274,8 @@ impl From<&str> for Error {
   fn from(string: &str) -> Self {
     Error {
       details: string.to_string(),
-      remove_mirror: true
+      remove_mirror: true,
+      kind: ErrorKind::Other
     }
   }
 }